@@ -0,0 +1,184 @@
+use crate::SyncServer;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+
+/// Requests queued for a document actor before senders wait.
+const MAILBOX_CAPACITY: usize = 256;
+
+type Command = Box<dyn FnOnce(&mut SyncServer) + Send>;
+
+/// Handle to a document actor: a task that owns one document's
+/// [`SyncServer`] and runs the requests sent to it one at a time.
+///
+/// Each document gets its own actor, so a slow diff or storage write only
+/// delays the clients of that document. Between requests the actor writes
+/// the document back once [`SyncServer::flush_due`] passes. Cloning the
+/// handle is cheap; the actor stops, flushing the document, once every
+/// handle is dropped.
+#[derive(Clone)]
+pub struct DocumentHandle {
+    commands: mpsc::Sender<Command>,
+    document_name: String,
+}
+
+impl DocumentHandle {
+    /// Start an actor owning `server` on the current tokio runtime. Requests
+    /// run on the runtime's blocking pool, since diffing and storage calls
+    /// block. Fails outside a runtime.
+    pub fn spawn(server: SyncServer) -> Result<Self, String> {
+        let runtime = Handle::try_current()
+            .map_err(|e| format!("Cannot serve '{}': {e}", server.document_name))?;
+        let (commands, mailbox) = mpsc::channel::<Command>(MAILBOX_CAPACITY);
+        let document_name = server.document_name.clone();
+        runtime.spawn(run_actor(server, mailbox));
+        Ok(Self {
+            commands,
+            document_name,
+        })
+    }
+
+    pub fn document_name(&self) -> &str {
+        &self.document_name
+    }
+
+    /// Run `request` on the actor and return its result.
+    pub async fn call<R, F>(&self, request: F) -> Result<R, String>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut SyncServer) -> R + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let command: Command = Box::new(move |server| {
+            let _ = reply.send(request(server));
+        });
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| self.stopped())?;
+        result.await.map_err(|_| self.stopped())
+    }
+
+    fn stopped(&self) -> String {
+        format!(
+            "Document '{}' is no longer being served",
+            self.document_name
+        )
+    }
+}
+
+/// Run requests from `mailbox` on `server`, and write the document back when
+/// due, until every handle is dropped.
+async fn run_actor(mut server: SyncServer, mut mailbox: mpsc::Receiver<Command>) {
+    loop {
+        let command: Command = tokio::select! {
+            command = mailbox.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            () = sleep_until(server.flush_due()) => Box::new(|server: &mut SyncServer| {
+                if let Err(e) = server.flush() {
+                    eprintln!("{e}");
+                }
+            }),
+        };
+        let run = task::spawn_blocking(move || {
+            // A panicking request fails only its caller, but may have left
+            // the server half updated: start again from the store, or stop
+            // serving.
+            if panic::catch_unwind(AssertUnwindSafe(|| command(&mut server))).is_err() {
+                if let Err(e) = server.reload() {
+                    eprintln!("{e}; no longer serving '{}'", server.document_name);
+                    return None;
+                }
+            }
+            Some(server)
+        });
+        match run.await {
+            Ok(Some(returned)) => server = returned,
+            // Stopped serving, or the runtime is shutting down; either way
+            // the server has been dropped, and so flushed.
+            Ok(None) | Err(_) => return,
+        }
+    }
+    // Dropping the server flushes it, which blocks too.
+    let _ = task::spawn_blocking(move || drop(server)).await;
+}
+
+/// Resolves at `deadline`; never resolves without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EditList;
+
+    #[tokio::test]
+    async fn test_concurrent_requests_are_serialized_on_the_actor() {
+        let handle =
+            DocumentHandle::spawn(SyncServer::new_in_memory("main".to_string()).unwrap()).unwrap();
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    handle
+                        .call(|server| {
+                            server.version += 1;
+                            server.version
+                        })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut versions = Vec::new();
+        for task in tasks {
+            versions.push(task.await.unwrap());
+        }
+        versions.sort();
+        assert_eq!(versions, (1..=10).collect::<Vec<u64>>());
+
+        // A panicking request does not take the document down with it.
+        assert!(handle.call(|_| panic!("boom")).await.is_err());
+        assert_eq!(handle.call(|server| server.version).await, Ok(10));
+    }
+
+    #[test]
+    fn test_spawning_outside_a_runtime_is_an_error() {
+        let server = SyncServer::new_in_memory("main".to_string()).unwrap();
+        assert!(DocumentHandle::spawn(server).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clients_resync_after_a_request_panics() {
+        let handle =
+            DocumentHandle::spawn(SyncServer::new_in_memory("main".to_string()).unwrap()).unwrap();
+        handle
+            .call(|server| server.connect_client("a".to_string()).map(|_| ()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(handle.call(|_| panic!("boom")).await.is_err());
+
+        // The server's shadow of "a" may be what the panic left behind, so
+        // "a" has to resync before its edits are taken again.
+        let diverged = handle
+            .call(|server| server.sync_with_client("a", EditList::default()).is_err())
+            .await;
+        assert_eq!(diverged, Ok(true));
+        let synced = handle
+            .call(|server| {
+                server.resync_client("a").unwrap();
+                server.sync_with_client("a", EditList::default()).is_ok()
+            })
+            .await;
+        assert_eq!(synced, Ok(true));
+    }
+}
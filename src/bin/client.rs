@@ -1,12 +1,15 @@
 use clap::Parser;
 use colored::*;
-use diff_sync::{deserialize_message, serialize_message, truncate_text, SyncEngine, SyncMessage};
+use diff_sync::{
+    diff, truncate_text, CodecKind, Compression, Compressor, EditList, Framing, OffsetUnit,
+    SyncEngine, SyncMessage, DEFAULT_COMPRESSION_THRESHOLD, PROTOCOL_VERSION,
+};
 use std::io::{self, Write};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::BufReader;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
-use tokio::time::{interval, timeout, Duration};
+use tokio::time::{interval, timeout, Duration, Instant};
 
 #[derive(Parser)]
 #[command(name = "sync-client")]
@@ -17,6 +20,27 @@ struct Cli {
 
     #[arg(short, long)]
     client_id: Option<String>,
+
+    /// Verify server edits against the local shadow checksum before applying
+    #[arg(long)]
+    strict_checksums: bool,
+
+    /// Message encoding after the handshake: json, msgpack or cbor
+    #[arg(long, default_value_t = CodecKind::Json)]
+    codec: CodecKind,
+
+    /// Payload compression: none, deflate or zstd
+    #[arg(long, default_value_t = Compression::None)]
+    compression: Compression,
+}
+
+/// Framing, codec and compression used on the connection. The handshake is
+/// always JSON.
+#[derive(Clone)]
+struct Wire {
+    framing: Framing,
+    codec: CodecKind,
+    compressor: Option<Compressor>,
 }
 
 #[tokio::main]
@@ -38,16 +62,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let connect_msg = SyncMessage::Connect {
         client_id: client_id.clone(),
+        protocol_version: PROTOCOL_VERSION,
+        features: vec!["push".to_string(), "resync".to_string()],
+        offset_unit: OffsetUnit::Bytes,
+        // Edits travel inside versioned packets, which keep the full form.
+        compact_edits: false,
+        codec: cli.codec,
+        compression: cli.compression,
+    };
+    // Envelopes need length-prefixed frames, like binary codecs.
+    let framing = match cli.compression {
+        Compression::None => Framing::for_codec(cli.codec),
+        _ => Framing::LengthPrefixed,
+    };
+    let mut wire = Wire {
+        framing,
+        codec: CodecKind::Json,
+        compressor: None,
     };
     let mut write_stream = write_half;
-    send_message(&mut write_stream, &connect_msg).await?;
-
+    send_message(&mut write_stream, &wire, &connect_msg).await?;
+
+    // Asking for compression gets an enveloped reply, even if the server
+    // declines it.
+    wire.compressor = Some(Compressor::new(
+        cli.compression,
+        DEFAULT_COMPRESSION_THRESHOLD,
+    ))
+    .filter(|_| cli.compression != Compression::None);
     let mut reader = BufReader::new(read_half);
-    let sync_engine = match receive_message(&mut reader).await? {
+    let pushes;
+    let sync_engine = match receive_message(&mut reader, &wire).await? {
         Some(SyncMessage::ConnectOk {
             server_version,
             document,
+            protocol_version,
+            features,
+            codec,
+            compression,
+            ..
         }) => {
+            if protocol_version < PROTOCOL_VERSION {
+                println!("Server speaks protocol version {protocol_version}");
+            }
+            if codec != cli.codec {
+                println!("Server chose the {} codec", codec.to_string().yellow());
+            }
+            if compression != cli.compression {
+                println!(
+                    "Server chose {} compression",
+                    compression.to_string().yellow()
+                );
+                wire.compressor = None;
+            }
+            wire.codec = codec;
+            pushes = features.iter().any(|f| f == "push");
             println!(
                 "Connected to server (v{})",
                 server_version.to_string().cyan()
@@ -56,6 +125,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             let mut engine = SyncEngine::new(document.content);
             engine.node_id = client_id.clone();
+            engine.strict_checksums = cli.strict_checksums;
             Arc::new(Mutex::new(engine))
         }
         Some(SyncMessage::Error { message }) => {
@@ -75,7 +145,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let sync_engine_bg = Arc::clone(&sync_engine);
     let client_id_bg = client_id.clone();
     let sync_task = tokio::spawn(async move {
-        if let Err(e) = background_sync(write_stream, reader, sync_engine_bg, client_id_bg).await {
+        if let Err(e) = background_sync(
+            write_stream,
+            reader,
+            wire,
+            pushes,
+            sync_engine_bg,
+            client_id_bg,
+        )
+        .await
+        {
             eprintln!("Sync task error: {e}");
         }
     });
@@ -92,70 +171,138 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// How long to wait for a ServerSync before retransmitting pending edits.
+const RESEND_AFTER: Duration = Duration::from_secs(5);
+
 async fn background_sync(
     mut writer: tokio::net::tcp::OwnedWriteHalf,
     mut reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    wire: Wire,
+    pushes: bool,
     engine: Arc<Mutex<SyncEngine>>,
     client_id: String,
 ) -> Result<(), String> {
     let mut sync_timer = interval(Duration::from_millis(500));
     let mut heartbeat_timer = interval(Duration::from_secs(30));
+    // When the outstanding ClientSync was sent. Only one is in flight at a
+    // time; if its reply never arrives the unacknowledged edits are resent.
+    let mut in_flight: Option<Instant> = None;
+    // With pushes, a ClientSync is only sent for local edits or to
+    // acknowledge server edits; the server pushes to us only once everything
+    // it sent is acknowledged. The first one tells it we use packets.
+    let mut ack_due = true;
 
     loop {
         tokio::select! {
             _ = sync_timer.tick() => {
-                let (edits, version) = {
+                if in_flight.is_some_and(|sent| sent.elapsed() < RESEND_AFTER) {
+                    continue;
+                }
+
+                let (packet, version) = {
                     let mut eng = engine.lock().await;
-                    (eng.diff_and_update_shadow(), eng.document().version)
+                    (eng.make_packet(), eng.document().version)
                 };
+                if pushes && packet.edits.is_empty() && !ack_due {
+                    continue;
+                }
+                ack_due = false;
 
                 let msg = SyncMessage::ClientSync {
                     client_id: client_id.clone(),
-                    edits,
+                    edits: EditList::default(),
+                    delta: None,
                     client_version: version,
                     cursor_position: None,
+                    packet: Some(packet),
                 };
 
-                send_message(&mut writer, &msg).await.map_err(|e| e.to_string())?;
+                send_message(&mut writer, &wire, &msg).await.map_err(|e| e.to_string())?;
+                in_flight = Some(Instant::now());
             }
 
             _ = heartbeat_timer.tick() => {
-                send_message(&mut writer, &SyncMessage::Ping).await?;
+                send_message(&mut writer, &wire, &SyncMessage::Ping).await?;
             }
 
-            result = receive_message(&mut reader) => {
+            result = receive_message(&mut reader, &wire) => {
                 match result {
-                    Ok(Some(SyncMessage::ServerSync { edits, server_version, .. })) => {
-                        if !edits.is_empty() {
-                            let mut eng = engine.lock().await;
-                            let old = eng.text().to_string();
+                    Ok(Some(SyncMessage::ServerSync { server_version, rejected_edits, packet, pushed, .. })) => {
+                        // A push crossing our request is superseded by the reply.
+                        if pushed && in_flight.is_some() {
+                            continue;
+                        }
+                        if !pushed {
+                            in_flight = None;
+                        }
+                        if !rejected_edits.is_empty() {
+                            eprintln!(
+                                "\n{} server could not place {} of your edits",
+                                "WARNING:".yellow().bold(),
+                                rejected_edits.len()
+                            );
+                        }
+                        let Some(packet) = packet else {
+                            eprintln!("Server did not reply with a versioned packet");
+                            continue;
+                        };
+
+                        let mut eng = engine.lock().await;
+                        let old = eng.text().to_string();
 
-                            if let Err(e) = eng.apply_edits(edits.clone()) {
+                        let report = match eng.receive_packet(packet) {
+                            Ok(report) => report,
+                            Err(e) => {
                                 eprintln!("Failed to apply server edits: {e}");
-                            } else {
-                                let new = eng.text().to_string();
-                                println!(
-                                    "\n{} {} edits (v{})",
-                                    "LIVE UPDATE:".green().bold(),
-                                    edits.len().to_string().cyan(),
-                                    server_version.to_string().dimmed()
-                                );
-                                println!("Document: \"{}\"", truncate_text(&new, 70).blue());
-
-                                if old != new {
-                                    println!(
-                                        "{} -> {}",
-                                        truncate_text(&old, 25).dimmed(),
-                                        truncate_text(&new, 25).green()
-                                    );
-                                }
-
-                                print!("\n> ");
-                                io::stdout().flush().unwrap();
+                                continue;
                             }
+                        };
+                        if report.outcomes.is_empty() {
+                            continue;
                         }
+                        ack_due = true;
+                        if !report.is_clean() {
+                            eprintln!(
+                                "{} {} server edits conflicted with local changes",
+                                "WARNING:".yellow().bold(),
+                                report.rejected_count()
+                            );
+                        }
+
+                        let new = eng.text().to_string();
+                        println!(
+                            "\n{} {} edits (v{})",
+                            "LIVE UPDATE:".green().bold(),
+                            report.outcomes.len().to_string().cyan(),
+                            server_version.to_string().dimmed()
+                        );
+                        println!("Document: \"{}\"", truncate_text(&new, 70).blue());
+
+                        if old != new {
+                            println!(
+                                "{} -> {}",
+                                truncate_text(&old, 25).dimmed(),
+                                truncate_text(&new, 25).green()
+                            );
+                        }
+
+                        print!("\n> ");
+                        io::stdout().flush().unwrap();
+                    }
+                    Ok(Some(SyncMessage::Resync { server_version, document })) => {
+                        in_flight = None;
+                        let report = engine.lock().await.resync(&document.content);
+                        println!(
+                            "\n{} shadow reset to server document (v{}), {} local edits kept",
+                            "RESYNC:".yellow().bold(),
+                            server_version.to_string().dimmed(),
+                            report.applied_count().to_string().cyan()
+                        );
+                        print!("\n> ");
+                        io::stdout().flush().unwrap();
                     }
                     Ok(Some(SyncMessage::Error { message })) => {
+                        in_flight = None;
                         eprintln!("Server error: {}", message.red());
                     }
                     Ok(Some(SyncMessage::Pong)) => {}
@@ -172,7 +319,9 @@ async fn background_sync(
 
 async fn interactive_loop(engine: Arc<Mutex<SyncEngine>>, client_id: String) -> Result<(), String> {
     println!("\n{}", "Interactive Collaborative Editor".bold().cyan());
-    println!("Commands: edit <text>, show, stats, help, quit\n");
+    println!("Commands: edit <text>, show, stats, export-diff [file], help, quit\n");
+    // Changes are exported relative to the document as it was on joining.
+    let joined_with = engine.lock().await.text().to_string();
 
     loop {
         print!("> ");
@@ -234,12 +383,35 @@ async fn interactive_loop(engine: Arc<Mutex<SyncEngine>>, client_id: String) ->
                 );
                 println!("  Shadow checksum: {}", stats.shadow_checksum.dimmed());
                 println!("  Has backup: {}", stats.has_backup);
+                println!("  Pending edits: {}", stats.pending_edits);
+                println!("  Rejected edits: {}", stats.rejected_edits);
+                println!("  Checksum mismatches: {}", stats.checksum_mismatches);
+                println!("  Diff deadline hits: {}", stats.deadline_hits);
+            }
+            "export-diff" => {
+                let eng = engine.lock().await;
+                let edits = diff(&joined_with, eng.text());
+                match edits.to_unified_diff(&joined_with, "document") {
+                    Ok(text) if text.is_empty() => println!("No changes since joining"),
+                    Ok(text) => match arg {
+                        Some(path) => match std::fs::write(path, &text) {
+                            Ok(()) => println!("Wrote diff to {}", path.cyan()),
+                            Err(e) => println!("{} {e}", "Failed to write diff:".red()),
+                        },
+                        None => print!("{text}"),
+                    },
+                    Err(e) => println!("{} {e}", "Failed to export diff:".red()),
+                }
             }
             "help" => {
                 println!("\n{}", "Commands:".bold());
                 println!("  {} <text> - Replace document", "edit".green());
                 println!("  {}        - Show document", "show".yellow());
                 println!("  {}        - Show statistics", "stats".blue());
+                println!(
+                    "  {} [file] - Unified diff of changes since joining",
+                    "export-diff".magenta()
+                );
                 println!("  {}        - Quit", "quit".red());
             }
             "quit" | "exit" => {
@@ -255,20 +427,31 @@ async fn interactive_loop(engine: Arc<Mutex<SyncEngine>>, client_id: String) ->
 
 async fn send_message(
     stream: &mut tokio::net::tcp::OwnedWriteHalf,
+    wire: &Wire,
     message: &SyncMessage,
 ) -> Result<(), String> {
-    let data = serialize_message(message).map_err(|e| e.to_string())?;
-    stream.write_all(&data).await.map_err(|e| e.to_string())
+    let mut data = wire.codec.codec().encode(message)?;
+    if let Some(compressor) = &wire.compressor {
+        data = compressor.seal(&data);
+    }
+    wire.framing
+        .write_frame(stream, &data)
+        .await
+        .map_err(|e| e.to_string())
 }
 
 async fn receive_message(
     reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+    wire: &Wire,
 ) -> Result<Option<SyncMessage>, String> {
-    let mut line = String::new();
-    match timeout(Duration::from_secs(60), reader.read_line(&mut line)).await {
-        Ok(Ok(0)) => Err("Connection closed".to_string()),
-        Ok(Ok(_)) => {
-            let msg = deserialize_message(line.as_bytes())?;
+    match timeout(Duration::from_secs(60), wire.framing.read_frame(reader)).await {
+        Ok(Ok(None)) => Err("Connection closed".to_string()),
+        Ok(Ok(Some(frame))) => {
+            let payload = match &wire.compressor {
+                Some(compressor) => compressor.open(&frame)?,
+                None => frame,
+            };
+            let msg = wire.codec.codec().decode(&payload)?;
             Ok(Some(msg))
         }
         Ok(Err(e)) => Err(e.to_string()),
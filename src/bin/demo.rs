@@ -1,7 +1,14 @@
 use clap::{Parser, Subcommand};
 use colored::*;
-use diff_sync::{truncate_text, SyncEngine, SyncResult};
+use diff_sync::{
+    handle_sync_message, truncate_text, CodecKind, Compression, DocumentHandle, DocumentStore,
+    MemoryStore, OffsetUnit, SessionManager, Storage, StorageKind, SyncEngine, SyncMessage,
+    SyncResult, SyncServer,
+};
 use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Parser)]
 #[command(name = "sync-demo")]
@@ -32,6 +39,23 @@ enum Commands {
         #[arg(short, long, default_value = "1000")]
         iterations: usize,
     },
+    /// Syncs/sec against in-memory document actors as sessions are added
+    LoadTest {
+        /// Largest number of concurrent sessions, doubling from 1
+        #[arg(short, long, default_value = "8")]
+        sessions: usize,
+        /// Clients editing each session's document
+        #[arg(short, long, default_value = "4")]
+        clients: usize,
+        /// Syncs sent by each client
+        #[arg(short = 'n', long, default_value = "200")]
+        syncs: usize,
+        /// Start sessions through a session manager keeping documents in
+        /// this storage (sqlite, memory or files), as the WebSocket server
+        /// does, instead of on bare in-memory actors
+        #[arg(long)]
+        storage: Option<StorageKind>,
+    },
 }
 
 fn main() {
@@ -40,6 +64,12 @@ fn main() {
         Commands::Interactive { initial_text } => run_interactive(initial_text),
         Commands::Simulate { iterations } => run_simulation(iterations),
         Commands::Benchmark { iterations } => run_benchmark(iterations),
+        Commands::LoadTest {
+            sessions,
+            clients,
+            syncs,
+            storage,
+        } => run_load_test(sessions, clients, syncs, storage),
     }
 }
 
@@ -180,6 +210,212 @@ fn run_benchmark(iterations: usize) {
     );
 }
 
+fn run_load_test(max_sessions: usize, clients: usize, syncs: usize, storage: Option<StorageKind>) {
+    println!("{}", "=== Document Actor Load Test ===".bold().cyan());
+    println!("{clients} clients per session, {syncs} syncs each");
+    match storage {
+        Some(kind) => println!("Sessions started by a session manager on {kind} storage\n"),
+        None => println!("Sessions on bare in-memory actors\n"),
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start tokio runtime");
+
+    let mut baseline = None;
+    let mut sessions = 1;
+    while sessions <= max_sessions.max(1) {
+        let (total, duration) = match storage {
+            Some(kind) => runtime.block_on(load_managed_sessions(kind, sessions, clients, syncs)),
+            None => runtime.block_on(load_sessions(sessions, clients, syncs)),
+        };
+        let rate = total as f64 / duration.as_secs_f64();
+        let speedup = rate / *baseline.get_or_insert(rate);
+        println!(
+            "{:>3} sessions: {} syncs in {duration:.2?} | {} syncs/sec ({speedup:.1}x)",
+            sessions,
+            total,
+            format!("{rate:.0}").green()
+        );
+        sessions *= 2;
+    }
+}
+
+/// Run `clients` clients against each of `sessions` fresh document actors and
+/// return the syncs completed and how long they took.
+async fn load_sessions(
+    sessions: usize,
+    clients: usize,
+    syncs: usize,
+) -> (usize, std::time::Duration) {
+    let mut handles = Vec::new();
+    for _ in 0..sessions {
+        let store = MemoryStore::new();
+        store
+            .update_document("main", load_document(clients))
+            .expect("in-memory document saves");
+        let mut server = SyncServer::new_with_store(Box::new(store), "main".to_string())
+            .expect("in-memory server starts");
+        server.quiet = true;
+        handles.push(DocumentHandle::spawn(server).expect("actor starts inside the runtime"));
+    }
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (session, handle) in handles.iter().enumerate() {
+        for client in 0..clients {
+            let handle = handle.clone();
+            tasks.push(tokio::spawn(run_load_client(
+                handle, session, client, syncs,
+            )));
+        }
+    }
+    let mut total = 0;
+    for task in tasks {
+        total += task.await.unwrap_or(0);
+    }
+    (total, start.elapsed())
+}
+
+/// Like [`load_sessions`], but with each session created in `kind` storage
+/// and every client looking its server up through a [`SessionManager`],
+/// while housekeeping flushes and sweeps all servers in the background.
+async fn load_managed_sessions(
+    kind: StorageKind,
+    sessions: usize,
+    clients: usize,
+    syncs: usize,
+) -> (usize, std::time::Duration) {
+    let path = scratch_path(kind, sessions);
+    let mut manager = SessionManager::new(Storage::new(kind, &path));
+    manager.set_quiet(true);
+    let manager = Arc::new(manager);
+
+    let tokens: Vec<String> = (0..sessions)
+        .map(|_| {
+            let (token, _) = manager
+                .create_session(&load_document(clients))
+                .expect("load test session is created");
+            token
+        })
+        .collect();
+
+    let housekeeping = tokio::spawn({
+        let manager = Arc::clone(&manager);
+        async move {
+            loop {
+                manager.cleanup_stale_clients(120).await;
+                for e in manager.flush_all().await {
+                    eprintln!("{e}");
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (session, token) in tokens.iter().enumerate() {
+        for client in 0..clients {
+            let manager = Arc::clone(&manager);
+            let token = token.clone();
+            tasks.push(tokio::spawn(async move {
+                let Ok((handle, _)) = manager.get_or_start_session(&token) else {
+                    return 0;
+                };
+                run_load_client(handle, session, client, syncs).await
+            }));
+        }
+    }
+    let mut total = 0;
+    for task in tasks {
+        total += task.await.unwrap_or(0);
+    }
+    let duration = start.elapsed();
+
+    housekeeping.abort();
+    for e in manager.flush_all().await {
+        eprintln!("{e}");
+    }
+    drop(manager);
+    let _ = match kind {
+        StorageKind::Files => std::fs::remove_dir_all(&path),
+        _ => std::fs::remove_file(&path),
+    };
+    (total, duration)
+}
+
+/// One line per client, so their edits never overlap.
+fn load_document(clients: usize) -> String {
+    (0..clients).map(|c| format!("client {c}:\n")).collect()
+}
+
+/// A fresh database file or store directory for one load test round.
+fn scratch_path(kind: StorageKind, sessions: usize) -> PathBuf {
+    let name = format!("diff-sync-load-{}-{sessions}", std::process::id());
+    let path = std::env::temp_dir().join(name);
+    match kind {
+        StorageKind::Files => path,
+        _ => path.with_extension("db"),
+    }
+}
+
+/// Connect, then type one character per sync at the end of this client's line.
+/// Returns the syncs the server answered.
+async fn run_load_client(
+    server: DocumentHandle,
+    session: usize,
+    client: usize,
+    syncs: usize,
+) -> usize {
+    let id = format!("load-{session}-{client}");
+    let line = format!("client {client}:");
+    let mut client_id = None;
+    let connect = SyncMessage::Connect {
+        client_id: id.clone(),
+        protocol_version: 1,
+        features: Vec::new(),
+        offset_unit: OffsetUnit::Bytes,
+        compact_edits: false,
+        codec: CodecKind::Json,
+        compression: Compression::None,
+    };
+    let Some(SyncMessage::ConnectOk { document, .. }) =
+        handle_sync_message(connect, &server, &mut client_id).await
+    else {
+        return 0;
+    };
+    let mut engine = SyncEngine::new(document.content);
+
+    let mut answered = 0;
+    for i in 0..syncs {
+        let mut typed = engine.text().to_string();
+        let Some(start) = typed.find(&line) else {
+            break;
+        };
+        let end = start + typed[start..].find('\n').unwrap_or(typed.len() - start);
+        typed.insert(end, (b'a' + (i % 26) as u8) as char);
+        engine.edit(&typed);
+        let sync = SyncMessage::ClientSync {
+            client_id: id.clone(),
+            edits: engine.diff_and_update_shadow(),
+            delta: None,
+            client_version: 0,
+            cursor_position: None,
+            packet: None,
+        };
+        if let Some(SyncMessage::ServerSync { edits, .. }) =
+            handle_sync_message(sync, &server, &mut client_id).await
+        {
+            if engine.apply_edits(edits).is_ok() {
+                answered += 1;
+            }
+        }
+    }
+    answered
+}
+
 fn print_state(alice: &SyncEngine, bob: &SyncEngine) {
     println!("\n{}", "Current State:".bold());
     println!(
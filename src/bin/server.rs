@@ -1,13 +1,7 @@
 use clap::Parser;
 use colored::*;
-use diff_sync::{
-    deserialize_message, handle_sync_message, serialize_message, truncate_text, DocumentDB,
-    SharedSyncServer, SyncMessage, SyncServer,
-};
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use diff_sync::{handle_tcp_client, truncate_text, DocumentDB, DocumentHandle, SyncServer};
+use tokio::net::TcpListener;
 use tokio::time::{interval, Duration};
 
 #[derive(Parser)]
@@ -22,6 +16,11 @@ struct Cli {
 
     #[arg(short, long, default_value = "main")]
     document_name: String,
+
+    /// Patch client edits whose checksum does not match the server's shadow
+    /// instead of resyncing the client
+    #[arg(long)]
+    lax_checksums: bool,
 }
 
 #[tokio::main]
@@ -35,32 +34,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let db = DocumentDB::new(&cli.database_path)
         .map_err(|e| format!("Failed to create database: {e}"))?;
 
-    let server: SharedSyncServer = Arc::new(Mutex::new(
-        SyncServer::new_with_db(db, cli.document_name)
-            .map_err(|e| format!("Failed to create server: {e}"))?,
-    ));
-
-    {
-        let lock = server.lock().await;
-        let doc = lock
-            .get_current_document()
-            .map_err(|e| format!("Failed to load document: {e}"))?;
-        println!("Initial content: \"{}\"", doc.content.blue());
-    }
+    let mut server = SyncServer::new_with_store(Box::new(db), cli.document_name)
+        .map_err(|e| format!("Failed to create server: {e}"))?;
+    server.strict_checksums = !cli.lax_checksums;
+    let doc = server
+        .get_current_document()
+        .map_err(|e| format!("Failed to load document: {e}"))?;
+    println!("Initial content: \"{}\"", doc.content.blue());
+    let server = DocumentHandle::spawn(server)?;
 
     let listener = TcpListener::bind(&cli.address).await?;
     println!("Listening on {}", cli.address.green());
 
-    spawn_cleanup_task(Arc::clone(&server));
-    spawn_status_task(Arc::clone(&server));
+    spawn_cleanup_task(server.clone());
+    spawn_status_task(server.clone());
 
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        match accepted {
             Ok((stream, addr)) => {
                 println!("New connection from {}", addr.to_string().yellow());
-                let server_clone = Arc::clone(&server);
+                let server_clone = server.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_client(stream, server_clone).await {
+                    if let Err(e) = handle_tcp_client(stream, server_clone).await {
                         eprintln!("Client error: {}", e.to_string().red());
                     }
                 });
@@ -68,79 +67,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Err(e) => eprintln!("Failed to accept connection: {e}"),
         }
     }
+
+    println!("\nShutting down...");
+    server.call(|server| server.flush()).await??;
+    Ok(())
 }
 
-fn spawn_cleanup_task(server: SharedSyncServer) {
+fn spawn_cleanup_task(server: DocumentHandle) {
     tokio::spawn(async move {
         let mut timer = interval(Duration::from_secs(30));
         loop {
             timer.tick().await;
-            server.lock().await.cleanup_stale_clients(120);
+            let _ = server
+                .call(|server| server.cleanup_stale_clients(120))
+                .await;
         }
     });
 }
 
-fn spawn_status_task(server: SharedSyncServer) {
+fn spawn_status_task(server: DocumentHandle) {
     tokio::spawn(async move {
         let mut timer = interval(Duration::from_secs(10));
         loop {
             timer.tick().await;
-            let lock = server.lock().await;
-            let clients = lock.get_connected_clients();
-            if !clients.is_empty() {
-                let content = lock
-                    .get_document_content()
-                    .unwrap_or_else(|_| "Error loading document".to_string());
+            let status = server
+                .call(|server| {
+                    let clients = server.get_connected_clients().len();
+                    (clients, server.get_document_content())
+                })
+                .await;
+            let Ok((clients, content)) = status else {
+                break;
+            };
+            if clients > 0 {
+                let content = content.unwrap_or_else(|_| "Error loading document".to_string());
                 println!(
                     "Active clients: {} | Document: \"{}\"",
-                    clients.len().to_string().cyan(),
+                    clients.to_string().cyan(),
                     truncate_text(&content, 40).dimmed()
                 );
             }
         }
     });
 }
-
-async fn handle_client(stream: TcpStream, server: SharedSyncServer) -> Result<(), String> {
-    let (read_half, mut write_half) = stream.into_split();
-    let mut reader = BufReader::new(read_half);
-    let mut line = String::new();
-    let mut client_id: Option<String> = None;
-
-    loop {
-        line.clear();
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                if let Some(id) = &client_id {
-                    server.lock().await.disconnect_client(id);
-                }
-                break;
-            }
-            Ok(_) => match deserialize_message(line.as_bytes()) {
-                Ok(message) => {
-                    if let Some(response) =
-                        handle_sync_message(message, &server, &mut client_id).await
-                    {
-                        let data = serialize_message(&response).map_err(|e| e.to_string())?;
-                        write_half
-                            .write_all(&data)
-                            .await
-                            .map_err(|e| e.to_string())?;
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Failed to parse message: {e}");
-                    let error_msg = SyncMessage::Error {
-                        message: format!("Invalid message format: {e}"),
-                    };
-                    if let Ok(data) = serialize_message(&error_msg) {
-                        let _ = write_half.write_all(&data).await;
-                    }
-                }
-            },
-            Err(e) => return Err(e.to_string()),
-        }
-    }
-
-    Ok(())
-}
@@ -9,14 +9,15 @@ use axum::{
 use clap::Parser;
 use colored::*;
 use diff_sync::{
-    handle_sync_message, DocumentDB, SessionError, SessionManager,
-    SharedSessionManager, SharedSyncServer, SyncMessage,
+    handle_sync_message, handle_tcp_client, push_signalled, CodecKind, Compression, Compressor,
+    SessionError, SessionManager, SharedSessionManager, Storage, StorageKind, SyncMessage,
+    DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_FLUSH_DELAY, DEFAULT_FLUSH_EVERY,
+    DEFAULT_LINE_MODE_THRESHOLD,
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
 use tokio::time::{interval, Duration};
 use tower_http::cors::CorsLayer;
 use tower_http::services::{ServeDir, ServeFile};
@@ -31,11 +32,42 @@ struct Cli {
     #[arg(long, default_value = "127.0.0.1:8081")]
     ws_address: String,
 
+    /// Where documents are kept: sqlite, memory or files
+    #[arg(long, default_value = "sqlite")]
+    storage: StorageKind,
+
+    /// SQLite database file, or directory for --storage files
     #[arg(short, long, default_value = "documents.db")]
     database_path: String,
 
     #[arg(long, default_value = "web/out")]
     static_dir: String,
+
+    /// Patch client edits whose checksum does not match the server's shadow
+    /// instead of resyncing the client
+    #[arg(long)]
+    lax_checksums: bool,
+
+    /// Diff documents of at least this many bytes line by line
+    #[arg(long, default_value_t = DEFAULT_LINE_MODE_THRESHOLD)]
+    line_mode_threshold: usize,
+
+    /// Compressions offered to TCP and WebSocket clients, comma separated
+    /// ("none" for none)
+    #[arg(long, value_delimiter = ',', default_value = "deflate,zstd")]
+    compression: Vec<Compression>,
+
+    /// Compress message payloads of at least this many bytes
+    #[arg(long, default_value_t = DEFAULT_COMPRESSION_THRESHOLD)]
+    compression_threshold: usize,
+
+    /// Write a changed document back to the database after this many ms
+    #[arg(long, default_value_t = DEFAULT_FLUSH_DELAY.as_millis() as u64)]
+    flush_delay_ms: u64,
+
+    /// Also write it back every this many versions
+    #[arg(long, default_value_t = DEFAULT_FLUSH_EVERY)]
+    flush_every: u64,
 }
 
 #[tokio::main]
@@ -48,14 +80,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
     println!("TCP: {}", cli.tcp_address.cyan());
     println!("WS:  {}", cli.ws_address.cyan());
-    println!("DB:  {}", cli.database_path.cyan());
-
-    DocumentDB::new(&cli.database_path)
-        .map_err(|e| format!("Failed to initialize database: {e}"))?;
+    match cli.storage {
+        StorageKind::Memory => println!("DB:  {}", "memory".cyan()),
+        kind => println!("DB:  {} ({kind})", cli.database_path.cyan()),
+    }
 
-    let manager: SharedSessionManager = Arc::new(Mutex::new(SessionManager::new(
-        cli.database_path.clone(),
-    )));
+    let storage = Storage::new(cli.storage, &cli.database_path);
+    storage
+        .open()
+        .map_err(|e| format!("Failed to initialize storage: {e}"))?;
+
+    let mut manager = SessionManager::new(storage);
+    manager.set_strict_checksums(!cli.lax_checksums);
+    manager.set_line_mode_threshold(cli.line_mode_threshold);
+    let compression = cli
+        .compression
+        .iter()
+        .copied()
+        .filter(|&c| c != Compression::None)
+        .collect();
+    manager.set_compression(compression, cli.compression_threshold);
+    manager.set_write_behind(Duration::from_millis(cli.flush_delay_ms), cli.flush_every);
+    let compression_stats = manager.compression_stats();
+    let manager: SharedSessionManager = Arc::new(manager);
 
     spawn_cleanup_task(Arc::clone(&manager));
 
@@ -73,6 +120,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/api/sessions", post(create_session_handler))
         .route("/api/sessions/:token", get(get_session_handler))
         .route("/api/sessions/:token/close", post(close_session_handler))
+        .route("/api/stats/compression", get(compression_stats_handler))
+        .route(
+            "/api/documents/:name/revisions",
+            get(list_revisions_handler),
+        )
+        .route("/api/documents/:name/blame", get(blame_handler))
+        .route(
+            "/api/documents/:name/revisions/:version",
+            get(get_revision_handler),
+        )
+        .route(
+            "/api/documents/:name/revisions/:version/restore",
+            post(restore_revision_handler),
+        )
         .route("/health", get(|| async { "ok" }))
         .with_state(Arc::clone(&manager))
         .fallback_service(ServeDir::new(&cli.static_dir).not_found_service(index))
@@ -86,6 +147,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_graceful_shutdown(shutdown_signal())
         .await?;
 
+    let report = compression_stats.report();
+    if report.messages > 0 {
+        println!(
+            "Compression: {} of {} payloads compressed, {} -> {} bytes (ratio {:.2})",
+            report.compressed_messages,
+            report.messages,
+            report.raw_bytes,
+            report.wire_bytes,
+            report.ratio
+        );
+    }
+    for e in manager.flush_all().await {
+        eprintln!("{e}");
+    }
     println!("Server shutting down...");
     Ok(())
 }
@@ -95,9 +170,10 @@ fn spawn_cleanup_task(manager: SharedSessionManager) {
         let mut timer = interval(Duration::from_secs(30));
         loop {
             timer.tick().await;
-            let mut mgr = manager.lock().await;
-            mgr.cleanup_stale_clients(120).await;
-            mgr.cleanup_idle_sessions(Duration::from_secs(300)).await;
+            manager.cleanup_stale_clients(120).await;
+            manager
+                .cleanup_idle_sessions(Duration::from_secs(300))
+                .await;
         }
     });
 }
@@ -127,8 +203,7 @@ async fn create_session_handler(
     Json(body): Json<CreateSessionRequest>,
 ) -> impl IntoResponse {
     let content = body.initial_content.as_deref().unwrap_or("");
-    let mgr = manager.lock().await;
-    match mgr.create_session(content) {
+    match manager.create_session(content) {
         Ok((token, creator_secret)) => {
             let url = format!("/s/{token}");
             println!("Session created: {}", token.green());
@@ -161,8 +236,7 @@ async fn get_session_handler(
     State(manager): State<SharedSessionManager>,
     Path(token): Path<String>,
 ) -> impl IntoResponse {
-    let mgr = manager.lock().await;
-    match mgr.get_session(&token) {
+    match manager.get_session(&token) {
         Ok(session) => (
             StatusCode::OK,
             Json(SessionInfoResponse {
@@ -178,6 +252,64 @@ async fn get_session_handler(
     }
 }
 
+async fn compression_stats_handler(
+    State(manager): State<SharedSessionManager>,
+) -> impl IntoResponse {
+    let stats = manager.compression_stats();
+    Json(stats.report())
+}
+
+async fn list_revisions_handler(
+    State(manager): State<SharedSessionManager>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match manager.list_revisions(&name) {
+        Ok(revisions) => (StatusCode::OK, Json(revisions)).into_response(),
+        Err(SessionError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn blame_handler(
+    State(manager): State<SharedSessionManager>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match manager.blame(&name) {
+        Ok(spans) => (StatusCode::OK, Json(spans)).into_response(),
+        Err(SessionError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn get_revision_handler(
+    State(manager): State<SharedSessionManager>,
+    Path((name, version)): Path<(String, u64)>,
+) -> impl IntoResponse {
+    match manager.load_document_at(&name, version) {
+        Ok(document) => (StatusCode::OK, Json(document)).into_response(),
+        Err(SessionError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn restore_revision_handler(
+    State(manager): State<SharedSessionManager>,
+    Path((name, version)): Path<(String, u64)>,
+) -> impl IntoResponse {
+    match manager.restore_document(&name, version).await {
+        Ok(document) => {
+            println!(
+                "Restored {} to version {version} (v{})",
+                name.yellow(),
+                document.version
+            );
+            (StatusCode::OK, Json(document)).into_response()
+        }
+        Err(SessionError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
 #[derive(Deserialize)]
 struct CloseSessionRequest {
     creator_secret: String,
@@ -193,8 +325,7 @@ async fn close_session_handler(
     Path(token): Path<String>,
     Json(body): Json<CloseSessionRequest>,
 ) -> impl IntoResponse {
-    let mut mgr = manager.lock().await;
-    match mgr.close_session(&token, &body.creator_secret).await {
+    match manager.close_session(&token, &body.creator_secret) {
         Ok(()) => {
             println!("Session closed: {}", token.yellow());
             (StatusCode::OK, Json(CloseSessionResponse { ok: true })).into_response()
@@ -223,8 +354,7 @@ async fn ws_handler(
     let session_token = params.session;
 
     if let Some(ref token) = session_token {
-        let mgr = manager.lock().await;
-        match mgr.get_session(token) {
+        match manager.get_session(token) {
             Ok(session) if session.status == "active" => {}
             Ok(_) => {
                 return (StatusCode::GONE, "Session has ended").into_response();
@@ -258,36 +388,33 @@ async fn handle_ws_client(
     manager: SharedSessionManager,
     session_token: Option<String>,
 ) {
-    let (server, mut shutdown) = {
-        let mut mgr = manager.lock().await;
-        if let Some(ref token) = session_token {
-            match mgr.get_or_start_session(token) {
-                Ok((s, rx)) => (s, Some(rx)),
-                Err(SessionError::Closed) => {
-                    let closed = SyncMessage::SessionClosed;
-                    if let Ok(json) = serde_json::to_string(&closed) {
-                        let _ = socket.send(Message::Text(json.into())).await;
-                    }
-                    return;
-                }
-                Err(e) => {
-                    eprintln!("Failed to start session: {e}");
-                    let err = SyncMessage::Error {
-                        message: e.to_string(),
-                    };
-                    if let Ok(json) = serde_json::to_string(&err) {
-                        let _ = socket.send(Message::Text(json.into())).await;
-                    }
-                    return;
+    let (server, mut shutdown) = if let Some(ref token) = session_token {
+        match manager.get_or_start_session(token) {
+            Ok((s, rx)) => (s, Some(rx)),
+            Err(SessionError::Closed) => {
+                let closed = SyncMessage::SessionClosed;
+                if let Ok(json) = serde_json::to_string(&closed) {
+                    let _ = socket.send(Message::Text(json)).await;
                 }
+                return;
             }
-        } else {
-            match mgr.default_server() {
-                Ok(s) => (s, None),
-                Err(e) => {
-                    eprintln!("Failed to start default server: {e}");
-                    return;
+            Err(e) => {
+                eprintln!("Failed to start session: {e}");
+                let err = SyncMessage::Error {
+                    message: e.to_string(),
+                };
+                if let Ok(json) = serde_json::to_string(&err) {
+                    let _ = socket.send(Message::Text(json)).await;
                 }
+                return;
+            }
+        }
+    } else {
+        match manager.default_server() {
+            Ok(s) => (s, None),
+            Err(e) => {
+                eprintln!("Failed to start default server: {e}");
+                return;
             }
         }
     };
@@ -299,62 +426,117 @@ async fn handle_ws_client(
     println!("New WebSocket connection ({})", label.cyan());
 
     let mut client_id: Option<String> = None;
+    // Text frames are always JSON; binary frames use the codec agreed in
+    // `ConnectOk`, inside an envelope once a compression is agreed too.
+    let mut codec = CodecKind::Json;
+    let mut compressor: Option<Compressor> = None;
+    // What the last `Connect` asked for: its `ConnectOk` is enveloped even if
+    // the server declines.
+    let mut requested = Compression::None;
+    let mut push_signal = None;
 
     loop {
         tokio::select! {
             _ = await_session_closed(&mut shutdown) => {
                 println!("Session closed, disconnecting client ({})", label.yellow());
-                let closed = SyncMessage::SessionClosed;
-                if let Ok(json) = serde_json::to_string(&closed) {
-                    let _ = socket.send(Message::Text(json.into())).await;
+                if let Ok(frame) = ws_frame(&SyncMessage::SessionClosed, codec, compressor.as_ref()) {
+                    let _ = socket.send(frame).await;
                 }
                 let _ = socket.send(Message::Close(None)).await;
                 break;
             }
+            () = push_signalled(&mut push_signal) => {
+                let Some(id) = client_id.clone() else { continue };
+                let Ok(Some(push)) = server.call(move |server| server.push_for(&id)).await else {
+                    continue;
+                };
+                match ws_frame(&push, codec, compressor.as_ref()) {
+                    Ok(frame) => {
+                        if socket.send(frame).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to serialize WS push: {e}"),
+                }
+            }
             incoming = socket.recv() => {
                 let msg = match incoming {
                     Some(Ok(msg)) => msg,
                     _ => break,
                 };
-                match msg {
-                    Message::Text(text) => {
-                        let parsed: Result<SyncMessage, _> = serde_json::from_str(&text);
-                        match parsed {
-                            Ok(message) => {
-                                let response =
-                                    handle_sync_message(message, &server, &mut client_id).await;
-                                if let Some(resp) = response {
-                                    match serde_json::to_string(&resp) {
-                                        Ok(json) => {
-                                            if socket.send(Message::Text(json.into())).await.is_err() {
-                                                break;
-                                            }
-                                        }
-                                        Err(e) => eprintln!("Failed to serialize WS response: {e}"),
-                                    }
-                                }
-                            }
+                let parsed = match msg {
+                    Message::Text(text) => CodecKind::Json.codec().decode(text.as_bytes()),
+                    Message::Binary(data) => match &compressor {
+                        Some(compressor) => compressor
+                            .open(&data)
+                            .and_then(|payload| codec.codec().decode(&payload)),
+                        None => codec.codec().decode(&data),
+                    },
+                    Message::Close(_) => break,
+                    _ => continue,
+                };
+                let response = match parsed {
+                    Ok(message) => {
+                        if let SyncMessage::Connect { compression, .. } = &message {
+                            requested = *compression;
+                        }
+                        handle_sync_message(message, &server, &mut client_id).await
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to parse WS message: {e}");
+                        Some(SyncMessage::Error {
+                            message: format!("Invalid message format: {e}"),
+                        })
+                    }
+                };
+                if let Some(resp) = response {
+                    let mut declined_envelope = false;
+                    if let SyncMessage::ConnectOk { compression, .. } = &resp {
+                        let (compression, id) = (*compression, client_id.clone());
+                        match server
+                            .call(move |server| {
+                                let compressor = server.compression.compressor(compression);
+                                (compressor, id.and_then(|id| server.subscribe(&id)))
+                            })
+                            .await
+                        {
+                            Ok((agreed, signal)) => (compressor, push_signal) = (agreed, signal),
                             Err(e) => {
-                                eprintln!("Failed to parse WS message: {e}");
-                                let err = SyncMessage::Error {
-                                    message: format!("Invalid message format: {e}"),
-                                };
-                                if let Ok(json) = serde_json::to_string(&err) {
-                                    let _ = socket.send(Message::Text(json.into())).await;
-                                }
+                                eprintln!("{e}");
+                                break;
                             }
                         }
+                        declined_envelope =
+                            compressor.is_none() && requested != Compression::None;
+                    }
+                    let frame = match declined_envelope {
+                        true => codec
+                            .codec()
+                            .encode(&resp)
+                            .map(|data| Message::Binary(Compression::seal_bare(&data))),
+                        false => ws_frame(&resp, codec, compressor.as_ref()),
+                    };
+                    if let SyncMessage::ConnectOk { codec: agreed, .. } = resp {
+                        codec = agreed;
+                    }
+                    match frame {
+                        Ok(frame) => {
+                            if socket.send(frame).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => eprintln!("Failed to serialize WS response: {e}"),
                     }
-                    Message::Close(_) => break,
-                    _ => {}
                 }
             }
         }
     }
 
-    if let Some(id) = &client_id {
+    if let Some(id) = client_id {
         println!("WebSocket client {} disconnected ({})", id, label);
-        server.lock().await.disconnect_client(id);
+        let _ = server
+            .call(move |server| server.disconnect_client(&id))
+            .await;
     }
 }
 
@@ -370,14 +552,11 @@ async fn run_tcp_listener(
                 println!("New TCP connection from {}", addr.to_string().yellow());
                 let mgr = Arc::clone(&manager);
                 tokio::spawn(async move {
-                    let server = {
-                        let mut m = mgr.lock().await;
-                        match m.default_server() {
-                            Ok(s) => s,
-                            Err(e) => {
-                                eprintln!("Failed to start default server: {e}");
-                                return;
-                            }
+                    let server = match mgr.default_server() {
+                        Ok(s) => s,
+                        Err(e) => {
+                            eprintln!("Failed to start default server: {e}");
+                            return;
                         }
                     };
                     if let Err(e) = handle_tcp_client(stream, server).await {
@@ -390,47 +569,21 @@ async fn run_tcp_listener(
     }
 }
 
-async fn handle_tcp_client(stream: TcpStream, server: SharedSyncServer) -> Result<(), String> {
-    let (read_half, mut write_half) = stream.into_split();
-    let mut reader = BufReader::new(read_half);
-    let mut line = String::new();
-    let mut client_id: Option<String> = None;
-
-    loop {
-        line.clear();
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                if let Some(id) = &client_id {
-                    server.lock().await.disconnect_client(id);
-                }
-                break;
-            }
-            Ok(_) => match diff_sync::deserialize_message(line.as_bytes()) {
-                Ok(message) => {
-                    if let Some(response) =
-                        handle_sync_message(message, &server, &mut client_id).await
-                    {
-                        let data =
-                            diff_sync::serialize_message(&response).map_err(|e| e.to_string())?;
-                        write_half
-                            .write_all(&data)
-                            .await
-                            .map_err(|e| e.to_string())?;
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Failed to parse TCP message: {e}");
-                    let err = SyncMessage::Error {
-                        message: format!("Invalid message format: {e}"),
-                    };
-                    if let Ok(data) = diff_sync::serialize_message(&err) {
-                        let _ = write_half.write_all(&data).await;
-                    }
-                }
-            },
-            Err(e) => return Err(e.to_string()),
-        }
+/// Encode a message as a text frame for JSON, or a binary frame otherwise.
+/// Once a compression is agreed, every frame is a binary envelope.
+fn ws_frame(
+    message: &SyncMessage,
+    codec: CodecKind,
+    compressor: Option<&Compressor>,
+) -> Result<Message, String> {
+    if let Some(compressor) = compressor {
+        let payload = codec.codec().encode(message)?;
+        return Ok(Message::Binary(compressor.seal(&payload)));
+    }
+    match codec {
+        CodecKind::Json => serde_json::to_string(message)
+            .map(Message::Text)
+            .map_err(|e| e.to_string()),
+        _ => codec.codec().encode(message).map(Message::Binary),
     }
-
-    Ok(())
 }
@@ -0,0 +1,619 @@
+use crate::SyncMessage;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Encoding of a [`SyncMessage`] on the wire.
+pub trait Codec: Send + Sync {
+    fn kind(&self) -> CodecKind;
+    fn encode(&self, message: &SyncMessage) -> Result<Vec<u8>, String>;
+    fn decode(&self, data: &[u8]) -> Result<SyncMessage, String>;
+}
+
+/// Externally-tagged JSON, as spoken by the web client.
+pub struct JsonCodec;
+
+/// MessagePack with named fields, so optional fields may be omitted.
+pub struct MessagePackCodec;
+
+pub struct CborCodec;
+
+impl Codec for JsonCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Json
+    }
+
+    fn encode(&self, message: &SyncMessage) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(message).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<SyncMessage, String> {
+        let text = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+        serde_json::from_str(text.trim()).map_err(|e| e.to_string())
+    }
+}
+
+impl Codec for MessagePackCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::MessagePack
+    }
+
+    fn encode(&self, message: &SyncMessage) -> Result<Vec<u8>, String> {
+        rmp_serde::to_vec_named(message).map_err(|e| e.to_string())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<SyncMessage, String> {
+        rmp_serde::from_slice(data).map_err(|e| e.to_string())
+    }
+}
+
+impl Codec for CborCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Cbor
+    }
+
+    fn encode(&self, message: &SyncMessage) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        ciborium::into_writer(message, &mut data).map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<SyncMessage, String> {
+        ciborium::from_reader(data).map_err(|e| e.to_string())
+    }
+}
+
+/// Codec named in `Connect`/`ConnectOk`. Both of those are always JSON; the
+/// codec agreed in `ConnectOk` applies from the next message on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodecKind {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl CodecKind {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            Self::Json => &JsonCodec,
+            Self::MessagePack => &MessagePackCodec,
+            Self::Cbor => &CborCodec,
+        }
+    }
+
+    /// Binary codecs need length-prefixed frames on TCP and binary frames on
+    /// WebSocket.
+    pub fn is_binary(self) -> bool {
+        self != Self::Json
+    }
+}
+
+impl fmt::Display for CodecKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            Self::MessagePack => write!(f, "msgpack"),
+            Self::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "msgpack" | "messagepack" => Ok(Self::MessagePack),
+            "cbor" => Ok(Self::Cbor),
+            _ => Err(format!(
+                "Unknown codec '{s}' (expected json, msgpack or cbor)"
+            )),
+        }
+    }
+}
+
+/// Largest frame accepted on a length-prefixed stream.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// How messages are delimited on a TCP stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One JSON message per line, as older clients send.
+    Lines,
+    /// Each message preceded by its length as a big-endian `u32`, so binary
+    /// payloads may contain newlines.
+    LengthPrefixed,
+}
+
+impl Framing {
+    /// Framing to use for a codec: JSON keeps the line protocol.
+    pub fn for_codec(codec: CodecKind) -> Self {
+        match codec.is_binary() {
+            true => Self::LengthPrefixed,
+            false => Self::Lines,
+        }
+    }
+
+    /// Tell the framing from the first byte the peer sent without consuming
+    /// it. A length prefix starts with a zero byte, since frames are smaller
+    /// than 16 MiB, while a JSON line never does. `None` at end of stream.
+    pub async fn detect<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Self>> {
+        let buf = reader.fill_buf().await?;
+        Ok(buf.first().map(|&b| match b {
+            0 => Self::LengthPrefixed,
+            _ => Self::Lines,
+        }))
+    }
+
+    /// Read the next frame's payload, or `None` at end of stream.
+    pub async fn read_frame<R: AsyncBufRead + Unpin>(
+        self,
+        reader: &mut R,
+    ) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Self::Lines => {
+                let mut line = Vec::new();
+                if reader.read_until(b'\n', &mut line).await? == 0 {
+                    return Ok(None);
+                }
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                Ok(Some(line))
+            }
+            Self::LengthPrefixed => {
+                let len = match reader.read_u32().await {
+                    Ok(len) => len as usize,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                if len > MAX_FRAME_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Frame of {len} bytes exceeds the {MAX_FRAME_LEN} byte limit"),
+                    ));
+                }
+                let mut payload = vec![0; len];
+                reader.read_exact(&mut payload).await?;
+                Ok(Some(payload))
+            }
+        }
+    }
+
+    pub async fn write_frame<W: AsyncWrite + Unpin>(
+        self,
+        writer: &mut W,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        match self {
+            Self::Lines => {
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+            }
+            Self::LengthPrefixed => {
+                let len = u32::try_from(payload.len())
+                    .ok()
+                    .filter(|&len| len as usize <= MAX_FRAME_LEN)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "Frame too large")
+                    })?;
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.extend_from_slice(payload);
+            }
+        }
+        writer.write_all(&frame).await
+    }
+}
+
+/// Compression of message payloads, named in `Connect`/`ConnectOk`.
+///
+/// Once a compression other than `None` is agreed, every payload in either
+/// direction, starting with `ConnectOk` itself, is wrapped in an envelope: one
+/// tag byte naming the compression used for that payload, then the payload.
+/// Payloads below the sender's threshold travel uncompressed with tag 0.
+/// A client that asks for compression always gets its `ConnectOk` in an
+/// envelope, with tag 0 if the server declines, and bare payloads after that.
+/// Length-prefixed TCP connections and WebSocket binary frames carry
+/// envelopes; line-framed connections are answered with `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zlib-wrapped deflate.
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Deflate => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Self::None),
+            1 => Some(Self::Deflate),
+            2 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::encode_all(data, 0),
+        }
+    }
+
+    /// Inflate a payload, refusing output larger than [`MAX_FRAME_LEN`] so a
+    /// small frame cannot expand without bound.
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::None => Box::new(data),
+            Self::Deflate => Box::new(ZlibDecoder::new(data)),
+            Self::Zstd => Box::new(zstd::Decoder::new(data)?),
+        };
+        let mut out = Vec::new();
+        reader
+            .take(MAX_FRAME_LEN as u64 + 1)
+            .read_to_end(&mut out)?;
+        if out.len() > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Payload inflates beyond the {MAX_FRAME_LEN} byte limit"),
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Envelope a payload uncompressed, as for a `ConnectOk` declining the
+    /// compression a client asked for.
+    pub fn seal_bare(payload: &[u8]) -> Vec<u8> {
+        let mut envelope = Vec::with_capacity(payload.len() + 1);
+        envelope.push(Self::None.tag());
+        envelope.extend_from_slice(payload);
+        envelope
+    }
+
+    /// Unwrap an envelope, whichever compression its tag names.
+    pub fn open(envelope: &[u8]) -> Result<Vec<u8>, String> {
+        let (&tag, payload) = envelope.split_first().ok_or("Empty payload")?;
+        let compression =
+            Self::from_tag(tag).ok_or_else(|| format!("Unknown compression tag {tag}"))?;
+        compression.decompress(payload).map_err(|e| e.to_string())
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Deflate => write!(f, "deflate"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "deflate" => Ok(Self::Deflate),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!(
+                "Unknown compression '{s}' (expected none, deflate or zstd)"
+            )),
+        }
+    }
+}
+
+/// Default for [`CompressionSettings::threshold`].
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Payload bytes before and after compression, summed over connections and
+/// both directions.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    messages: AtomicU64,
+    compressed_messages: AtomicU64,
+    raw_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+}
+
+/// Snapshot of [`CompressionStats`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CompressionReport {
+    pub messages: u64,
+    pub compressed_messages: u64,
+    pub raw_bytes: u64,
+    pub wire_bytes: u64,
+    /// `wire_bytes / raw_bytes`; 1.0 before any traffic.
+    pub ratio: f64,
+}
+
+impl CompressionStats {
+    pub fn record(&self, raw: usize, wire: usize, compressed: bool) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        if compressed {
+            self.compressed_messages.fetch_add(1, Ordering::Relaxed);
+        }
+        self.raw_bytes.fetch_add(raw as u64, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> CompressionReport {
+        let raw_bytes = self.raw_bytes.load(Ordering::Relaxed);
+        let wire_bytes = self.wire_bytes.load(Ordering::Relaxed);
+        CompressionReport {
+            messages: self.messages.load(Ordering::Relaxed),
+            compressed_messages: self.compressed_messages.load(Ordering::Relaxed),
+            raw_bytes,
+            wire_bytes,
+            ratio: match raw_bytes {
+                0 => 1.0,
+                raw => wire_bytes as f64 / raw as f64,
+            },
+        }
+    }
+}
+
+/// Which compressions a server accepts and from what payload size it uses
+/// them.
+#[derive(Debug, Clone)]
+pub struct CompressionSettings {
+    pub allowed: Vec<Compression>,
+    pub threshold: usize,
+    pub stats: Arc<CompressionStats>,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            allowed: vec![Compression::Deflate, Compression::Zstd],
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            stats: Arc::default(),
+        }
+    }
+}
+
+impl CompressionSettings {
+    /// The compression to agree to when a client asks for `requested`.
+    pub fn accept(&self, requested: Compression) -> Compression {
+        match self.allowed.contains(&requested) {
+            true => requested,
+            false => Compression::None,
+        }
+    }
+
+    /// Envelope handling for a connection, or `None` if payloads are sent
+    /// bare.
+    pub fn compressor(&self, compression: Compression) -> Option<Compressor> {
+        (compression != Compression::None).then(|| Compressor {
+            compression,
+            threshold: self.threshold,
+            stats: Arc::clone(&self.stats),
+        })
+    }
+}
+
+/// Wraps and unwraps the payloads of one connection once compression is
+/// agreed.
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    pub compression: Compression,
+    pub threshold: usize,
+    pub stats: Arc<CompressionStats>,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression, threshold: usize) -> Self {
+        Self {
+            compression,
+            threshold,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Envelope a payload, compressed if it is at least `threshold` bytes and
+    /// compression actually shrinks it.
+    pub fn seal(&self, payload: &[u8]) -> Vec<u8> {
+        let compressed = match payload.len() >= self.threshold {
+            true => self
+                .compression
+                .compress(payload)
+                .ok()
+                .filter(|data| data.len() < payload.len()),
+            false => None,
+        };
+        let (tag, body) = match &compressed {
+            Some(data) => (self.compression.tag(), data.as_slice()),
+            None => (Compression::None.tag(), payload),
+        };
+        let mut envelope = Vec::with_capacity(body.len() + 1);
+        envelope.push(tag);
+        envelope.extend_from_slice(body);
+        self.stats
+            .record(payload.len(), envelope.len(), compressed.is_some());
+        envelope
+    }
+
+    pub fn open(&self, envelope: &[u8]) -> Result<Vec<u8>, String> {
+        let payload = Compression::open(envelope)?;
+        let compressed = envelope.first() != Some(&Compression::None.tag());
+        self.stats.record(payload.len(), envelope.len(), compressed);
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diff, Document, OffsetUnit};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn sample_messages() -> Vec<SyncMessage> {
+        vec![
+            SyncMessage::Connect {
+                client_id: "alice".to_string(),
+                protocol_version: 2,
+                features: vec!["resync".to_string()],
+                offset_unit: OffsetUnit::Utf16,
+                compact_edits: true,
+                codec: CodecKind::Cbor,
+                compression: Compression::Zstd,
+            },
+            SyncMessage::ConnectOk {
+                server_version: 3,
+                document: Document::new("Hello\nworld 😀".to_string()),
+                protocol_version: 2,
+                features: Vec::new(),
+                offset_unit: OffsetUnit::Bytes,
+                compact_edits: false,
+                codec: CodecKind::MessagePack,
+                compression: Compression::Deflate,
+            },
+            SyncMessage::ClientSync {
+                client_id: "alice".to_string(),
+                edits: diff("Hello\nworld", "Hello,\nbrave world"),
+                delta: None,
+                client_version: 7,
+                cursor_position: Some(4),
+                packet: None,
+            },
+            SyncMessage::Error {
+                message: "nope".to_string(),
+            },
+            SyncMessage::Ping,
+        ]
+    }
+
+    #[test]
+    fn test_codecs_round_trip_messages() {
+        for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Cbor] {
+            let codec = kind.codec();
+            assert_eq!(codec.kind(), kind);
+            for message in sample_messages() {
+                let decoded = codec.decode(&codec.encode(&message).unwrap()).unwrap();
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(&message).unwrap(),
+                    "{kind}"
+                );
+            }
+        }
+
+        // Binary encodings are smaller than JSON for a typical sync.
+        let sync = &sample_messages()[2];
+        let json = CodecKind::Json.codec().encode(sync).unwrap().len();
+        assert!(CodecKind::MessagePack.codec().encode(sync).unwrap().len() < json);
+        assert!(CodecKind::Cbor.codec().encode(sync).unwrap().len() < json);
+    }
+
+    #[test]
+    fn test_codec_kind_parses_names() {
+        for kind in [CodecKind::Json, CodecKind::MessagePack, CodecKind::Cbor] {
+            assert_eq!(kind.to_string().parse::<CodecKind>(), Ok(kind));
+        }
+        assert!("xml".parse::<CodecKind>().is_err());
+    }
+
+    #[test]
+    fn test_compression_round_trips_and_parses_names() {
+        let text = "the quick brown fox jumps over the lazy dog\n".repeat(100);
+        for compression in [Compression::None, Compression::Deflate, Compression::Zstd] {
+            assert_eq!(compression.to_string().parse(), Ok(compression));
+            let packed = compression.compress(text.as_bytes()).unwrap();
+            assert_eq!(compression.decompress(&packed).unwrap(), text.as_bytes());
+        }
+        assert!("lz4".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_seal_compresses_only_above_threshold() {
+        let compressor = Compressor::new(Compression::Deflate, 64);
+        let large = "x".repeat(4096);
+        for payload in [&b"\"Ping\""[..], large.as_bytes()] {
+            let envelope = compressor.seal(payload);
+            assert_eq!(compressor.open(&envelope).unwrap(), payload);
+        }
+        assert_eq!(compressor.seal(b"\"Ping\"")[0], 0);
+        assert_eq!(compressor.seal(large.as_bytes())[0], 1);
+
+        // Incompressible payloads above the threshold travel bare.
+        let mut noise = vec![0u8; 1024];
+        StdRng::seed_from_u64(18).fill(&mut noise[..]);
+        assert_eq!(compressor.seal(&noise)[0], 0);
+
+        let report = compressor.stats.report();
+        assert_eq!(report.compressed_messages, 3);
+        assert!(report.messages > report.compressed_messages);
+        assert!(report.ratio < 0.5, "{report:?}");
+    }
+
+    #[test]
+    fn test_open_rejects_bad_envelopes() {
+        assert!(Compression::open(b"").is_err());
+        assert!(Compression::open(b"\x07data").is_err());
+        assert!(Compression::open(b"\x02not zstd").is_err());
+
+        let bomb = Compression::Zstd
+            .compress(&vec![0; MAX_FRAME_LEN + 1])
+            .unwrap();
+        let mut envelope = vec![2];
+        envelope.extend_from_slice(&bomb);
+        let err = Compression::open(&envelope).unwrap_err();
+        assert!(err.contains("limit"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        let lines: &[&[u8]] = &[b"\"Ping\"", b"{\"a\":1}"];
+        let binary: &[&[u8]] = &[b"\x92bin\nary\n", b"", b"\x00"];
+        for (framing, payloads) in [(Framing::Lines, lines), (Framing::LengthPrefixed, binary)] {
+            let mut stream = Vec::new();
+            for payload in payloads {
+                framing.write_frame(&mut stream, payload).await.unwrap();
+            }
+
+            let mut reader = stream.as_slice();
+            assert_eq!(Framing::detect(&mut reader).await.unwrap(), Some(framing));
+            for payload in payloads {
+                let frame = framing.read_frame(&mut reader).await.unwrap();
+                assert_eq!(frame.as_deref(), Some(*payload));
+            }
+            assert_eq!(framing.read_frame(&mut reader).await.unwrap(), None);
+            assert_eq!(Framing::detect(&mut reader).await.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() {
+        let header = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        let mut reader = &header[..];
+        let err = Framing::LengthPrefixed
+            .read_frame(&mut reader)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
 
 /// A single edit operation with byte-offset positions.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -19,10 +22,42 @@ pub enum Edit {
     },
 }
 
+impl Edit {
+    /// Byte offset, number of source bytes replaced and replacement text.
+    pub(crate) fn parts(&self) -> (usize, usize, &str) {
+        match self {
+            Edit::Insert { pos, text } => (*pos, 0, text),
+            Edit::Delete { pos, len } => (*pos, *len, ""),
+            Edit::Replace {
+                pos,
+                old_len,
+                new_text,
+            } => (*pos, *old_len, new_text),
+        }
+    }
+
+    /// Inverse of [`parts`](Self::parts); `None` if the edit would be a no-op.
+    fn from_parts(pos: usize, old_len: usize, new_text: String) -> Option<Self> {
+        match (old_len, new_text.is_empty()) {
+            (0, true) => None,
+            (0, false) => Some(Edit::Insert {
+                pos,
+                text: new_text,
+            }),
+            (len, true) => Some(Edit::Delete { pos, len }),
+            (old_len, false) => Some(Edit::Replace {
+                pos,
+                old_len,
+                new_text,
+            }),
+        }
+    }
+}
+
 /// An ordered collection of edits with a checksum of the source text they were
 /// computed against. Applying the edits to a string matching the checksum
 /// produces the target text.
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct EditList {
     pub edits: Vec<Edit>,
     pub checksum: String,
@@ -63,186 +98,3447 @@ impl fmt::Display for EditList {
     }
 }
 
-/// Simple checksum combining length and char-value sum. Not cryptographic —
-/// used only for quick shadow-equality checks during sync.
+/// Content hash used for `EditList` checksums and shadow comparisons.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    /// 64-bit xxHash: fast, non-cryptographic, ample for detecting divergence.
+    #[default]
+    XxHash64,
+    /// SHA-256, for peers that want collision resistance against adversaries.
+    Sha256,
+}
+
+impl HashAlgorithm {
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::XxHash64 => "xxh64",
+            Self::Sha256 => "sha256",
+        }
+    }
+}
+
+/// Checksum of `text` with the default [`HashAlgorithm`].
 pub fn checksum(text: &str) -> String {
+    checksum_with(text, HashAlgorithm::default())
+}
+
+/// Checksum of `text`, tagged with the algorithm that produced it
+/// (`"xxh64:…"`, `"sha256:…"`) so the receiver can verify it.
+pub fn checksum_with(text: &str, algorithm: HashAlgorithm) -> String {
+    let digest = match algorithm {
+        HashAlgorithm::XxHash64 => {
+            format!("{:016x}", xxhash_rust::xxh64::xxh64(text.as_bytes(), 0))
+        }
+        HashAlgorithm::Sha256 => {
+            use sha2::{Digest, Sha256};
+            Sha256::digest(text.as_bytes())
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect()
+        }
+    };
+    format!("{}:{digest}", algorithm.prefix())
+}
+
+/// Check that `checksum` was computed from `text`.
+///
+/// Untagged checksums are the legacy length-XOR-char-sum still produced by
+/// the web client; they are accepted but catch far fewer divergences.
+pub fn verify_checksum(text: &str, checksum: &str) -> bool {
+    match checksum.split_once(':') {
+        Some(("xxh64", _)) => checksum_with(text, HashAlgorithm::XxHash64) == checksum,
+        Some(("sha256", _)) => checksum_with(text, HashAlgorithm::Sha256) == checksum,
+        Some(_) => false,
+        None => legacy_checksum(text) == checksum,
+    }
+}
+
+fn legacy_checksum(text: &str) -> String {
     let hash = text.len() ^ (text.chars().map(|c| c as u32).sum::<u32>() as usize);
     format!("{hash:x}")
 }
 
 /// Compute the minimal edit list to transform `from` into `to`.
 ///
-/// Strips common prefix and suffix, then emits a single Insert, Delete, or
-/// Replace for the differing middle section. Positions are byte offsets.
+/// Runs Myers' O(ND) algorithm with middle-snake bisection over the chars of
+/// both strings, so independent changes far apart in the text come out as
+/// separate edits rather than one Replace spanning everything in between.
+/// Positions are byte offsets into `from`, listed in ascending order.
 pub fn diff(from: &str, to: &str) -> EditList {
+    diff_chars(from, to, &mut Deadline::none())
+}
+
+fn diff_chars(from: &str, to: &str, deadline: &mut Deadline) -> EditList {
     if from == to {
         return EditList::empty(from);
     }
-    if from.is_empty() {
-        return EditList::new(
-            vec![Edit::Insert {
-                pos: 0,
-                text: to.to_string(),
-            }],
-            from,
-        );
-    }
-    if to.is_empty() {
-        return EditList::new(
-            vec![Edit::Delete {
-                pos: 0,
-                len: from.len(),
-            }],
-            from,
-        );
-    }
 
     let from_chars: Vec<char> = from.chars().collect();
     let to_chars: Vec<char> = to.chars().collect();
+    let runs = diff_units_until(&from_chars, &to_chars, deadline);
+
+    EditList::new(
+        runs_to_edits(&runs, &char_offsets(from), to, &char_offsets(to)),
+        from,
+    )
+}
+
+/// Unit that [`diff_with_options`] compares the two texts by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiffGranularity {
+    /// Individual chars: minimal edits, but slow on large documents.
+    #[default]
+    Char,
+    /// Runs of whitespace or of non-whitespace.
+    Word,
+    /// Lines, each including its trailing newline.
+    Line,
+}
+
+/// Post-processing pass applied to a fresh diff by [`diff_with_options`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiffCleanup {
+    /// Keep the minimal edit script as computed.
+    #[default]
+    None,
+    /// [`EditList::cleanup_semantic`]: fewer, human-readable edits.
+    Semantic,
+    /// [`EditList::cleanup_semantic_lossless`]: align edits to boundaries.
+    SemanticLossless,
+    /// [`EditList::cleanup_efficiency`] with [`DiffOptions::edit_cost`].
+    Efficiency,
+}
+
+/// Tuning for [`diff_with_options`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffOptions {
+    pub granularity: DiffGranularity,
+    pub cleanup: DiffCleanup,
+    /// Rough per-edit overhead in bytes, used by [`DiffCleanup::Efficiency`].
+    pub edit_cost: usize,
+    /// Wall-clock budget for the diff. Once it runs out, the remaining
+    /// sections are emitted as whole replacements: still correct, but no
+    /// longer minimal.
+    pub deadline: Option<Duration>,
+    /// Never start or end an edit inside an extended grapheme cluster (an
+    /// emoji sequence, a flag, a letter and its combining marks). Char mode
+    /// then diffs whole clusters, and the result is passed through
+    /// [`EditList::align_to_graphemes`] after cleanup.
+    pub grapheme_safe: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            granularity: DiffGranularity::default(),
+            cleanup: DiffCleanup::default(),
+            edit_cost: 4,
+            deadline: None,
+            grapheme_safe: false,
+        }
+    }
+}
+
+/// Compute an edit list from `from` to `to` at the requested granularity,
+/// then run the requested cleanup pass over it.
+///
+/// In word and line mode every distinct token is interned to a small integer
+/// and the Myers diff runs over those, so a document of thousands of lines
+/// costs no more than a few thousand units. The resulting runs are expanded
+/// back to byte offsets, so edits cover whole tokens and still apply with
+/// [`patch`] like any other edit list.
+pub fn diff_with_options(from: &str, to: &str, options: &DiffOptions) -> EditList {
+    diff_with_report(from, to, options).edits
+}
+
+/// Edit list from [`diff_with_report`], with whether the diff ran out of time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffReport {
+    pub edits: EditList,
+    /// The [`DiffOptions::deadline`] expired, so the edits may not be minimal.
+    pub deadline_hit: bool,
+}
+
+/// Like [`diff_with_options`], but also reports whether the deadline expired.
+pub fn diff_with_report(from: &str, to: &str, options: &DiffOptions) -> DiffReport {
+    let mut deadline = Deadline::after(options.deadline);
+    let mut edits = match options.granularity {
+        DiffGranularity::Char if options.grapheme_safe => {
+            diff_tokens(from, to, grapheme_offsets, &mut deadline)
+        }
+        DiffGranularity::Char => diff_chars(from, to, &mut deadline),
+        DiffGranularity::Word => diff_tokens(from, to, word_offsets, &mut deadline),
+        DiffGranularity::Line => diff_tokens(from, to, line_offsets, &mut deadline),
+    };
 
-    let mut common_start = 0;
-    while common_start < from_chars.len()
-        && common_start < to_chars.len()
-        && from_chars[common_start] == to_chars[common_start]
-    {
-        common_start += 1;
+    match options.cleanup {
+        DiffCleanup::None => {}
+        DiffCleanup::Semantic => edits.cleanup_semantic(from),
+        DiffCleanup::SemanticLossless => edits.cleanup_semantic_lossless(from),
+        DiffCleanup::Efficiency => edits.cleanup_efficiency(from, options.edit_cost),
+    }
+    if options.grapheme_safe {
+        edits.align_to_graphemes(from);
     }
+    DiffReport {
+        edits,
+        deadline_hit: deadline.expired,
+    }
+}
 
-    let mut common_end = 0;
-    while common_end < from_chars.len() - common_start
-        && common_end < to_chars.len() - common_start
-        && from_chars[from_chars.len() - 1 - common_end]
-            == to_chars[to_chars.len() - 1 - common_end]
-    {
-        common_end += 1;
+fn diff_tokens(
+    from: &str,
+    to: &str,
+    split: fn(&str) -> Vec<usize>,
+    deadline: &mut Deadline,
+) -> EditList {
+    if from == to {
+        return EditList::empty(from);
     }
 
-    let prefix_bytes: usize = from_chars[..common_start]
-        .iter()
-        .map(|c| c.len_utf8())
-        .sum();
-    let suffix_bytes: usize = if common_end > 0 {
-        from_chars[from_chars.len() - common_end..]
-            .iter()
-            .map(|c| c.len_utf8())
-            .sum()
+    let (from_offsets, to_offsets) = (split(from), split(to));
+    let mut ids = HashMap::new();
+    let from_tokens = intern_tokens(from, &from_offsets, &mut ids);
+    let to_tokens = intern_tokens(to, &to_offsets, &mut ids);
+    let runs = diff_units_until(&from_tokens, &to_tokens, deadline);
+
+    EditList::new(runs_to_edits(&runs, &from_offsets, to, &to_offsets), from)
+}
+
+/// Byte offset of every line start in `text`, plus the end.
+fn line_offsets(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .filter(|&i| i < text.len())
+        .chain(std::iter::once(text.len()))
+        .collect()
+}
+
+/// Byte offset of every switch between whitespace and non-whitespace in
+/// `text`, plus the start and end.
+fn word_offsets(text: &str) -> Vec<usize> {
+    let mut offsets = vec![0];
+    let mut prev: Option<bool> = None;
+    for (i, c) in text.char_indices() {
+        let space = c.is_whitespace();
+        if prev.is_some_and(|p| p != space) {
+            offsets.push(i);
+        }
+        prev = Some(space);
+    }
+    if !text.is_empty() {
+        offsets.push(text.len());
+    }
+    offsets
+}
+
+/// Byte offset of every extended grapheme cluster start in `text`, plus the
+/// end.
+fn grapheme_offsets(text: &str) -> Vec<usize> {
+    text.grapheme_indices(true)
+        .map(|(i, _)| i)
+        .chain(std::iter::once(text.len()))
+        .collect()
+}
+
+/// Map each token between consecutive `offsets` to an id shared across calls.
+fn intern_tokens<'a>(
+    text: &'a str,
+    offsets: &[usize],
+    ids: &mut HashMap<&'a str, u32>,
+) -> Vec<u32> {
+    offsets
+        .windows(2)
+        .map(|w| {
+            let next = ids.len() as u32;
+            *ids.entry(&text[w[0]..w[1]]).or_insert(next)
+        })
+        .collect()
+}
+
+/// Operation of a run in a unit-level diff script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Byte offset of every char boundary in `text`, including the end.
+fn char_offsets(text: &str) -> Vec<usize> {
+    text.char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(text.len()))
+        .collect()
+}
+
+/// Convert a unit-level diff script into byte-offset edits.
+///
+/// `from_offsets` and `to_offsets` map unit indices to byte offsets in the
+/// source and target text. Adjacent deletes and inserts between two equal runs
+/// collapse into a single Replace.
+pub(crate) fn runs_to_edits(
+    runs: &[(Op, usize)],
+    from_offsets: &[usize],
+    to: &str,
+    to_offsets: &[usize],
+) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut k = 0;
+
+    while k < runs.len() {
+        if runs[k].0 == Op::Equal {
+            i += runs[k].1;
+            j += runs[k].1;
+            k += 1;
+            continue;
+        }
+
+        let (start_i, start_j) = (i, j);
+        while k < runs.len() && runs[k].0 != Op::Equal {
+            match runs[k].0 {
+                Op::Delete => i += runs[k].1,
+                Op::Insert => j += runs[k].1,
+                Op::Equal => unreachable!(),
+            }
+            k += 1;
+        }
+
+        let pos = from_offsets[start_i];
+        let old_len = from_offsets[i] - pos;
+        let new_text = &to[to_offsets[start_j]..to_offsets[j]];
+
+        edits.extend(Edit::from_parts(pos, old_len, new_text.to_string()));
+    }
+
+    edits
+}
+
+/// Minimal diff script between two unit sequences, as (op, length) runs.
+///
+/// Trims the common prefix and suffix, handles the trivial cases directly and
+/// falls back to Myers bisection for the remaining middle section.
+pub(crate) fn diff_units<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(Op, usize)> {
+    diff_units_until(a, b, &mut Deadline::none())
+}
+
+/// Wall-clock limit for a diff, remembering whether it was reached.
+struct Deadline {
+    at: Option<Instant>,
+    expired: bool,
+}
+
+impl Deadline {
+    fn none() -> Self {
+        Self::after(None)
+    }
+
+    fn after(budget: Option<Duration>) -> Self {
+        Self {
+            at: budget.map(|budget| Instant::now() + budget),
+            expired: false,
+        }
+    }
+
+    fn check(&mut self) -> bool {
+        if !self.expired && self.at.is_some_and(|at| Instant::now() >= at) {
+            self.expired = true;
+        }
+        self.expired
+    }
+}
+
+/// [`diff_units`] that gives up on minimality once `deadline` has passed.
+fn diff_units_until<T: PartialEq>(a: &[T], b: &[T], deadline: &mut Deadline) -> Vec<(Op, usize)> {
+    let prefix = common_prefix(a, b);
+    let suffix = common_suffix(&a[prefix..], &b[prefix..]);
+
+    let mut runs = vec![(Op::Equal, prefix)];
+    runs.extend(compute(
+        &a[prefix..a.len() - suffix],
+        &b[prefix..b.len() - suffix],
+        deadline,
+    ));
+    runs.push((Op::Equal, suffix));
+    merge_runs(runs)
+}
+
+fn common_prefix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn common_suffix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+/// Diff two sequences that share no common prefix or suffix.
+fn compute<T: PartialEq>(a: &[T], b: &[T], deadline: &mut Deadline) -> Vec<(Op, usize)> {
+    if a.is_empty() {
+        return vec![(Op::Insert, b.len())];
+    }
+    if b.is_empty() {
+        return vec![(Op::Delete, a.len())];
+    }
+
+    // A shorter sequence wholly contained in the longer one is a pure
+    // insertion or deletion around it.
+    let (long, short, outer) = if a.len() > b.len() {
+        (a, b, Op::Delete)
     } else {
-        0
+        (b, a, Op::Insert)
     };
+    if let Some(at) = long.windows(short.len()).position(|w| w == short) {
+        return vec![
+            (outer, at),
+            (Op::Equal, short.len()),
+            (outer, long.len() - at - short.len()),
+        ];
+    }
+    if short.len() == 1 {
+        return vec![(Op::Delete, a.len()), (Op::Insert, b.len())];
+    }
 
-    let from_middle = &from[prefix_bytes..from.len() - suffix_bytes];
-    let to_middle = &to[prefix_bytes..to.len() - suffix_bytes];
+    bisect(a, b, deadline)
+}
 
-    let edit = match (from_middle.is_empty(), to_middle.is_empty()) {
-        (true, true) => return EditList::empty(from),
-        (true, false) => Edit::Insert {
-            pos: prefix_bytes,
-            text: to_middle.to_string(),
-        },
-        (false, true) => Edit::Delete {
-            pos: prefix_bytes,
-            len: from_middle.len(),
-        },
-        (false, false) => Edit::Replace {
-            pos: prefix_bytes,
-            old_len: from_middle.len(),
-            new_text: to_middle.to_string(),
-        },
+/// Find the middle snake of the edit graph and recurse on both halves.
+///
+/// Walks the forward and reverse D-paths simultaneously (Myers 1986, section
+/// 4b) until they overlap, giving linear space and O(ND) time.
+fn bisect<T: PartialEq>(a: &[T], b: &[T], deadline: &mut Deadline) -> Vec<(Op, usize)> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max_d = (n + m + 1) / 2;
+    let v_offset = max_d;
+    let v_length = 2 * max_d;
+    let mut v1 = vec![-1isize; v_length as usize];
+    let mut v2 = vec![-1isize; v_length as usize];
+    v1[(v_offset + 1) as usize] = 0;
+    v2[(v_offset + 1) as usize] = 0;
+
+    let delta = n - m;
+    // With an odd delta the forward path detects the overlap, otherwise the
+    // reverse path does.
+    let front = delta % 2 != 0;
+
+    // Diagonals that have run off the edge of the grid are skipped.
+    let (mut k1start, mut k1end, mut k2start, mut k2end) = (0, 0, 0, 0);
+
+    for d in 0..max_d {
+        if deadline.check() {
+            break;
+        }
+
+        let mut k1 = -d + k1start;
+        while k1 <= d - k1end {
+            let k1_offset = (v_offset + k1) as usize;
+            let mut x1 = if k1 == -d || (k1 != d && v1[k1_offset - 1] < v1[k1_offset + 1]) {
+                v1[k1_offset + 1]
+            } else {
+                v1[k1_offset - 1] + 1
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && a[x1 as usize] == b[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+            v1[k1_offset] = x1;
+
+            if x1 > n {
+                k1end += 2;
+            } else if y1 > m {
+                k1start += 2;
+            } else if front {
+                let k2_offset = v_offset + delta - k1;
+                if k2_offset >= 0 && k2_offset < v_length && v2[k2_offset as usize] != -1 {
+                    let x2 = n - v2[k2_offset as usize];
+                    if x1 >= x2 {
+                        return bisect_split(a, b, x1 as usize, y1 as usize, deadline);
+                    }
+                }
+            }
+            k1 += 2;
+        }
+
+        let mut k2 = -d + k2start;
+        while k2 <= d - k2end {
+            let k2_offset = (v_offset + k2) as usize;
+            let mut x2 = if k2 == -d || (k2 != d && v2[k2_offset - 1] < v2[k2_offset + 1]) {
+                v2[k2_offset + 1]
+            } else {
+                v2[k2_offset - 1] + 1
+            };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m && a[(n - x2 - 1) as usize] == b[(m - y2 - 1) as usize] {
+                x2 += 1;
+                y2 += 1;
+            }
+            v2[k2_offset] = x2;
+
+            if x2 > n {
+                k2end += 2;
+            } else if y2 > m {
+                k2start += 2;
+            } else if !front {
+                let k1_offset = v_offset + delta - k2;
+                if k1_offset >= 0 && k1_offset < v_length && v1[k1_offset as usize] != -1 {
+                    let x1 = v1[k1_offset as usize];
+                    let y1 = v_offset + x1 - k1_offset;
+                    if x1 >= n - x2 {
+                        return bisect_split(a, b, x1 as usize, y1 as usize, deadline);
+                    }
+                }
+            }
+            k2 += 2;
+        }
+    }
+
+    // Out of time (the paths always meet before `max_d`), so give up on this
+    // section and replace it wholesale.
+    vec![(Op::Delete, a.len()), (Op::Insert, b.len())]
+}
+
+fn bisect_split<T: PartialEq>(
+    a: &[T],
+    b: &[T],
+    x: usize,
+    y: usize,
+    deadline: &mut Deadline,
+) -> Vec<(Op, usize)> {
+    let mut runs = diff_units_until(&a[..x], &b[..y], deadline);
+    runs.extend(diff_units_until(&a[x..], &b[y..], deadline));
+    runs
+}
+
+/// Drop empty runs, coalesce neighbours with the same op and put each block of
+/// changes between two equalities in delete-then-insert order.
+fn merge_runs(runs: Vec<(Op, usize)>) -> Vec<(Op, usize)> {
+    let mut merged: Vec<(Op, usize)> = Vec::with_capacity(runs.len());
+    let (mut deleted, mut inserted) = (0, 0);
+
+    let flush = |merged: &mut Vec<(Op, usize)>, deleted: &mut usize, inserted: &mut usize| {
+        if *deleted > 0 {
+            merged.push((Op::Delete, std::mem::take(deleted)));
+        }
+        if *inserted > 0 {
+            merged.push((Op::Insert, std::mem::take(inserted)));
+        }
     };
 
-    EditList::new(vec![edit], from)
+    for (op, len) in runs {
+        match op {
+            Op::Delete => deleted += len,
+            Op::Insert => inserted += len,
+            Op::Equal if len > 0 => {
+                flush(&mut merged, &mut deleted, &mut inserted);
+                match merged.last_mut() {
+                    Some((Op::Equal, prev)) => *prev += len,
+                    _ => merged.push((Op::Equal, len)),
+                }
+            }
+            Op::Equal => {}
+        }
+    }
+    flush(&mut merged, &mut deleted, &mut inserted);
+
+    merged
 }
 
-/// Apply edits to `text`, returning the transformed result.
-///
-/// Edits are applied in reverse order to avoid cascading position shifts.
-/// Positions are clamped to text bounds for fuzzy-patch tolerance.
-pub fn patch(text: &str, edit_list: &EditList) -> Result<String, PatchError> {
-    if edit_list.is_empty() {
-        return Ok(text.to_string());
+/// A run of text in a full diff script: kept, removed from the source, or
+/// added in the target.
+type Segment = (Op, String);
+
+impl EditList {
+    /// Trade minimality for readability: fold short equalities that are
+    /// dwarfed by the edits around them into those edits, then slide the
+    /// remaining edits onto word and line boundaries.
+    ///
+    /// `source` must be the text the edits were computed against; otherwise
+    /// the list is left unchanged. The resulting edits apply to the same source
+    /// and produce the same target.
+    pub fn cleanup_semantic(&mut self, source: &str) {
+        self.rewrite(source, |segments| {
+            cleanup_semantic(segments);
+            cleanup_semantic_lossless(segments);
+        });
     }
 
-    let mut result = text.to_string();
+    /// Slide each edit sideways within the equal text around it so that it
+    /// starts and ends on a word, sentence or line boundary where possible.
+    /// The number and size of the edits does not change.
+    pub fn cleanup_semantic_lossless(&mut self, source: &str) {
+        self.rewrite(source, cleanup_semantic_lossless);
+    }
 
-    for edit in edit_list.edits.iter().rev() {
-        match edit {
-            Edit::Insert {
-                pos,
-                text: insert_text,
-            } => {
-                let safe_pos = (*pos).min(result.len());
-                result.insert_str(safe_pos, insert_text);
+    /// Fold equalities shorter than `edit_cost` bytes into the surrounding
+    /// edits when that saves sending several small edits, each of which costs
+    /// about `edit_cost` bytes of overhead on the wire.
+    pub fn cleanup_efficiency(&mut self, source: &str, edit_cost: usize) {
+        self.rewrite(source, |segments| cleanup_efficiency(segments, edit_cost));
+    }
+
+    /// Widen each edit until it starts and ends on an extended grapheme
+    /// cluster boundary in both `source` and the target, merging edits that
+    /// meet. Char-level diffs and cleanup passes can otherwise split an emoji
+    /// sequence or separate a letter from its combining marks.
+    ///
+    /// `source` must be the text the edits were computed against; otherwise
+    /// the list is left unchanged.
+    pub fn align_to_graphemes(&mut self, source: &str) {
+        let Some(segments) = self.segments(source) else {
+            return;
+        };
+
+        // Each change as (source start, source end, target start, target end).
+        let mut changes: Vec<(usize, usize, usize, usize)> = Vec::new();
+        let mut target = String::new();
+        let mut pos = 0;
+        for (op, text) in &segments {
+            let (old_len, new_len) = match op {
+                Op::Equal => {
+                    pos += text.len();
+                    target.push_str(text);
+                    continue;
+                }
+                Op::Delete => (text.len(), 0),
+                Op::Insert => (0, text.len()),
+            };
+            match changes.last_mut() {
+                Some(last) if last.1 == pos => {
+                    last.1 += old_len;
+                    last.3 += new_len;
+                }
+                _ => changes.push((pos, pos + old_len, target.len(), target.len() + new_len)),
             }
-            Edit::Delete { pos, len } => {
-                let start = (*pos).min(result.len());
-                let end = (start + len).min(result.len());
-                if start < end {
-                    result.drain(start..end);
+            pos += old_len;
+            if *op == Op::Insert {
+                target.push_str(text);
+            }
+        }
+
+        let (source_bounds, target_bounds) = (grapheme_offsets(source), grapheme_offsets(&target));
+        let aligned = |s: usize, t: usize| {
+            source_bounds.binary_search(&s).is_ok() && target_bounds.binary_search(&t).is_ok()
+        };
+
+        // Text between changes is equal in both, so each boundary moves by
+        // the same number of bytes on either side.
+        let mut widened: Vec<(usize, usize, usize, usize)> = Vec::new();
+        let mut pending = changes.into_iter().peekable();
+        while let Some(mut change) = pending.next() {
+            while !aligned(change.0, change.2) {
+                let step = source[..change.0]
+                    .chars()
+                    .next_back()
+                    .map_or(0, char::len_utf8);
+                change.0 -= step;
+                change.2 -= step;
+                if widened.last().is_some_and(|prev| prev.1 == change.0) {
+                    let prev = widened.pop().unwrap();
+                    (change.0, change.2) = (prev.0, prev.2);
                 }
             }
-            Edit::Replace {
-                pos,
-                old_len,
-                new_text,
-            } => {
-                let start = (*pos).min(result.len());
-                let end = (start + old_len).min(result.len());
-                result.replace_range(start..end, new_text);
+            while !aligned(change.1, change.3) {
+                let step = source[change.1..].chars().next().map_or(0, char::len_utf8);
+                change.1 += step;
+                change.3 += step;
+                if let Some(next) = pending.next_if(|next| next.0 == change.1) {
+                    (change.1, change.3) = (next.1, next.3);
+                }
             }
+            widened.push(change);
         }
+
+        self.edits = widened
+            .into_iter()
+            .filter_map(|(s0, s1, t0, t1)| {
+                Edit::from_parts(s0, s1 - s0, target[t0..t1].to_string())
+            })
+            .collect();
     }
 
-    Ok(result)
-}
+    /// Expand the edits into a full diff script against `source`, let `pass`
+    /// rework it and turn the result back into edits.
+    fn rewrite(&mut self, source: &str, pass: impl FnOnce(&mut Vec<Segment>)) {
+        let Some(mut segments) = self.segments(source) else {
+            return;
+        };
+        pass(&mut segments);
+        self.edits = edits_from_segments(&segments);
+    }
 
-/// Errors that can occur during patch application.
-#[derive(Debug, Clone, PartialEq)]
-pub enum PatchError {
-    ChecksumMismatch,
-    InvalidPosition,
-    InvalidEdit,
+    fn segments(&self, source: &str) -> Option<Vec<Segment>> {
+        let mut segments = Vec::new();
+        let mut cursor = 0;
+        for edit in &self.edits {
+            let (pos, old_len, new_text) = edit.parts();
+            segments.push((Op::Equal, source.get(cursor..pos)?.to_string()));
+            segments.push((Op::Delete, source.get(pos..pos + old_len)?.to_string()));
+            segments.push((Op::Insert, new_text.to_string()));
+            cursor = pos + old_len;
+        }
+        segments.push((Op::Equal, source.get(cursor..)?.to_string()));
+        segments.retain(|(_, text)| !text.is_empty());
+        Some(segments)
+    }
 }
 
-impl fmt::Display for PatchError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::ChecksumMismatch => write!(f, "Checksum mismatch"),
-            Self::InvalidPosition => write!(f, "Invalid position"),
-            Self::InvalidEdit => write!(f, "Invalid edit"),
+fn edits_from_segments(segments: &[Segment]) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let mut pos = 0;
+    let mut k = 0;
+
+    while k < segments.len() {
+        if segments[k].0 == Op::Equal {
+            pos += segments[k].1.len();
+            k += 1;
+            continue;
         }
+
+        let mut old_len = 0;
+        let mut new_text = String::new();
+        while k < segments.len() && segments[k].0 != Op::Equal {
+            match segments[k].0 {
+                Op::Delete => old_len += segments[k].1.len(),
+                Op::Insert => new_text.push_str(&segments[k].1),
+                Op::Equal => unreachable!(),
+            }
+            k += 1;
+        }
+
+        edits.extend(Edit::from_parts(pos, old_len, new_text));
+        pos += old_len;
     }
+
+    edits
 }
 
-impl std::error::Error for PatchError {}
+fn char_len(text: &str) -> usize {
+    text.chars().count()
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Byte length of the longest common prefix of `a` and `b`.
+fn common_prefix_str(a: &str, b: &str) -> usize {
+    a.chars()
+        .zip(b.chars())
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x.len_utf8())
+        .sum()
+}
 
-    #[test]
-    fn test_diff_and_patch() {
-        let original = "The quick brown fox";
-        let modified = "The quick red fox jumps";
+/// Byte length of the longest common suffix of `a` and `b`.
+fn common_suffix_str(a: &str, b: &str) -> usize {
+    a.chars()
+        .rev()
+        .zip(b.chars().rev())
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x.len_utf8())
+        .sum()
+}
 
-        let edits = diff(original, modified);
-        let result = patch(original, &edits).unwrap();
-        assert_eq!(result, modified);
+/// Byte length of the longest suffix of `a` that is also a prefix of `b`.
+fn common_overlap(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| b.starts_with(&a[i..]))
+        .map_or(0, |i| a.len() - i)
+}
+
+/// Merge adjacent segments of the same kind, factor text shared by a delete
+/// and an insert out into the equalities around them, and shift single edits
+/// sideways to absorb neighbouring equalities where possible.
+fn cleanup_merge(segments: &mut Vec<Segment>) {
+    segments.push((Op::Equal, String::new()));
+    let mut pointer = 0;
+    let (mut count_delete, mut count_insert) = (0, 0);
+    let (mut text_delete, mut text_insert) = (String::new(), String::new());
+
+    while pointer < segments.len() {
+        match segments[pointer].0 {
+            Op::Insert => {
+                count_insert += 1;
+                text_insert.push_str(&segments[pointer].1);
+                pointer += 1;
+            }
+            Op::Delete => {
+                count_delete += 1;
+                text_delete.push_str(&segments[pointer].1);
+                pointer += 1;
+            }
+            Op::Equal => {
+                let start = pointer - count_delete - count_insert;
+                if count_delete + count_insert > 1 {
+                    if count_delete != 0 && count_insert != 0 {
+                        let prefix = common_prefix_str(&text_insert, &text_delete);
+                        if prefix != 0 {
+                            if start > 0 && segments[start - 1].0 == Op::Equal {
+                                segments[start - 1].1.push_str(&text_insert[..prefix]);
+                            } else {
+                                segments.insert(0, (Op::Equal, text_insert[..prefix].to_string()));
+                                pointer += 1;
+                            }
+                            text_insert.drain(..prefix);
+                            text_delete.drain(..prefix);
+                        }
+                        let suffix = common_suffix_str(&text_insert, &text_delete);
+                        if suffix != 0 {
+                            let shared = text_insert[text_insert.len() - suffix..].to_string();
+                            segments[pointer].1.insert_str(0, &shared);
+                            text_insert.truncate(text_insert.len() - suffix);
+                            text_delete.truncate(text_delete.len() - suffix);
+                        }
+                    }
+                    pointer -= count_delete + count_insert;
+                    segments.drain(pointer..pointer + count_delete + count_insert);
+                    if !text_delete.is_empty() {
+                        segments.insert(pointer, (Op::Delete, std::mem::take(&mut text_delete)));
+                        pointer += 1;
+                    }
+                    if !text_insert.is_empty() {
+                        segments.insert(pointer, (Op::Insert, std::mem::take(&mut text_insert)));
+                        pointer += 1;
+                    }
+                    pointer += 1;
+                } else if pointer != 0 && segments[pointer - 1].0 == Op::Equal {
+                    let (_, text) = segments.remove(pointer);
+                    segments[pointer - 1].1.push_str(&text);
+                } else {
+                    pointer += 1;
+                }
+                count_delete = 0;
+                count_insert = 0;
+                text_delete.clear();
+                text_insert.clear();
+            }
+        }
+    }
+    if segments.last().is_some_and(|(_, text)| text.is_empty()) {
+        segments.pop();
     }
 
-    #[test]
-    fn test_empty_diff() {
-        let text = "Same text";
-        assert!(diff(text, text).is_empty());
+    // Slide single edits between two equalities over one of them, e.g.
+    // "A<ins>BA</ins>C" becomes "<ins>AB</ins>AC".
+    let mut changes = false;
+    let mut pointer = 1;
+    while pointer + 1 < segments.len() {
+        if segments[pointer - 1].0 == Op::Equal && segments[pointer + 1].0 == Op::Equal {
+            let prev = segments[pointer - 1].1.clone();
+            let next = segments[pointer + 1].1.clone();
+            let edit = &segments[pointer].1;
+            if edit.ends_with(&prev) {
+                segments[pointer].1 = format!("{prev}{}", &edit[..edit.len() - prev.len()]);
+                segments[pointer + 1].1.insert_str(0, &prev);
+                segments.remove(pointer - 1);
+                changes = true;
+            } else if edit.starts_with(&next) {
+                segments[pointer].1 = format!("{}{next}", &edit[next.len()..]);
+                segments[pointer - 1].1.push_str(&next);
+                segments.remove(pointer + 1);
+                changes = true;
+            }
+        }
+        pointer += 1;
     }
+    if changes {
+        cleanup_merge(segments);
+    }
+}
 
-    #[test]
-    fn test_fuzzy_patch() {
-        let original = "Hello world";
-        let modified = "Hello beautiful world";
-        let edits = diff(original, modified);
+/// Turn short equalities that are no longer than the edits on either side of
+/// them into a delete and an insert, then split off any overlap between a
+/// delete and the insert that follows it.
+fn cleanup_semantic(segments: &mut Vec<Segment>) {
+    let mut changes = false;
+    let mut equalities: Vec<usize> = Vec::new();
+    let mut last_equality: Option<usize> = None;
+    let mut pointer = 0;
+    // Chars inserted and deleted before and after the last equality.
+    let (mut inserted_before, mut deleted_before) = (0, 0);
+    let (mut inserted_after, mut deleted_after) = (0, 0);
 
-        let different_text = "Hello cruel world";
-        let result = patch(different_text, &edits).unwrap();
-        assert!(result.contains("beautiful"));
+    while pointer < segments.len() {
+        if segments[pointer].0 == Op::Equal {
+            equalities.push(pointer);
+            inserted_before = inserted_after;
+            deleted_before = deleted_after;
+            inserted_after = 0;
+            deleted_after = 0;
+            last_equality = Some(char_len(&segments[pointer].1));
+            pointer += 1;
+            continue;
+        }
+
+        match segments[pointer].0 {
+            Op::Insert => inserted_after += char_len(&segments[pointer].1),
+            _ => deleted_after += char_len(&segments[pointer].1),
+        }
+        let dwarfed = last_equality.is_some_and(|len| {
+            len <= inserted_before.max(deleted_before) && len <= inserted_after.max(deleted_after)
+        });
+        if dwarfed {
+            let at = equalities.pop().expect("an equality was seen");
+            let text = segments[at].1.clone();
+            segments.insert(at, (Op::Delete, text));
+            segments[at + 1].0 = Op::Insert;
+            // The previous equality needs to be re-evaluated as well.
+            equalities.pop();
+            pointer = equalities.last().map_or(0, |&e| e + 1);
+            (inserted_before, deleted_before) = (0, 0);
+            (inserted_after, deleted_after) = (0, 0);
+            last_equality = None;
+            changes = true;
+            continue;
+        }
+        pointer += 1;
+    }
+
+    if changes {
+        cleanup_merge(segments);
+    }
+
+    let mut pointer = 1;
+    while pointer < segments.len() {
+        if segments[pointer - 1].0 == Op::Delete && segments[pointer].0 == Op::Insert {
+            let deletion = segments[pointer - 1].1.clone();
+            let insertion = segments[pointer].1.clone();
+            let (del_len, ins_len) = (char_len(&deletion), char_len(&insertion));
+            let overlap1 = common_overlap(&deletion, &insertion);
+            let overlap2 = common_overlap(&insertion, &deletion);
+            let significant = |overlap: &str| {
+                let len = char_len(overlap);
+                len * 2 >= del_len || len * 2 >= ins_len
+            };
+
+            if overlap1 >= overlap2 {
+                let shared = &insertion[..overlap1];
+                if overlap1 > 0 && significant(shared) {
+                    segments.insert(pointer, (Op::Equal, shared.to_string()));
+                    segments[pointer - 1].1 = deletion[..deletion.len() - overlap1].to_string();
+                    segments[pointer + 1].1 = insertion[overlap1..].to_string();
+                    pointer += 1;
+                }
+            } else {
+                let shared = &deletion[..overlap2];
+                if significant(shared) {
+                    segments.insert(pointer, (Op::Equal, shared.to_string()));
+                    segments[pointer - 1] = (
+                        Op::Insert,
+                        insertion[..insertion.len() - overlap2].to_string(),
+                    );
+                    segments[pointer + 1] = (Op::Delete, deletion[overlap2..].to_string());
+                    pointer += 1;
+                }
+            }
+            pointer += 1;
+        }
+        pointer += 1;
+    }
+    segments.retain(|(_, text)| !text.is_empty());
+}
+
+/// Slide single edits surrounded by equalities to the position where their
+/// edges best line up with word, sentence and line boundaries.
+fn cleanup_semantic_lossless(segments: &mut Vec<Segment>) {
+    let mut pointer = 1;
+    while pointer + 1 < segments.len() {
+        if segments[pointer - 1].0 != Op::Equal || segments[pointer + 1].0 != Op::Equal {
+            pointer += 1;
+            continue;
+        }
+
+        let mut equality1 = segments[pointer - 1].1.clone();
+        let mut edit = segments[pointer].1.clone();
+        let mut equality2 = segments[pointer + 1].1.clone();
+
+        // Shift the edit as far left as possible first.
+        let common = common_suffix_str(&equality1, &edit);
+        if common > 0 {
+            let shared = edit[edit.len() - common..].to_string();
+            equality1.truncate(equality1.len() - common);
+            edit = format!("{shared}{}", &edit[..edit.len() - common]);
+            equality2.insert_str(0, &shared);
+        }
+
+        // Then step right one char at a time, keeping the best-scoring fit.
+        let mut best = (equality1.clone(), edit.clone(), equality2.clone());
+        let mut best_score = boundary_score(&equality1, &edit) + boundary_score(&edit, &equality2);
+        while let (Some(c), Some(d)) = (edit.chars().next(), equality2.chars().next()) {
+            if c != d {
+                break;
+            }
+            equality1.push(c);
+            edit.remove(0);
+            edit.push(c);
+            equality2.remove(0);
+            let score = boundary_score(&equality1, &edit) + boundary_score(&edit, &equality2);
+            // ">=" favours the rightmost of equally good positions.
+            if score >= best_score {
+                best_score = score;
+                best = (equality1.clone(), edit.clone(), equality2.clone());
+            }
+        }
+
+        let (best1, best_edit, best2) = best;
+        if segments[pointer - 1].1 != best1 {
+            segments[pointer].1 = best_edit;
+            if best2.is_empty() {
+                segments.remove(pointer + 1);
+            } else {
+                segments[pointer + 1].1 = best2;
+            }
+            if best1.is_empty() {
+                segments.remove(pointer - 1);
+                pointer -= 1;
+            } else {
+                segments[pointer - 1].1 = best1;
+            }
+        }
+        pointer += 1;
+    }
+}
+
+/// How good a split between `one` and `two` is, from 6 (edge of the text)
+/// through blank lines, line breaks, sentence ends and whitespace down to 0
+/// (the middle of a word).
+fn boundary_score(one: &str, two: &str) -> u8 {
+    let (Some(char1), Some(char2)) = (one.chars().next_back(), two.chars().next()) else {
+        return 6;
+    };
+    let non_alnum1 = !char1.is_alphanumeric();
+    let non_alnum2 = !char2.is_alphanumeric();
+    let whitespace1 = non_alnum1 && char1.is_whitespace();
+    let whitespace2 = non_alnum2 && char2.is_whitespace();
+    let line_break1 = whitespace1 && (char1 == '\n' || char1 == '\r');
+    let line_break2 = whitespace2 && (char2 == '\n' || char2 == '\r');
+    let blank_line1 = line_break1 && (one.ends_with("\n\n") || one.ends_with("\n\r\n"));
+    let blank_line2 = line_break2
+        && ["\n\n", "\n\r\n", "\r\n\n", "\r\n\r\n"]
+            .iter()
+            .any(|p| two.starts_with(p));
+
+    if blank_line1 || blank_line2 {
+        5
+    } else if line_break1 || line_break2 {
+        4
+    } else if non_alnum1 && !whitespace1 && whitespace2 {
+        3
+    } else if whitespace1 || whitespace2 {
+        2
+    } else if non_alnum1 || non_alnum2 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Turn short equalities into a delete and an insert when they sit between
+/// edits on both sides, or between three edit kinds and are very short.
+fn cleanup_efficiency(segments: &mut Vec<Segment>, edit_cost: usize) {
+    let mut changes = false;
+    let mut equalities: Vec<usize> = Vec::new();
+    let mut last_equality: Option<usize> = None;
+    let mut pointer = 0;
+    // Whether there is an insert/delete before and after the last equality.
+    let (mut pre_ins, mut pre_del) = (false, false);
+    let (mut post_ins, mut post_del) = (false, false);
+
+    while pointer < segments.len() {
+        if segments[pointer].0 == Op::Equal {
+            let len = segments[pointer].1.len();
+            if len < edit_cost && (post_ins || post_del) {
+                equalities.push(pointer);
+                pre_ins = post_ins;
+                pre_del = post_del;
+                last_equality = Some(len);
+            } else {
+                equalities.clear();
+                last_equality = None;
+            }
+            post_ins = false;
+            post_del = false;
+            pointer += 1;
+            continue;
+        }
+
+        if segments[pointer].0 == Op::Delete {
+            post_del = true;
+        } else {
+            post_ins = true;
+        }
+        let sides = [pre_ins, pre_del, post_ins, post_del];
+        let surrounded = last_equality.is_some_and(|len| {
+            sides.iter().all(|&s| s)
+                || (len * 2 < edit_cost && sides.iter().filter(|&&s| s).count() == 3)
+        });
+        if surrounded {
+            let at = *equalities.last().expect("an equality was seen");
+            let text = segments[at].1.clone();
+            segments.insert(at, (Op::Delete, text));
+            segments[at + 1].0 = Op::Insert;
+            equalities.pop();
+            last_equality = None;
+            changes = true;
+            if pre_ins && pre_del {
+                // No changes made that could affect previous entries.
+                post_ins = true;
+                post_del = true;
+                equalities.clear();
+            } else {
+                equalities.pop();
+                pointer = equalities.last().map_or(0, |&e| e + 1);
+                post_ins = false;
+                post_del = false;
+                continue;
+            }
+        }
+        pointer += 1;
+    }
+
+    if changes {
+        cleanup_merge(segments);
+    }
+}
+
+/// Apply edits to `text`, returning the transformed result.
+///
+/// Edits are applied in reverse order to avoid cascading position shifts.
+/// `text` must be the source the edits were computed against: an edit that
+/// falls outside it or splits a UTF-8 sequence yields `InvalidPosition`, and
+/// edits that are out of order or overlap yield `InvalidEdit`. Use
+/// [`apply_hunks`] to patch text that may have drifted.
+pub fn patch(text: &str, edit_list: &EditList) -> Result<String, PatchError> {
+    let mut result = text.to_string();
+    // Processing back to front, every edit must end before the previous starts.
+    let mut limit = text.len();
+
+    for edit in edit_list.edits.iter().rev() {
+        let (pos, old_len, new_text) = edit.parts();
+        let end = pos
+            .checked_add(old_len)
+            .ok_or(PatchError::InvalidPosition)?;
+        if end > text.len() || !text.is_char_boundary(pos) || !text.is_char_boundary(end) {
+            return Err(PatchError::InvalidPosition);
+        }
+        if end > limit {
+            return Err(PatchError::InvalidEdit);
+        }
+        result.replace_range(pos..end, new_text);
+        limit = pos;
+    }
+
+    Ok(result)
+}
+
+/// Longest pattern the Bitap matcher can search for in one pass.
+const MATCH_MAX_BITS: usize = 32;
+
+/// Tuning for context-anchored fuzzy patching, mirroring the knobs of
+/// diff-match-patch's `match_main` / `patch_apply`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatchOptions {
+    /// How sloppy a match may be: 0.0 demands a perfect match at the expected
+    /// location, 1.0 accepts almost anything.
+    pub match_threshold: f64,
+    /// How far (in chars) from the expected location a match may be found
+    /// before its score reaches `match_threshold`. 0 requires the exact spot.
+    pub match_distance: usize,
+    /// For hunks too long for a single Bitap pass, the largest share of the
+    /// pattern that may differ from the located text before rejecting it.
+    pub delete_threshold: f64,
+    /// Chars of surrounding context captured on each side of an edit.
+    pub context_len: usize,
+}
+
+impl Default for PatchOptions {
+    fn default() -> Self {
+        Self {
+            match_threshold: 0.5,
+            match_distance: 1000,
+            delete_threshold: 0.5,
+            context_len: 4,
+        }
+    }
+}
+
+/// An edit anchored by the source text around it, so it can still be placed
+/// correctly after the target has drifted away from the source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hunk {
+    /// Char offset of the edit in the source text, so it can be compared with
+    /// positions in a drifted target without knowing the source.
+    pub pos: usize,
+    pub before: String,
+    pub old_text: String,
+    pub new_text: String,
+    pub after: String,
+}
+
+impl Hunk {
+    /// The source text this hunk expects to find: context plus replaced text.
+    fn pattern(&self) -> Vec<char> {
+        self.before
+            .chars()
+            .chain(self.old_text.chars())
+            .chain(self.after.chars())
+            .collect()
+    }
+}
+
+/// Attach context from `source` to every edit in `edit_list`.
+///
+/// Context starts at `options.context_len` chars per side and grows until the
+/// anchored pattern is unique in `source` (or too long to match in one pass).
+pub fn make_hunks(source: &str, edit_list: &EditList, options: &PatchOptions) -> Vec<Hunk> {
+    let chars: Vec<char> = source.chars().collect();
+    let offsets = char_offsets(source);
+    let to_char = |byte: usize| offsets.partition_point(|&o| o < byte.min(source.len()));
+
+    edit_list
+        .edits
+        .iter()
+        .map(|edit| {
+            let (pos, old_len, new_text) = edit.parts();
+            let start = to_char(pos);
+            let end = to_char(pos + old_len).max(start);
+
+            let margin = options.context_len;
+            let mut padding = 0;
+            loop {
+                padding += margin;
+                let lo = start.saturating_sub(padding);
+                let hi = (end + padding).min(chars.len());
+                let pattern = &chars[lo..hi];
+                let unique = pattern.is_empty()
+                    || chars
+                        .windows(pattern.len())
+                        .filter(|w| *w == pattern)
+                        .nth(1)
+                        .is_none();
+                let whole = lo == 0 && hi == chars.len();
+                if margin == 0 || unique || whole || hi - lo + 2 * margin > MATCH_MAX_BITS {
+                    break;
+                }
+            }
+            let lo = start.saturating_sub(padding);
+            let hi = (end + padding).min(chars.len());
+
+            Hunk {
+                pos: start,
+                before: chars[lo..start].iter().collect(),
+                old_text: chars[start..end].iter().collect(),
+                new_text: new_text.to_string(),
+                after: chars[end..hi].iter().collect(),
+            }
+        })
+        .collect()
+}
+
+/// How a single hunk fared when patched into a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HunkOutcome {
+    /// The expected text was found unchanged at the expected position.
+    Exact,
+    /// The hunk was placed, but `offset` chars away from the expected
+    /// position and/or over context that had been edited.
+    Fuzzy { offset: isize },
+    /// No acceptable match was found; the hunk was not applied.
+    Rejected,
+}
+
+/// Patched text together with the outcome of every hunk, in hunk order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PatchReport {
+    pub text: String,
+    pub outcomes: Vec<HunkOutcome>,
+}
+
+impl PatchReport {
+    /// Whether every hunk was applied, exactly or fuzzily.
+    pub fn is_clean(&self) -> bool {
+        self.rejected_count() == 0
+    }
+
+    pub fn applied_count(&self) -> usize {
+        self.outcomes.len() - self.rejected_count()
+    }
+
+    pub fn rejected_count(&self) -> usize {
+        self.rejected().count()
+    }
+
+    pub fn fuzzy_count(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|o| matches!(o, HunkOutcome::Fuzzy { .. }))
+            .count()
+    }
+
+    /// Indices of the hunks (and so of the source edits) that were rejected.
+    pub fn rejected(&self) -> impl Iterator<Item = usize> + '_ {
+        self.outcomes
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| **o == HunkOutcome::Rejected)
+            .map(|(i, _)| i)
+    }
+}
+
+/// Apply context-anchored hunks to `text`, which may have drifted from the
+/// source the hunks were made against.
+///
+/// Each hunk is located near its expected position with Bitap matching and
+/// applied there; hunks with no acceptable match are skipped and reported as
+/// [`HunkOutcome::Rejected`].
+pub fn apply_hunks(text: &str, hunks: &[Hunk], options: &PatchOptions) -> PatchReport {
+    let mut result: Vec<char> = text.chars().collect();
+    let mut outcomes = Vec::with_capacity(hunks.len());
+    // Shift (in chars) between where hunks were expected and where they are
+    // being found, including the length change of hunks already applied.
+    let mut delta: isize = 0;
+
+    for hunk in hunks {
+        let pattern = hunk.pattern();
+        let before_len = hunk.before.chars().count();
+        let old_len = hunk.old_text.chars().count();
+        let base = hunk.pos.saturating_sub(before_len) as isize;
+        let expected = (base + delta).max(0) as usize;
+
+        let found = if pattern.len() > MATCH_MAX_BITS {
+            let head = &pattern[..MATCH_MAX_BITS];
+            let tail = &pattern[pattern.len() - MATCH_MAX_BITS..];
+            match_main(&result, head, expected, options).and_then(|start| {
+                match_main(
+                    &result,
+                    tail,
+                    expected + pattern.len() - MATCH_MAX_BITS,
+                    options,
+                )
+                .filter(|&end| end > start)
+                .map(|end| (start, (end + MATCH_MAX_BITS).min(result.len())))
+            })
+        } else {
+            match_main(&result, &pattern, expected, options)
+                .map(|start| (start, (start + pattern.len()).min(result.len())))
+        };
+
+        let Some((start, end)) = found else {
+            outcomes.push(HunkOutcome::Rejected);
+            continue;
+        };
+
+        let offset = start as isize - expected as isize;
+        let found_text = &result[start..end];
+        let (from, to, outcome) = if found_text == pattern.as_slice() {
+            let outcome = if offset == 0 {
+                HunkOutcome::Exact
+            } else {
+                HunkOutcome::Fuzzy { offset }
+            };
+            (start + before_len, start + before_len + old_len, outcome)
+        } else {
+            // Map the replaced region through the differences between the
+            // expected pattern and the text actually found.
+            let runs = diff_units(&pattern, found_text);
+            if pattern.len() > MATCH_MAX_BITS
+                && levenshtein(&runs) as f64 / pattern.len() as f64 > options.delete_threshold
+            {
+                outcomes.push(HunkOutcome::Rejected);
+                continue;
+            }
+            (
+                start + x_index(&runs, before_len),
+                start + x_index(&runs, before_len + old_len),
+                HunkOutcome::Fuzzy { offset },
+            )
+        };
+
+        let new_chars: Vec<char> = hunk.new_text.chars().collect();
+        let shift = new_chars.len() as isize - (to - from) as isize;
+        result.splice(from..to, new_chars);
+        delta = start as isize - base + shift;
+        outcomes.push(outcome);
+    }
+
+    PatchReport {
+        text: result.into_iter().collect(),
+        outcomes,
+    }
+}
+
+/// Locate `pattern` in `text` near `loc`, preferring an exact hit there and
+/// otherwise falling back to Bitap.
+fn match_main(
+    text: &[char],
+    pattern: &[char],
+    loc: usize,
+    options: &PatchOptions,
+) -> Option<usize> {
+    let loc = loc.min(text.len());
+    if text == pattern {
+        Some(0)
+    } else if text.is_empty() {
+        None
+    } else if text[loc..].starts_with(pattern) {
+        Some(loc)
+    } else {
+        match_bitap(text, pattern, loc, options)
+    }
+}
+
+/// Fuzzy-search for `pattern` near `loc` using the Bitap (shift-or) algorithm,
+/// scoring candidates by error count and distance from `loc`.
+fn match_bitap(
+    text: &[char],
+    pattern: &[char],
+    loc: usize,
+    options: &PatchOptions,
+) -> Option<usize> {
+    debug_assert!(pattern.len() <= MATCH_MAX_BITS);
+
+    let plen = pattern.len();
+    let mut alphabet: std::collections::HashMap<char, u64> = std::collections::HashMap::new();
+    for (i, c) in pattern.iter().enumerate() {
+        *alphabet.entry(*c).or_insert(0) |= 1 << (plen - i - 1);
+    }
+
+    let loc_i = loc as isize;
+    let score = |errors: usize, x: isize| -> f64 {
+        let accuracy = errors as f64 / plen as f64;
+        let proximity = (loc_i - x).unsigned_abs();
+        if options.match_distance == 0 {
+            return if proximity == 0 { accuracy } else { 1.0 };
+        }
+        accuracy + proximity as f64 / options.match_distance as f64
+    };
+
+    let mut score_threshold = options.match_threshold;
+    // Exact matches nearby bound how bad a fuzzy match may be.
+    let find_from = |start: usize| {
+        text.get(start..)
+            .and_then(|t| t.windows(plen).position(|w| w == pattern))
+            .map(|i| i + start)
+    };
+    if let Some(best) = find_from(loc) {
+        score_threshold = score_threshold.min(score(0, best as isize));
+    }
+    let search_end = (loc + plen).min(text.len());
+    if let Some(best) = text[..search_end].windows(plen).rposition(|w| w == pattern) {
+        score_threshold = score_threshold.min(score(0, best as isize));
+    }
+
+    let match_mask: u64 = 1 << (plen - 1);
+    let mut best_loc: Option<usize> = None;
+    let mut bin_max = (plen + text.len()) as isize;
+    let mut last_rd: Vec<u64> = Vec::new();
+
+    for d in 0..plen {
+        // Binary search for how far from `loc` a match with `d` errors could
+        // still beat the current threshold.
+        let mut bin_min = 0;
+        let mut bin_mid = bin_max;
+        while bin_min < bin_mid {
+            if score(d, loc_i + bin_mid) <= score_threshold {
+                bin_min = bin_mid;
+            } else {
+                bin_max = bin_mid;
+            }
+            bin_mid = (bin_max - bin_min) / 2 + bin_min;
+        }
+        bin_max = bin_mid;
+
+        let mut start = (loc_i - bin_mid + 1).max(1);
+        let finish = ((loc_i + bin_mid).min(text.len() as isize) + plen as isize) as usize;
+
+        let mut rd = vec![0u64; finish + 2];
+        rd[finish + 1] = (1u64 << d) - 1;
+        let mut j = finish as isize;
+        while j >= start {
+            let ju = j as usize;
+            let char_match = text
+                .get(ju - 1)
+                .and_then(|c| alphabet.get(c))
+                .copied()
+                .unwrap_or(0);
+            rd[ju] = if d == 0 {
+                ((rd[ju + 1] << 1) | 1) & char_match
+            } else {
+                let prev = |i: usize| last_rd.get(i).copied().unwrap_or(0);
+                (((rd[ju + 1] << 1) | 1) & char_match)
+                    | (((prev(ju + 1) | prev(ju)) << 1) | 1)
+                    | prev(ju + 1)
+            };
+            if rd[ju] & match_mask != 0 {
+                let candidate = score(d, j - 1);
+                if candidate <= score_threshold {
+                    score_threshold = candidate;
+                    best_loc = Some(ju - 1);
+                    if ju - 1 > loc {
+                        // Past `loc`: only look as far back as the mirror image.
+                        start = (2 * loc_i - (j - 1)).max(1);
+                    } else {
+                        break;
+                    }
+                }
+            }
+            j -= 1;
+        }
+
+        if score(d + 1, loc_i) > score_threshold {
+            break;
+        }
+        last_rd = rd;
+    }
+
+    best_loc
+}
+
+/// Index in the target sequence corresponding to index `loc` in the source.
+fn x_index(runs: &[(Op, usize)], loc: usize) -> usize {
+    let (mut x, mut y) = (0, 0);
+    for &(op, len) in runs {
+        let (next_x, next_y) = match op {
+            Op::Equal => (x + len, y + len),
+            Op::Delete => (x + len, y),
+            Op::Insert => (x, y + len),
+        };
+        if next_x > loc {
+            // Inside a deletion the position collapses to where it started.
+            return if op == Op::Delete { y } else { y + (loc - x) };
+        }
+        x = next_x;
+        y = next_y;
+    }
+    y
+}
+
+/// Edit distance implied by a diff script.
+fn levenshtein(runs: &[(Op, usize)]) -> usize {
+    let (mut total, mut inserted, mut deleted) = (0, 0, 0);
+    for &(op, len) in runs {
+        match op {
+            Op::Insert => inserted += len,
+            Op::Delete => deleted += len,
+            Op::Equal => {
+                total += inserted.max(deleted);
+                inserted = 0;
+                deleted = 0;
+            }
+        }
+    }
+    total + inserted.max(deleted)
+}
+
+/// Unchanged lines shown around each hunk of a unified diff.
+const UNIFIED_CONTEXT_LINES: usize = 3;
+
+/// Chars of context kept around each patch in diff-match-patch text.
+const PATCH_MARGIN: usize = 4;
+
+impl EditList {
+    /// Render the edits as a unified diff of `source` against the text they
+    /// produce, with `name` in the `---`/`+++` headers.
+    ///
+    /// The output can be fed to `patch -p1` or a code-review tool. Unchanged
+    /// input yields an empty string, like `diff -u`.
+    pub fn to_unified_diff(&self, source: &str, name: &str) -> Result<String, PatchError> {
+        let target = patch(source, self)?;
+        let (from_offsets, to_offsets) = (line_offsets(source), line_offsets(&target));
+        let mut ids = HashMap::new();
+        let from_lines = intern_tokens(source, &from_offsets, &mut ids);
+        let to_lines = intern_tokens(&target, &to_offsets, &mut ids);
+
+        // One entry per line of the script.
+        let mut lines: Vec<(Op, &str)> = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        for (op, len) in diff_units(&from_lines, &to_lines) {
+            for _ in 0..len {
+                let line = match op {
+                    Op::Insert => &target[to_offsets[j]..to_offsets[j + 1]],
+                    _ => &source[from_offsets[i]..from_offsets[i + 1]],
+                };
+                lines.push((op, line));
+                if op != Op::Insert {
+                    i += 1;
+                }
+                if op != Op::Delete {
+                    j += 1;
+                }
+            }
+        }
+
+        let changes: Vec<usize> = (0..lines.len())
+            .filter(|&k| lines[k].0 != Op::Equal)
+            .collect();
+        if changes.is_empty() {
+            return Ok(String::new());
+        }
+
+        let mut out = format!("--- a/{name}\n+++ b/{name}\n");
+        let (mut old_line, mut new_line, mut counted) = (0, 0, 0);
+        let mut c = 0;
+        while c < changes.len() {
+            let start = changes[c].saturating_sub(UNIFIED_CONTEXT_LINES);
+            let mut last = changes[c];
+            c += 1;
+            // Hunks separated by at most twice the context are merged.
+            while c < changes.len() && changes[c] - last <= 2 * UNIFIED_CONTEXT_LINES + 1 {
+                last = changes[c];
+                c += 1;
+            }
+            let end = (last + 1 + UNIFIED_CONTEXT_LINES).min(lines.len());
+
+            for &(op, _) in &lines[counted..start] {
+                old_line += usize::from(op != Op::Insert);
+                new_line += usize::from(op != Op::Delete);
+            }
+            let hunk = &lines[start..end];
+            let old_count = hunk.iter().filter(|(op, _)| *op != Op::Insert).count();
+            let new_count = hunk.iter().filter(|(op, _)| *op != Op::Delete).count();
+            out.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                hunk_range(old_line, old_count),
+                hunk_range(new_line, new_count)
+            ));
+            for &(op, line) in hunk {
+                out.push(match op {
+                    Op::Equal => ' ',
+                    Op::Delete => '-',
+                    Op::Insert => '+',
+                });
+                out.push_str(line);
+                if !line.ends_with('\n') {
+                    out.push_str("\n\\ No newline at end of file\n");
+                }
+            }
+            old_line += old_count;
+            new_line += new_count;
+            counted = end;
+        }
+
+        Ok(out)
+    }
+
+    /// Parse a unified diff of `source`, as produced by
+    /// [`to_unified_diff`](Self::to_unified_diff) or `diff -u`, back into
+    /// edits. File headers are skipped; every hunk must apply to `source`
+    /// exactly, otherwise `InvalidEdit` is returned.
+    pub fn from_unified_diff(source: &str, text: &str) -> Result<Self, PatchError> {
+        let offsets = line_offsets(source);
+        let source_line = |k: usize| offsets.get(k + 1).map(|&end| &source[offsets[k]..end]);
+        let mut edits = Vec::new();
+        let mut cursor = 0;
+        let mut body = text.split_inclusive('\n').enumerate().peekable();
+
+        while let Some((n, header)) = body.next() {
+            if !header.starts_with("@@") {
+                continue;
+            }
+            let malformed = PatchError::Malformed { line: n + 1 };
+            let ((start, mut old_left), (_, mut new_left)) =
+                parse_hunk_header(header).ok_or(malformed.clone())?;
+            if start < cursor || start + old_left > offsets.len() - 1 {
+                return Err(PatchError::InvalidPosition);
+            }
+            cursor = start;
+
+            let mut pending: Option<(usize, usize, String)> = None;
+            while old_left > 0 || new_left > 0 {
+                let (n, line) = body.next().ok_or(malformed.clone())?;
+                let malformed = PatchError::Malformed { line: n + 1 };
+                let (sign, rest) = match line.chars().next() {
+                    Some('\n') => (' ', line),
+                    Some(sign @ (' ' | '-' | '+')) => (sign, &line[1..]),
+                    _ => return Err(malformed),
+                };
+                let mut content = rest.strip_suffix('\n').unwrap_or(rest).to_string();
+                if body.next_if(|(_, l)| l.starts_with('\\')).is_none() {
+                    content.push('\n');
+                }
+
+                if sign != '+' {
+                    if source_line(cursor) != Some(content.as_str()) {
+                        return Err(PatchError::InvalidEdit);
+                    }
+                    old_left = old_left.checked_sub(1).ok_or(malformed.clone())?;
+                }
+                if sign != '-' {
+                    new_left = new_left.checked_sub(1).ok_or(malformed)?;
+                }
+                match sign {
+                    ' ' => {
+                        flush_pending(&mut edits, &mut pending);
+                        cursor += 1;
+                    }
+                    '-' => {
+                        pending.get_or_insert((offsets[cursor], 0, String::new())).1 +=
+                            content.len();
+                        cursor += 1;
+                    }
+                    _ => pending
+                        .get_or_insert((offsets[cursor], 0, String::new()))
+                        .2
+                        .push_str(&content),
+                }
+            }
+            flush_pending(&mut edits, &mut pending);
+        }
+
+        Ok(Self::new(edits, source))
+    }
+
+    /// Render the edits in diff-match-patch's patch text format.
+    ///
+    /// Each patch carries up to four chars of context and is URI-encoded one
+    /// segment per line under an `@@ -a,b +c,d @@` header. As in
+    /// diff-match-patch, coordinates count chars and each patch is positioned
+    /// in the text as left by the patches before it.
+    pub fn to_patch_text(&self, source: &str) -> Result<String, PatchError> {
+        patch(source, self)?;
+        let offsets = char_offsets(source);
+        let to_char = |byte: usize| offsets.partition_point(|&o| o < byte);
+        let slice = |from: usize, to: usize| &source[offsets[from]..offsets[to]];
+        let spans: Vec<(usize, usize, &str)> = self
+            .edits
+            .iter()
+            .map(|edit| {
+                let (pos, old_len, new_text) = edit.parts();
+                (to_char(pos), to_char(pos + old_len), new_text)
+            })
+            .collect();
+
+        let mut out = String::new();
+        let mut delta = 0isize;
+        let mut i = 0;
+        while i < spans.len() {
+            // Edits whose contexts would overlap share one patch.
+            let mut j = i + 1;
+            while j < spans.len() && spans[j].0 - spans[j - 1].1 < 2 * PATCH_MARGIN {
+                j += 1;
+            }
+            let group = &spans[i..j];
+            let start = group[0].0.saturating_sub(PATCH_MARGIN);
+            let end = (group[group.len() - 1].1 + PATCH_MARGIN).min(offsets.len() - 1);
+
+            let mut body = String::new();
+            let mut cursor = start;
+            let (old_len, mut new_len) = (end - start, end - start);
+            for &(from, to, new_text) in group {
+                push_patch_line(&mut body, ' ', slice(cursor, from));
+                push_patch_line(&mut body, '-', slice(from, to));
+                push_patch_line(&mut body, '+', new_text);
+                new_len = new_len - (to - from) + new_text.chars().count();
+                cursor = to;
+            }
+            push_patch_line(&mut body, ' ', slice(cursor, end));
+
+            let at = start.saturating_add_signed(delta);
+            out.push_str(&format!(
+                "@@ -{} +{} @@\n{body}",
+                hunk_range(at, old_len),
+                hunk_range(at, new_len)
+            ));
+            delta += new_len as isize - old_len as isize;
+            i = j;
+        }
+
+        Ok(out)
+    }
+
+    /// Parse diff-match-patch patch text for `source`, as produced by
+    /// [`to_patch_text`](Self::to_patch_text) or diff-match-patch's
+    /// `patch_toText`. Context and deleted text must match `source` exactly,
+    /// otherwise `InvalidEdit` is returned.
+    pub fn from_patch_text(source: &str, text: &str) -> Result<Self, PatchError> {
+        let offsets = char_offsets(source);
+        let mut edits = Vec::new();
+        let mut delta = 0isize;
+        let mut floor = 0;
+        let mut lines = text.split('\n').enumerate().peekable();
+
+        while let Some((n, header)) = lines.next() {
+            if header.is_empty() {
+                continue;
+            }
+            let ((start, _), _) =
+                parse_hunk_header(header).ok_or(PatchError::Malformed { line: n + 1 })?;
+            let mut cursor = start
+                .checked_add_signed(-delta)
+                .filter(|&c| c >= floor && c < offsets.len())
+                .ok_or(PatchError::InvalidPosition)?;
+
+            let mut pending: Option<(usize, usize, String)> = None;
+            while let Some((n, line)) = lines.next_if(|(_, l)| !l.starts_with('@')) {
+                let Some(sign) = line.chars().next() else {
+                    continue;
+                };
+                let text = decode_patch_text(&line[sign.len_utf8()..])
+                    .ok_or(PatchError::Malformed { line: n + 1 })?;
+                let len = text.chars().count();
+                if sign != '+' {
+                    let expected = offsets
+                        .get(cursor + len)
+                        .map(|&end| &source[offsets[cursor]..end]);
+                    if expected != Some(text.as_str()) {
+                        return Err(PatchError::InvalidEdit);
+                    }
+                }
+                match sign {
+                    ' ' => flush_pending(&mut edits, &mut pending),
+                    '-' => {
+                        pending.get_or_insert((offsets[cursor], 0, String::new())).1 += text.len();
+                        delta -= len as isize;
+                    }
+                    '+' => {
+                        pending
+                            .get_or_insert((offsets[cursor], 0, String::new()))
+                            .2
+                            .push_str(&text);
+                        delta += len as isize;
+                        continue;
+                    }
+                    _ => return Err(PatchError::Malformed { line: n + 1 }),
+                }
+                cursor += len;
+            }
+            flush_pending(&mut edits, &mut pending);
+            floor = cursor;
+        }
+
+        Ok(Self::new(edits, source))
+    }
+
+    /// Compact, lossless form of the list in the style of diff-match-patch's
+    /// `toDelta`: tab-separated tokens where `=n` skips `n` units, `-n` removes
+    /// `n` units and `+text` inserts URI-encoded text. A `Replace` is a `-n`
+    /// directly followed by `+text`; consecutive edits are always separated by
+    /// an `=` token, even `=0`, so every variant survives the round trip.
+    /// Counts are in whatever unit the positions are in, and nothing is
+    /// emitted after the last edit.
+    pub fn to_delta(&self) -> Result<DeltaEdits, PatchError> {
+        let mut tokens = Vec::with_capacity(self.edits.len() * 2);
+        let mut cursor = 0;
+        for (i, edit) in self.edits.iter().enumerate() {
+            let (pos, old_len, new_text) = edit.parts();
+            let gap = pos.checked_sub(cursor).ok_or(PatchError::InvalidPosition)?;
+            if i > 0 || gap > 0 {
+                tokens.push(format!("={gap}"));
+            }
+            if !matches!(edit, Edit::Insert { .. }) {
+                tokens.push(format!("-{old_len}"));
+            }
+            if !matches!(edit, Edit::Delete { .. }) {
+                let mut token = String::from("+");
+                encode_uri(&mut token, new_text);
+                tokens.push(token);
+            }
+            cursor = pos + old_len;
+        }
+        Ok(DeltaEdits {
+            delta: tokens.join("\t"),
+            checksum: self.checksum.clone(),
+        })
+    }
+
+    /// Inverse of [`to_delta`](Self::to_delta). Also accepts a trailing `=n`,
+    /// as diff-match-patch emits; anything else that does not follow the
+    /// grammar is an `InvalidEdit`.
+    pub fn from_delta(delta: &DeltaEdits) -> Result<Self, PatchError> {
+        let mut edits = Vec::new();
+        let mut cursor = 0usize;
+        // Removed length and inserted text of the edit being read.
+        let mut current: Option<(Option<usize>, Option<String>)> = None;
+        let count = |n: &str| n.parse::<usize>().map_err(|_| PatchError::InvalidEdit);
+
+        for token in delta.delta.split('\t').filter(|t| !t.is_empty()) {
+            let rest = token.get(1..).ok_or(PatchError::InvalidEdit)?;
+            match &token[..1] {
+                "=" => {
+                    if let Some(edit) = current.take() {
+                        let (pos, old_len) = (cursor, edit.0.unwrap_or(0));
+                        edits.push(delta_edit(pos, edit));
+                        cursor = pos + old_len;
+                    }
+                    cursor = cursor
+                        .checked_add(count(rest)?)
+                        .ok_or(PatchError::InvalidPosition)?;
+                }
+                "-" if current.is_none() => current = Some((Some(count(rest)?), None)),
+                "+" => {
+                    let text = decode_patch_text(rest).ok_or(PatchError::InvalidEdit)?;
+                    match current.get_or_insert((None, None)) {
+                        (_, inserted @ None) => *inserted = Some(text),
+                        _ => return Err(PatchError::InvalidEdit),
+                    }
+                }
+                _ => return Err(PatchError::InvalidEdit),
+            }
+        }
+        if let Some(edit) = current {
+            edits.push(delta_edit(cursor, edit));
+        }
+
+        Ok(Self {
+            edits,
+            checksum: delta.checksum.clone(),
+        })
+    }
+}
+
+/// [`EditList`] in the compact wire form produced by [`EditList::to_delta`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeltaEdits {
+    pub delta: String,
+    pub checksum: String,
+}
+
+/// Edit at `pos` from a parsed delta edit, keeping its variant.
+fn delta_edit(pos: usize, (removed, inserted): (Option<usize>, Option<String>)) -> Edit {
+    match (removed, inserted) {
+        (Some(len), None) => Edit::Delete { pos, len },
+        (None, Some(text)) => Edit::Insert { pos, text },
+        (old_len, new_text) => Edit::Replace {
+            pos,
+            old_len: old_len.unwrap_or(0),
+            new_text: new_text.unwrap_or_default(),
+        },
+    }
+}
+
+/// Turn a pending `(pos, old_len, new_text)` change into an edit.
+fn flush_pending(edits: &mut Vec<Edit>, pending: &mut Option<(usize, usize, String)>) {
+    if let Some((pos, old_len, new_text)) = pending.take() {
+        edits.extend(Edit::from_parts(pos, old_len, new_text));
+    }
+}
+
+/// `start,count` for a hunk header, where `start` is the zero-based index of
+/// the first line or char. Follows `diff -u`: a one-unit range omits the
+/// count, and an empty range names the unit before it.
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+/// Parse `@@ -a,b +c,d @@` into zero-based `(start, count)` pairs, the inverse
+/// of [`hunk_range`]. Anything after the closing `@@` is ignored.
+fn parse_hunk_header(line: &str) -> Option<((usize, usize), (usize, usize))> {
+    let range = |text: &str| -> Option<(usize, usize)> {
+        let (start, count) = match text.split_once(',') {
+            Some((start, count)) => (start.parse::<usize>().ok()?, count.parse().ok()?),
+            None => (text.parse().ok()?, 1),
+        };
+        Some(if count == 0 {
+            (start, 0)
+        } else {
+            (start.checked_sub(1)?, count)
+        })
+    };
+
+    let mut parts = line.trim_end().split(' ');
+    if parts.next() != Some("@@") {
+        return None;
+    }
+    let old = range(parts.next()?.strip_prefix('-')?)?;
+    let new = range(parts.next()?.strip_prefix('+')?)?;
+    (parts.next() == Some("@@")).then_some((old, new))
+}
+
+fn push_patch_line(out: &mut String, sign: char, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    out.push(sign);
+    encode_uri(out, text);
+    out.push('\n');
+}
+
+/// Escape like JavaScript's encodeURI, but keep spaces readable.
+fn encode_uri(out: &mut String, text: &str) {
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b" -_.!~*'();/?:@&=+$,#".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{byte:02X}"));
+        }
+    }
+}
+
+fn decode_patch_text(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// One step of an edit list walked from the start of its source: keep or
+/// drop that many bytes, or insert text.
+#[derive(Debug, Clone, Copy)]
+enum Step<'a> {
+    Retain(usize),
+    Delete(usize),
+    Insert(&'a str),
+}
+
+impl<'a> Step<'a> {
+    fn len(self) -> usize {
+        match self {
+            Step::Retain(n) | Step::Delete(n) => n,
+            Step::Insert(text) => text.len(),
+        }
+    }
+
+    /// The part of the step left after its first `n` bytes, if any.
+    fn skip(self, n: usize) -> Result<Option<Self>, PatchError> {
+        if n >= self.len() {
+            return Ok(None);
+        }
+        Ok(Some(match self {
+            Step::Retain(len) => Step::Retain(len - n),
+            Step::Delete(len) => Step::Delete(len - n),
+            Step::Insert(text) => Step::Insert(text.get(n..).ok_or(PatchError::InvalidPosition)?),
+        }))
+    }
+}
+
+/// Accumulates retain/delete/insert steps into ascending edits.
+#[derive(Default)]
+struct EditBuilder {
+    edits: Vec<Edit>,
+    pos: usize,
+    deleted: usize,
+    inserted: String,
+}
+
+impl EditBuilder {
+    fn retain(&mut self, n: usize) {
+        self.flush();
+        self.pos += n;
+    }
+
+    fn delete(&mut self, n: usize) {
+        self.deleted += n;
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.inserted.push_str(text);
+    }
+
+    fn flush(&mut self) {
+        let inserted = std::mem::take(&mut self.inserted);
+        self.edits
+            .extend(Edit::from_parts(self.pos, self.deleted, inserted));
+        self.pos += std::mem::take(&mut self.deleted);
+    }
+
+    fn finish(mut self) -> Vec<Edit> {
+        self.flush();
+        self.edits
+    }
+}
+
+impl EditList {
+    /// Edits that turn the text these edits produce back into `source`.
+    pub fn invert(&self, source: &str) -> Result<EditList, PatchError> {
+        let target = patch(source, self)?;
+        let mut delta = 0isize;
+        let edits = self
+            .edits
+            .iter()
+            .filter_map(|edit| {
+                let (pos, old_len, new_text) = edit.parts();
+                let at = pos.saturating_add_signed(delta);
+                delta += new_text.len() as isize - old_len as isize;
+                Edit::from_parts(at, new_text.len(), source[pos..pos + old_len].to_string())
+            })
+            .collect();
+        Ok(EditList::new(edits, &target))
+    }
+
+    /// A single edit list with the effect of applying `a` and then `b`, where
+    /// `b` was computed against the text `a` produces. The result applies to
+    /// `a`'s source and carries its checksum.
+    pub fn compose(a: &EditList, b: &EditList) -> Result<EditList, PatchError> {
+        let (a_len, b_len) = (a.target_span()?, b.source_span());
+        let mut steps_a = a.steps(b_len.saturating_sub(a_len))?.into_iter();
+        let mut steps_b = b.steps(a_len.saturating_sub(b_len))?.into_iter();
+        let (mut step_a, mut step_b) = (steps_a.next(), steps_b.next());
+        let mut out = EditBuilder::default();
+
+        loop {
+            match (step_a, step_b) {
+                (None, None) => break,
+                (Some(Step::Delete(n)), _) => {
+                    out.delete(n);
+                    step_a = steps_a.next();
+                }
+                (_, Some(Step::Insert(text))) => {
+                    out.insert(text);
+                    step_b = steps_b.next();
+                }
+                (Some(x), Some(y)) => {
+                    let n = x.len().min(y.len());
+                    match (x, y) {
+                        (Step::Retain(_), Step::Retain(_)) => out.retain(n),
+                        (Step::Retain(_), Step::Delete(_)) => out.delete(n),
+                        (Step::Insert(text), Step::Retain(_)) => {
+                            out.insert(text.get(..n).ok_or(PatchError::InvalidPosition)?)
+                        }
+                        // Text inserted by `a` and deleted again by `b`.
+                        (Step::Insert(_), Step::Delete(_)) => {}
+                        _ => unreachable!("deletes in a and inserts in b are handled above"),
+                    }
+                    step_a = x.skip(n)?.or_else(|| steps_a.next());
+                    step_b = y.skip(n)?.or_else(|| steps_b.next());
+                }
+                _ => unreachable!("both walks are padded to the same length"),
+            }
+        }
+
+        Ok(EditList {
+            edits: out.finish(),
+            checksum: a.checksum.clone(),
+        })
+    }
+
+    /// Bytes of source text the edits reach into.
+    fn source_span(&self) -> usize {
+        self.edits.last().map_or(0, |edit| {
+            let (pos, old_len, _) = edit.parts();
+            pos + old_len
+        })
+    }
+
+    /// Length of the text [`source_span`](Self::source_span) bytes become.
+    /// Fails for edits out of order, which may delete more than the span.
+    fn target_span(&self) -> Result<usize, PatchError> {
+        self.edits.iter().try_fold(self.source_span(), |len, edit| {
+            let (_, old_len, new_text) = edit.parts();
+            let kept = len.checked_sub(old_len).ok_or(PatchError::InvalidEdit)?;
+            Ok(kept + new_text.len())
+        })
+    }
+
+    /// The edits as steps over their source, followed by `pad` retained bytes.
+    fn steps(&self, pad: usize) -> Result<Vec<Step<'_>>, PatchError> {
+        let mut steps = Vec::new();
+        let mut cursor = 0;
+        for edit in &self.edits {
+            let (pos, old_len, new_text) = edit.parts();
+            if pos < cursor {
+                return Err(PatchError::InvalidEdit);
+            }
+            steps.extend([
+                Step::Retain(pos - cursor),
+                Step::Delete(old_len),
+                Step::Insert(new_text),
+            ]);
+            cursor = pos + old_len;
+        }
+        steps.push(Step::Retain(pad));
+        steps.retain(|step| step.len() > 0);
+        Ok(steps)
+    }
+}
+
+/// Rebase two concurrent edit lists over each other.
+///
+/// Given `a` and `b` computed against the same source, returns `(a', b')`
+/// such that applying `b` then `a'` gives the same text as applying `a` then
+/// `b'`. Text one side deleted is not deleted twice, text the other side
+/// inserted inside a deleted range survives, and when both insert at the same
+/// position `a`'s text comes first.
+///
+/// The rebased lists apply to texts this function never sees, so their
+/// checksums are left empty for the caller to fill in.
+pub fn transform(a: &EditList, b: &EditList) -> Result<(EditList, EditList), PatchError> {
+    let (a_len, b_len) = (a.source_span(), b.source_span());
+    let mut steps_a = a.steps(b_len.saturating_sub(a_len))?.into_iter();
+    let mut steps_b = b.steps(a_len.saturating_sub(b_len))?.into_iter();
+    let (mut step_a, mut step_b) = (steps_a.next(), steps_b.next());
+    let (mut a_prime, mut b_prime) = (EditBuilder::default(), EditBuilder::default());
+
+    loop {
+        match (step_a, step_b) {
+            (None, None) => break,
+            (Some(Step::Insert(text)), _) => {
+                a_prime.insert(text);
+                b_prime.retain(text.len());
+                step_a = steps_a.next();
+            }
+            (_, Some(Step::Insert(text))) => {
+                a_prime.retain(text.len());
+                b_prime.insert(text);
+                step_b = steps_b.next();
+            }
+            (Some(x), Some(y)) => {
+                let n = x.len().min(y.len());
+                match (x, y) {
+                    (Step::Retain(_), Step::Retain(_)) => {
+                        a_prime.retain(n);
+                        b_prime.retain(n);
+                    }
+                    (Step::Delete(_), Step::Retain(_)) => a_prime.delete(n),
+                    (Step::Retain(_), Step::Delete(_)) => b_prime.delete(n),
+                    // Both sides deleted the same text.
+                    (Step::Delete(_), Step::Delete(_)) => {}
+                    _ => unreachable!("inserts are handled above"),
+                }
+                step_a = x.skip(n)?.or_else(|| steps_a.next());
+                step_b = y.skip(n)?.or_else(|| steps_b.next());
+            }
+            _ => unreachable!("both walks are padded to the same length"),
+        }
+    }
+
+    let rebased = |edits| EditList {
+        edits,
+        checksum: String::new(),
+    };
+    Ok((rebased(a_prime.finish()), rebased(b_prime.finish())))
+}
+
+/// Outcome of a three-way merge.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergeResult {
+    /// Merged text, with our side kept wherever the two sides conflict.
+    pub text: String,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A region both sides changed in different ways.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conflict {
+    /// Byte offset in [`MergeResult::text`] where our version of the region
+    /// starts.
+    pub pos: usize,
+    pub base: String,
+    pub ours: String,
+    pub theirs: String,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// The merged text with every conflict spelled out between
+    /// `<<<<<<< ours`, `=======` and `>>>>>>> theirs` markers.
+    pub fn conflict_marker_text(&self) -> String {
+        let mut out = String::new();
+        let mut cursor = 0;
+        for conflict in &self.conflicts {
+            out.push_str(&self.text[cursor..conflict.pos]);
+            out.push_str("<<<<<<< ours\n");
+            push_marker_side(&mut out, &conflict.ours);
+            out.push_str("=======\n");
+            push_marker_side(&mut out, &conflict.theirs);
+            out.push_str(">>>>>>> theirs\n");
+            cursor = conflict.pos + conflict.ours.len();
+        }
+        out.push_str(&self.text[cursor..]);
+        out
+    }
+}
+
+fn push_marker_side(out: &mut String, side: &str) {
+    out.push_str(side);
+    if !side.is_empty() && !side.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+/// Merge two descendants of `base` line by line.
+///
+/// Changes only one side made are taken as they are, and identical changes on
+/// both sides once. Where both sides changed the same or adjacent lines
+/// differently, the result keeps our version and records a [`Conflict`].
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> MergeResult {
+    let options = DiffOptions {
+        granularity: DiffGranularity::Line,
+        ..DiffOptions::default()
+    };
+    merge3_with_options(base, ours, theirs, &options)
+}
+
+/// [`merge3`] with the diffs against `base` computed using `options`, e.g. at
+/// word granularity for prose edited within the same line.
+pub fn merge3_with_options(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    options: &DiffOptions,
+) -> MergeResult {
+    let ours_edits = diff_with_options(base, ours, options);
+    let theirs_edits = diff_with_options(base, theirs, options);
+
+    // (is ours, start, end, replacement) in base coordinates.
+    let mut changes: Vec<(bool, usize, usize, &str)> = ours_edits
+        .edits
+        .iter()
+        .map(|edit| (true, edit))
+        .chain(theirs_edits.edits.iter().map(|edit| (false, edit)))
+        .map(|(side, edit)| {
+            let (pos, old_len, new_text) = edit.parts();
+            (side, pos, pos + old_len, new_text)
+        })
+        .collect();
+    changes.sort_by_key(|&(_, start, _, _)| start);
+
+    let mut text = String::new();
+    let mut conflicts = Vec::new();
+    let mut cursor = 0;
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes from the two sides that overlap or touch form one region.
+        let (_, start, mut end, _) = changes[i];
+        let mut j = i + 1;
+        while j < changes.len() {
+            let (side, next_start, next_end, _) = changes[j];
+            let other_side_seen = changes[i..j].iter().any(|c| c.0 != side);
+            if next_start < end || (next_start == end && other_side_seen) {
+                end = end.max(next_end);
+                j += 1;
+            } else {
+                break;
+            }
+        }
+
+        let region = &changes[i..j];
+        let resolve = |side: bool| -> String {
+            let mut out = String::new();
+            let mut at = start;
+            for &(_, from, to, new_text) in region.iter().filter(|c| c.0 == side) {
+                out.push_str(&base[at..from]);
+                out.push_str(new_text);
+                at = to;
+            }
+            out.push_str(&base[at..end]);
+            out
+        };
+        let (mine, other) = (resolve(true), resolve(false));
+
+        text.push_str(&base[cursor..start]);
+        if region.iter().all(|c| c.0) || mine == other {
+            text.push_str(&mine);
+        } else if region.iter().all(|c| !c.0) {
+            text.push_str(&other);
+        } else {
+            conflicts.push(Conflict {
+                pos: text.len(),
+                base: base[start..end].to_string(),
+                ours: mine.clone(),
+                theirs: other,
+            });
+            text.push_str(&mine);
+        }
+        cursor = end;
+        i = j;
+    }
+    text.push_str(&base[cursor..]);
+
+    MergeResult { text, conflicts }
+}
+
+/// Unit that edit positions and lengths are counted in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OffsetUnit {
+    /// UTF-8 bytes, as Rust strings are indexed.
+    #[default]
+    Bytes,
+    /// Unicode scalar values (Rust `char`s, Python `str` indices).
+    Chars,
+    /// UTF-16 code units, as JavaScript strings and most editors count.
+    Utf16,
+}
+
+impl OffsetUnit {
+    fn width(self, c: char) -> usize {
+        match self {
+            Self::Bytes => c.len_utf8(),
+            Self::Chars => 1,
+            Self::Utf16 => c.len_utf16(),
+        }
+    }
+}
+
+/// Convert `offset` into `text` from one unit to another, or `None` if it is
+/// past the end of `text` or falls inside a char (e.g. between the two halves
+/// of a UTF-16 surrogate pair).
+pub fn convert_offset(
+    text: &str,
+    offset: usize,
+    from: OffsetUnit,
+    to: OffsetUnit,
+) -> Option<usize> {
+    convert_offsets(text, [offset], from, to).ok()?.pop()
+}
+
+/// Convert ascending offsets into `text` in a single pass.
+fn convert_offsets(
+    text: &str,
+    offsets: impl IntoIterator<Item = usize>,
+    from: OffsetUnit,
+    to: OffsetUnit,
+) -> Result<Vec<usize>, PatchError> {
+    let mut chars = text.chars();
+    let (mut at_from, mut at_to) = (0, 0);
+    let mut converted = Vec::new();
+    for offset in offsets {
+        while at_from < offset {
+            let c = chars.next().ok_or(PatchError::InvalidPosition)?;
+            at_from += from.width(c);
+            at_to += to.width(c);
+        }
+        if at_from != offset {
+            return Err(PatchError::InvalidPosition);
+        }
+        converted.push(at_to);
+    }
+    Ok(converted)
+}
+
+impl EditList {
+    /// Re-express positions and lengths, counted in `from` units over
+    /// `source`, in `to` units. Inserted text is unaffected.
+    ///
+    /// Fails with `InvalidPosition` if an edit reaches past `source` or splits
+    /// a char, and `InvalidEdit` if the edits are out of order.
+    pub fn convert_offsets(
+        &self,
+        source: &str,
+        from: OffsetUnit,
+        to: OffsetUnit,
+    ) -> Result<EditList, PatchError> {
+        if from == to {
+            return Ok(self.clone());
+        }
+
+        let mut bounds = Vec::with_capacity(self.edits.len() * 2);
+        for edit in &self.edits {
+            let (pos, old_len, _) = edit.parts();
+            if bounds.last().is_some_and(|&end| pos < end) {
+                return Err(PatchError::InvalidEdit);
+            }
+            bounds.extend([pos, pos + old_len]);
+        }
+        let converted = convert_offsets(source, bounds, from, to)?;
+
+        let edits = self
+            .edits
+            .iter()
+            .zip(converted.chunks(2))
+            .map(|(edit, span)| match edit {
+                Edit::Insert { text, .. } => Edit::Insert {
+                    pos: span[0],
+                    text: text.clone(),
+                },
+                Edit::Delete { .. } => Edit::Delete {
+                    pos: span[0],
+                    len: span[1] - span[0],
+                },
+                Edit::Replace { new_text, .. } => Edit::Replace {
+                    pos: span[0],
+                    old_len: span[1] - span[0],
+                    new_text: new_text.clone(),
+                },
+            })
+            .collect();
+        Ok(EditList {
+            edits,
+            checksum: self.checksum.clone(),
+        })
+    }
+}
+
+/// Errors that can occur during patch application.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchError {
+    ChecksumMismatch,
+    InvalidPosition,
+    InvalidEdit,
+    /// Edits arrived out of sequence for the receiver's shadow versions.
+    VersionMismatch,
+    /// Patch text that could not be parsed, with the 1-based line number.
+    Malformed {
+        line: usize,
+    },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            Self::InvalidPosition => write!(f, "Invalid position"),
+            Self::InvalidEdit => write!(f, "Invalid edit"),
+            Self::VersionMismatch => write!(f, "Version mismatch"),
+            Self::Malformed { line } => write!(f, "Malformed patch text at line {line}"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Up to `max_len` pieces of `alphabet`, picked at random.
+    fn random_text(rng: &mut StdRng, alphabet: &[&str], max_len: usize) -> String {
+        let len = rng.gen_range(0..max_len);
+        (0..len)
+            .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_and_patch() {
+        let original = "The quick brown fox";
+        let modified = "The quick red fox jumps";
+
+        let edits = diff(original, modified);
+        let result = patch(original, &edits).unwrap();
+        assert_eq!(result, modified);
+    }
+
+    #[test]
+    fn test_empty_diff() {
+        let text = "Same text";
+        assert!(diff(text, text).is_empty());
+    }
+
+    #[test]
+    fn test_fuzzy_patch() {
+        let original = "Hello world";
+        let modified = "Hello beautiful world";
+        let edits = diff(original, modified);
+
+        let different_text = "Hello cruel world";
+        let result = patch(different_text, &edits).unwrap();
+        assert!(result.contains("beautiful"));
+    }
+
+    #[test]
+    fn test_checksum_distinguishes_anagrams() {
+        assert_ne!(checksum("listen"), checksum("silent"));
+        assert_ne!(
+            checksum_with("listen", HashAlgorithm::Sha256),
+            checksum_with("silent", HashAlgorithm::Sha256)
+        );
+        // The legacy format could not tell them apart.
+        assert_eq!(legacy_checksum("listen"), legacy_checksum("silent"));
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let text = "Hello world";
+        assert!(verify_checksum(text, &checksum(text)));
+        assert!(verify_checksum(
+            text,
+            &checksum_with(text, HashAlgorithm::Sha256)
+        ));
+        assert!(verify_checksum(text, &legacy_checksum(text)));
+        assert!(!verify_checksum("Hello World", &checksum(text)));
+        assert!(!verify_checksum(text, "md5:whatever"));
+        assert!(checksum(text).starts_with("xxh64:"));
+    }
+
+    #[test]
+    fn test_hunks_follow_drifted_text() {
+        let source = "The quick brown fox jumps over the lazy dog.";
+        let edited = "The quick brown fox leaps over the lazy dog.";
+        let options = PatchOptions::default();
+        let hunks = make_hunks(source, &diff(source, edited), &options);
+
+        // Text moved right by a prefix the edit knows nothing about.
+        let drifted = "Yesterday, the quick brown fox jumps over the lazy dog.";
+        let report = apply_hunks(drifted, &hunks, &options);
+        assert_eq!(
+            report.text,
+            "Yesterday, the quick brown fox leaps over the lazy dog."
+        );
+        assert_eq!(report.outcomes, vec![HunkOutcome::Fuzzy { offset: 11 }]);
+
+        // Raw byte offsets would have landed in the wrong word instead.
+        let misplaced = patch(drifted, &diff(source, edited)).unwrap();
+        assert_ne!(misplaced, report.text);
+    }
+
+    #[test]
+    fn test_hunks_are_placed_by_char_position() {
+        let source = format!("{} the fox jumps", "é".repeat(20));
+        let edited = format!("{} the fox leaps", "é".repeat(20));
+        let options = PatchOptions {
+            match_distance: 5,
+            ..PatchOptions::default()
+        };
+        let hunks = make_hunks(&source, &diff(&source, &edited), &options);
+
+        // Same chars before the edit, but half the bytes.
+        let target = format!("{} the fox jumps", "e".repeat(20));
+        let report = apply_hunks(&target, &hunks, &options);
+        assert_eq!(report.text, format!("{} the fox leaps", "e".repeat(20)));
+        assert_eq!(report.outcomes, vec![HunkOutcome::Exact]);
+    }
+
+    #[test]
+    fn test_hunks_report_exact_matches() {
+        let source = "first line\nsecond line\nthird line";
+        let edited = "First line\nsecond line\nthird line!";
+        let options = PatchOptions::default();
+        let hunks = make_hunks(source, &diff(source, edited), &options);
+
+        let report = apply_hunks(source, &hunks, &options);
+        assert_eq!(report.text, edited);
+        assert_eq!(report.outcomes, vec![HunkOutcome::Exact; 2]);
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn test_patch_rejects_invalid_positions() {
+        let past_end = EditList::new(vec![Edit::Delete { pos: 3, len: 10 }], "");
+        assert_eq!(patch("abcdef", &past_end), Err(PatchError::InvalidPosition));
+
+        let mid_char = EditList::new(
+            vec![Edit::Insert {
+                pos: 1,
+                text: "x".to_string(),
+            }],
+            "",
+        );
+        assert_eq!(patch("éa", &mid_char), Err(PatchError::InvalidPosition));
+
+        let overlapping = EditList::new(
+            vec![
+                Edit::Delete { pos: 0, len: 4 },
+                Edit::Delete { pos: 2, len: 2 },
+            ],
+            "",
+        );
+        assert_eq!(patch("abcdef", &overlapping), Err(PatchError::InvalidEdit));
+    }
+
+    #[test]
+    fn test_hunks_tolerate_edited_context() {
+        let source = "one two three four five six seven";
+        let edited = "one two three FOUR five six seven";
+        let options = PatchOptions::default();
+        let hunks = make_hunks(source, &diff(source, edited), &options);
+
+        let drifted = "zero one too three four five six seven";
+        let report = apply_hunks(drifted, &hunks, &options);
+        assert_eq!(report.text, "zero one too three FOUR five six seven");
+        assert_eq!(report.applied_count(), 1);
+    }
+
+    #[test]
+    fn test_hunks_reject_missing_context() {
+        let source = "Hello world, this is a test";
+        let edited = "Hello world, this is a fine test";
+        let options = PatchOptions::default();
+        let hunks = make_hunks(source, &diff(source, edited), &options);
+
+        let unrelated = "Completely different content without anything in common";
+        let report = apply_hunks(unrelated, &hunks, &options);
+        assert_eq!(report.text, unrelated);
+        assert_eq!(report.outcomes, vec![HunkOutcome::Rejected]);
+        assert_eq!(report.rejected().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_match_distance_limits_search() {
+        let strict = PatchOptions {
+            match_distance: 0,
+            ..PatchOptions::default()
+        };
+        let text: Vec<char> = "abcdefghijabcdefghij".chars().collect();
+        let pattern: Vec<char> = "fghij".chars().collect();
+        assert_eq!(match_main(&text, &pattern, 5, &strict), Some(5));
+        assert_eq!(match_main(&text, &pattern, 3, &strict), None);
+        assert_eq!(
+            match_main(&text, &pattern, 13, &PatchOptions::default()),
+            Some(15)
+        );
+    }
+
+    #[test]
+    fn test_diff_separate_hunks() {
+        let original = "alpha middle section that nobody touches omega";
+        let modified = "ALPHA middle section that nobody touches omega!";
+
+        let edits = diff(original, modified);
+        assert_eq!(edits.len(), 2);
+        assert!(edits.edits.iter().all(|e| !matches!(
+            e,
+            Edit::Replace { old_len, .. } if *old_len > 5
+        )));
+        assert_eq!(patch(original, &edits).unwrap(), modified);
+    }
+
+    #[test]
+    fn test_diff_is_minimal() {
+        // Classic example from Myers' paper: edit distance 5.
+        let runs = diff_units(
+            &['a', 'b', 'c', 'a', 'b', 'b', 'a'],
+            &['c', 'b', 'a', 'b', 'a', 'c'],
+        );
+        let changed: usize = runs
+            .iter()
+            .filter(|(op, _)| *op != Op::Equal)
+            .map(|(_, len)| len)
+            .sum();
+        assert_eq!(changed, 5);
+    }
+
+    #[test]
+    fn test_diff_multibyte_offsets() {
+        let original = "naïve café über straße";
+        let modified = "naive café uber strasse";
+
+        let edits = diff(original, modified);
+        assert!(edits.len() > 1);
+        assert_eq!(patch(original, &edits).unwrap(), modified);
+    }
+
+    #[test]
+    fn test_diff_round_trips_random_text() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let alphabet = ["a", "b", " ", "c", "é", "\n"];
+
+        for _ in 0..200 {
+            let from = random_text(&mut rng, &alphabet, 40);
+            let to = random_text(&mut rng, &alphabet, 40);
+            assert_eq!(patch(&from, &diff(&from, &to)).unwrap(), to);
+            for granularity in [DiffGranularity::Word, DiffGranularity::Line] {
+                let options = DiffOptions {
+                    granularity,
+                    ..Default::default()
+                };
+                let edits = diff_with_options(&from, &to, &options);
+                assert_eq!(patch(&from, &edits).unwrap(), to);
+            }
+
+            let raw = diff(&from, &to);
+            let mut semantic = raw.clone();
+            semantic.cleanup_semantic(&from);
+            let mut lossless = raw.clone();
+            lossless.cleanup_semantic_lossless(&from);
+            let mut efficient = raw.clone();
+            efficient.cleanup_efficiency(&from, 4);
+            for cleaned in [semantic, lossless, efficient] {
+                assert_eq!(patch(&from, &cleaned).unwrap(), to);
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_mode_edits_whole_lines() {
+        let from = "one\ntwo\nthree\nfour\n";
+        let to = "one\ntwice\nthree\nfour\nfive";
+        let options = DiffOptions {
+            granularity: DiffGranularity::Line,
+            ..Default::default()
+        };
+        let edits = diff_with_options(from, to, &options);
+
+        assert_eq!(
+            edits.edits,
+            vec![
+                Edit::Replace {
+                    pos: 4,
+                    old_len: 4,
+                    new_text: "twice\n".to_string(),
+                },
+                Edit::Insert {
+                    pos: 19,
+                    text: "five".to_string(),
+                },
+            ]
+        );
+        assert_eq!(patch(from, &edits).unwrap(), to);
+    }
+
+    #[test]
+    fn test_word_mode_edits_whole_words() {
+        let from = "the quick brown fox";
+        let to = "the quack brown  fox";
+        let options = DiffOptions {
+            granularity: DiffGranularity::Word,
+            ..Default::default()
+        };
+        let edits = diff_with_options(from, to, &options);
+
+        assert_eq!(
+            edits.edits,
+            vec![
+                Edit::Replace {
+                    pos: 4,
+                    old_len: 5,
+                    new_text: "quack".to_string(),
+                },
+                Edit::Replace {
+                    pos: 15,
+                    old_len: 1,
+                    new_text: "  ".to_string(),
+                },
+            ]
+        );
+        assert_eq!(patch(from, &edits).unwrap(), to);
+    }
+
+    #[test]
+    fn test_cleanup_semantic_merges_fragments() {
+        let (from, to) = ("mouse", "sofas");
+        let mut edits = diff(from, to);
+        assert!(edits.len() > 1);
+
+        edits.cleanup_semantic(from);
+        assert_eq!(
+            edits.edits,
+            vec![Edit::Replace {
+                pos: 0,
+                old_len: 5,
+                new_text: "sofas".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_cleanup_semantic_lossless_aligns_to_words() {
+        let source = "The cat.";
+        let mut edits = EditList::new(
+            vec![Edit::Insert {
+                pos: 5,
+                text: "ow and the c".to_string(),
+            }],
+            source,
+        );
+
+        edits.cleanup_semantic_lossless(source);
+        assert_eq!(
+            edits.edits,
+            vec![Edit::Insert {
+                pos: 4,
+                text: "cow and the ".to_string(),
+            }]
+        );
+        assert_eq!(patch(source, &edits).unwrap(), "The cow and the cat.");
+    }
+
+    #[test]
+    fn test_cleanup_efficiency_folds_short_equalities() {
+        let source = "abxyzcd";
+        let mut edits = EditList::new(
+            vec![
+                Edit::Replace {
+                    pos: 0,
+                    old_len: 2,
+                    new_text: "12".to_string(),
+                },
+                Edit::Replace {
+                    pos: 5,
+                    old_len: 2,
+                    new_text: "34".to_string(),
+                },
+            ],
+            source,
+        );
+        let mut expensive = edits.clone();
+
+        edits.cleanup_efficiency(source, 4);
+        assert_eq!(
+            edits.edits,
+            vec![Edit::Replace {
+                pos: 0,
+                old_len: 7,
+                new_text: "12xyz34".to_string(),
+            }]
+        );
+
+        // An equality as long as the edit cost is worth keeping.
+        expensive.cleanup_efficiency(source, 3);
+        assert_eq!(expensive.len(), 2);
+    }
+
+    #[test]
+    fn test_expired_deadline_still_yields_valid_edits() {
+        let (from, to) = ("abc XdefY ghi", "abc ZdefW ghi");
+        let options = DiffOptions {
+            deadline: Some(Duration::ZERO),
+            ..Default::default()
+        };
+
+        let report = diff_with_report(from, to, &options);
+        assert!(report.deadline_hit);
+        assert_eq!(report.edits.len(), 1);
+        assert_eq!(patch(from, &report.edits).unwrap(), to);
+
+        let report = diff_with_report(from, to, &DiffOptions::default());
+        assert!(!report.deadline_hit);
+        assert_eq!(report.edits.len(), 2);
+    }
+
+    #[test]
+    fn test_unified_diff_round_trip() {
+        let source = "one\ntwo\nthree\n";
+        let target = "one\n2\nthree\nfour";
+        let edits = diff(source, target);
+
+        let text = edits.to_unified_diff(source, "doc.txt").unwrap();
+        assert_eq!(
+            text,
+            "--- a/doc.txt\n+++ b/doc.txt\n@@ -1,3 +1,4 @@\n one\n-two\n+2\n three\n+four\n\\ No newline at end of file\n"
+        );
+
+        let parsed = EditList::from_unified_diff(source, &text).unwrap();
+        assert_eq!(patch(source, &parsed).unwrap(), target);
+        assert_eq!(
+            EditList::from_unified_diff("one\nTWO\nthree\n", &text),
+            Err(PatchError::InvalidEdit)
+        );
+    }
+
+    #[test]
+    fn test_patch_text_matches_diff_match_patch() {
+        // From diff-match-patch's own tests; note the rolling coordinates of
+        // the second patch.
+        let source = "That quick brown fox jumped over a lazy dog.";
+        let text = "@@ -1,8 +1,7 @@\n Th\n-at\n+e\n  qui\n@@ -21,17 +21,18 @@\n jump\n-ed\n+s\n  over \n-a\n+the\n  laz\n";
+        let edits = EditList::new(
+            vec![
+                Edit::Replace {
+                    pos: 2,
+                    old_len: 2,
+                    new_text: "e".to_string(),
+                },
+                Edit::Replace {
+                    pos: 25,
+                    old_len: 2,
+                    new_text: "s".to_string(),
+                },
+                Edit::Replace {
+                    pos: 33,
+                    old_len: 1,
+                    new_text: "the".to_string(),
+                },
+            ],
+            source,
+        );
+
+        assert_eq!(edits.to_patch_text(source).unwrap(), text);
+        assert_eq!(EditList::from_patch_text(source, text).unwrap(), edits);
+        assert_eq!(
+            patch(source, &edits).unwrap(),
+            "The quick brown fox jumps over the lazy dog."
+        );
+    }
+
+    #[test]
+    fn test_text_formats_round_trip_random_edits() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let alphabet = ["a", "b", "\n", " ", "é", "%", "+"];
+
+        for _ in 0..200 {
+            let from = random_text(&mut rng, &alphabet, 60);
+            let to = random_text(&mut rng, &alphabet, 60);
+            let edits = diff(&from, &to);
+
+            let unified = edits.to_unified_diff(&from, "f").unwrap();
+            let parsed = EditList::from_unified_diff(&from, &unified).unwrap();
+            assert_eq!(patch(&from, &parsed).unwrap(), to);
+
+            let patch_text = edits.to_patch_text(&from).unwrap();
+            assert_eq!(
+                EditList::from_patch_text(&from, &patch_text).unwrap(),
+                edits
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_patch_text() {
+        assert_eq!(
+            EditList::from_patch_text("abc", "@@ -1,2 +1,2 bogus\n"),
+            Err(PatchError::Malformed { line: 1 })
+        );
+        assert_eq!(
+            EditList::from_unified_diff("abc\n", "--- a/f\n+++ b/f\n@@ -1 +1 @@\n*abc\n"),
+            Err(PatchError::Malformed { line: 4 })
+        );
+    }
+
+    #[test]
+    fn test_invert_undoes_edits() {
+        let source = "The quick brown fox";
+        let target = "A quick red fox!";
+        let edits = diff(source, target);
+
+        let undo = edits.invert(source).unwrap();
+        assert!(verify_checksum(target, &undo.checksum));
+        assert_eq!(patch(target, &undo).unwrap(), source);
+        assert_eq!(
+            patch(source, &undo.invert(target).unwrap()).unwrap(),
+            target
+        );
+    }
+
+    #[test]
+    fn test_compose_squashes_edit_lists() {
+        let (v1, v2, v3) = (
+            "hello world",
+            "hello brave world",
+            "goodbye brave new world",
+        );
+        let first = diff(v1, v2);
+        let second = diff(v2, v3);
+
+        let squashed = EditList::compose(&first, &second).unwrap();
+        assert_eq!(squashed.checksum, first.checksum);
+        assert_eq!(patch(v1, &squashed).unwrap(), v3);
+
+        // Out of order, on either side.
+        let unordered = EditList::new(
+            vec![
+                Edit::Delete { pos: 10, len: 5 },
+                Edit::Delete { pos: 0, len: 1 },
+            ],
+            "hello brave world",
+        );
+        assert_eq!(
+            EditList::compose(&unordered, &second),
+            Err(PatchError::InvalidEdit)
+        );
+        assert_eq!(
+            EditList::compose(&first, &unordered),
+            Err(PatchError::InvalidEdit)
+        );
+    }
+
+    #[test]
+    fn test_transform_converges_concurrent_edits() {
+        let source = "one two three";
+        let converge = |a: Vec<Edit>, b: Vec<Edit>| {
+            let (a, b) = (EditList::new(a, source), EditList::new(b, source));
+            let (a2, b2) = transform(&a, &b).unwrap();
+            let via_b = patch(&patch(source, &b).unwrap(), &a2).unwrap();
+            let via_a = patch(&patch(source, &a).unwrap(), &b2).unwrap();
+            assert_eq!(via_b, via_a);
+            via_b
+        };
+
+        let two = || Edit::Replace {
+            pos: 4,
+            old_len: 3,
+            new_text: "2".to_string(),
+        };
+        let merged = converge(
+            vec![two()],
+            vec![
+                Edit::Insert {
+                    pos: 0,
+                    text: "zero ".to_string(),
+                },
+                Edit::Replace {
+                    pos: 8,
+                    old_len: 5,
+                    new_text: "3".to_string(),
+                },
+            ],
+        );
+        assert_eq!(merged, "zero one 2 3");
+
+        // Text inserted inside a range the other side deleted survives.
+        let merged = converge(vec![Edit::Delete { pos: 3, len: 10 }], vec![two()]);
+        assert_eq!(merged, "one2");
+
+        // Inserts at the same position keep `a`'s text first.
+        let a = EditList::new(
+            vec![Edit::Insert {
+                pos: 3,
+                text: "A".to_string(),
+            }],
+            source,
+        );
+        let b = EditList::new(
+            vec![Edit::Insert {
+                pos: 3,
+                text: "B".to_string(),
+            }],
+            source,
+        );
+        let (a2, b2) = transform(&a, &b).unwrap();
+        assert_eq!(
+            patch(&patch(source, &b).unwrap(), &a2).unwrap(),
+            "oneAB two three"
+        );
+        assert_eq!(
+            patch(&patch(source, &a).unwrap(), &b2).unwrap(),
+            "oneAB two three"
+        );
+    }
+
+    #[test]
+    fn test_edit_algebra_random() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let alphabet = ["a", "b", "c", " ", "é", "\n"];
+
+        for _ in 0..300 {
+            let (s, t, u) = (
+                random_text(&mut rng, &alphabet, 30),
+                random_text(&mut rng, &alphabet, 30),
+                random_text(&mut rng, &alphabet, 30),
+            );
+            let (a, b) = (diff(&s, &t), diff(&t, &u));
+            assert_eq!(patch(&t, &a.invert(&s).unwrap()).unwrap(), s);
+            assert_eq!(patch(&s, &EditList::compose(&a, &b).unwrap()).unwrap(), u);
+
+            let b = diff(&s, &u);
+            let (a2, b2) = transform(&a, &b).unwrap();
+            assert_eq!(
+                patch(&patch(&s, &b).unwrap(), &a2).unwrap(),
+                patch(&t, &b2).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge3_combines_independent_changes() {
+        let base = "title\nintro\nbody\nend\n";
+        let ours = "Title\nintro\nbody\nend\n";
+        let theirs = "title\nintro\nbody, expanded\nend\nappendix\n";
+
+        let merged = merge3(base, ours, theirs);
+        assert!(merged.is_clean());
+        assert_eq!(merged.text, "Title\nintro\nbody, expanded\nend\nappendix\n");
+
+        // The same change on both sides is taken once.
+        let merged = merge3(base, ours, ours);
+        assert!(merged.is_clean());
+        assert_eq!(merged.text, ours);
+    }
+
+    #[test]
+    fn test_merge3_reports_conflicts() {
+        let base = "a\nb\nc\n";
+        let merged = merge3(base, "a\nours\nc\n", "a\ntheirs\nc\nd\n");
+
+        assert_eq!(merged.text, "a\nours\nc\nd\n");
+        assert_eq!(
+            merged.conflicts,
+            vec![Conflict {
+                pos: 2,
+                base: "b\n".to_string(),
+                ours: "ours\n".to_string(),
+                theirs: "theirs\n".to_string(),
+            }]
+        );
+        assert_eq!(
+            merged.conflict_marker_text(),
+            "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\nd\n"
+        );
+    }
+
+    #[test]
+    fn test_merge3_word_mode_merges_within_a_line() {
+        let base = "the quick brown fox";
+        let options = DiffOptions {
+            granularity: DiffGranularity::Word,
+            ..Default::default()
+        };
+
+        let merged =
+            merge3_with_options(base, "the slow brown fox", "the quick brown cat", &options);
+        assert!(merged.is_clean());
+        assert_eq!(merged.text, "the slow brown cat");
+        assert!(!merge3(base, "the slow brown fox", "the quick brown cat").is_clean());
+    }
+
+    #[test]
+    fn test_convert_offset_between_units() {
+        let text = "a€😀b";
+        // Boundaries after each char: bytes 1, 4, 8, 9; UTF-16 units 1, 2, 4, 5.
+        assert_eq!(
+            convert_offset(text, 8, OffsetUnit::Bytes, OffsetUnit::Utf16),
+            Some(4)
+        );
+        assert_eq!(
+            convert_offset(text, 4, OffsetUnit::Utf16, OffsetUnit::Chars),
+            Some(3)
+        );
+        assert_eq!(
+            convert_offset(text, 3, OffsetUnit::Chars, OffsetUnit::Bytes),
+            Some(8)
+        );
+        assert_eq!(
+            convert_offset(text, 9, OffsetUnit::Bytes, OffsetUnit::Bytes),
+            Some(9)
+        );
+
+        // Inside the surrogate pair, inside a UTF-8 sequence, past the end.
+        assert_eq!(
+            convert_offset(text, 3, OffsetUnit::Utf16, OffsetUnit::Bytes),
+            None
+        );
+        assert_eq!(
+            convert_offset(text, 2, OffsetUnit::Bytes, OffsetUnit::Chars),
+            None
+        );
+        assert_eq!(
+            convert_offset(text, 6, OffsetUnit::Utf16, OffsetUnit::Bytes),
+            None
+        );
+    }
+
+    #[test]
+    fn test_edit_list_offsets_round_trip_through_units() {
+        let source = "héllo 😀 wörld";
+        let edits = diff(source, "hello 😀😀 world!");
+
+        for unit in [OffsetUnit::Chars, OffsetUnit::Utf16] {
+            let converted = edits
+                .convert_offsets(source, OffsetUnit::Bytes, unit)
+                .unwrap();
+            assert_ne!(converted, edits);
+            let back = converted
+                .convert_offsets(source, unit, OffsetUnit::Bytes)
+                .unwrap();
+            assert_eq!(back, edits);
+        }
+
+        let utf16 = EditList::new(vec![Edit::Delete { pos: 7, len: 1 }], source);
+        assert_eq!(
+            utf16.convert_offsets(source, OffsetUnit::Utf16, OffsetUnit::Bytes),
+            Err(PatchError::InvalidPosition)
+        );
+    }
+
+    /// Assert that every edit starts and ends on a grapheme cluster boundary
+    /// of both `source` and the patched target.
+    fn assert_grapheme_aligned(source: &str, edits: &EditList) {
+        let target = patch(source, edits).unwrap();
+        let (source_bounds, target_bounds) = (grapheme_offsets(source), grapheme_offsets(&target));
+        let mut shift = 0isize;
+        for edit in &edits.edits {
+            let (pos, old_len, new_text) = edit.parts();
+            let at = (pos as isize + shift) as usize;
+            assert!(
+                source_bounds.contains(&pos),
+                "{edit:?} starts inside a cluster"
+            );
+            assert!(
+                source_bounds.contains(&(pos + old_len)),
+                "{edit:?} ends inside a cluster"
+            );
+            assert!(target_bounds.contains(&at));
+            assert!(target_bounds.contains(&(at + new_text.len())));
+            shift += new_text.len() as isize - old_len as isize;
+        }
+    }
+
+    #[test]
+    fn test_grapheme_safe_diff_keeps_clusters_whole() {
+        let options = DiffOptions {
+            grapheme_safe: true,
+            ..Default::default()
+        };
+        let cases = [
+            // Flags: a pair of regional indicators sharing the first one.
+            ("Go 🇺🇸!", "Go 🇺🇦!", "🇺🇸", "🇺🇦"),
+            // Skin-tone modifier on the same base emoji.
+            ("ok 👍🏻", "ok 👍🏽", "👍🏻", "👍🏽"),
+            // ZWJ family sequence differing only in its last member.
+            (
+                "👨\u{200d}👩\u{200d}👧",
+                "👨\u{200d}👩\u{200d}👦",
+                "👨\u{200d}👩\u{200d}👧",
+                "👨\u{200d}👩\u{200d}👦",
+            ),
+            // Devanagari consonant with a different dependent vowel sign.
+            ("नमस्ते कि", "नमस्ते की", "कि", "की"),
+        ];
+
+        for (from, to, old, new) in cases {
+            // A plain char diff splits the cluster...
+            let naive = diff(from, to);
+            assert_eq!(patch(from, &naive).unwrap(), to);
+            let (_, old_len, _) = naive.edits[0].parts();
+            assert!(old_len < old.len(), "{from:?}: {naive:?}");
+
+            // ...while the grapheme-safe one replaces it whole.
+            let edits = diff_with_options(from, to, &options);
+            assert_eq!(patch(from, &edits).unwrap(), to);
+            assert_eq!(
+                edits.edits,
+                vec![Edit::Replace {
+                    pos: from.len() - old.len() - if from.ends_with('!') { 1 } else { 0 },
+                    old_len: old.len(),
+                    new_text: new.to_string(),
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_align_to_graphemes_after_cleanup() {
+        // Adding a combining accent or a skin tone is a pure insert at char
+        // level; aligned, it must take the base character with it.
+        let from = "cafe 👍 and e";
+        let to = "cafe\u{301} 👍🏾 and e\u{300}";
+        for cleanup in [
+            DiffCleanup::None,
+            DiffCleanup::Semantic,
+            DiffCleanup::SemanticLossless,
+            DiffCleanup::Efficiency,
+        ] {
+            let options = DiffOptions {
+                cleanup,
+                ..Default::default()
+            };
+            let mut edits = diff_with_options(from, to, &options);
+            edits.align_to_graphemes(from);
+            assert_eq!(patch(from, &edits).unwrap(), to, "{cleanup:?}");
+            assert_grapheme_aligned(from, &edits);
+
+            let options = DiffOptions {
+                grapheme_safe: true,
+                ..options
+            };
+            let edits = diff_with_options(from, to, &options);
+            assert_eq!(patch(from, &edits).unwrap(), to, "{cleanup:?}");
+            assert_grapheme_aligned(from, &edits);
+        }
+
+        let mut edits = diff(from, to);
+        edits.align_to_graphemes(from);
+        assert_eq!(
+            edits.edits[0],
+            Edit::Replace {
+                pos: 3,
+                old_len: 1,
+                new_text: "e\u{301}".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_random_grapheme_safe_diffs() {
+        let alphabet = [
+            "a", " ", "e\u{301}", "\u{301}", "🇺", "🇸", "👍", "🏽", "\u{200d}", "क", "ि", "्",
+        ];
+        let mut rng = StdRng::seed_from_u64(14);
+        let options = DiffOptions {
+            grapheme_safe: true,
+            cleanup: DiffCleanup::Semantic,
+            ..Default::default()
+        };
+        for _ in 0..200 {
+            let (from, to) = (
+                random_text(&mut rng, &alphabet, 16),
+                random_text(&mut rng, &alphabet, 16),
+            );
+            let edits = diff_with_options(&from, &to, &options);
+            assert_eq!(patch(&from, &edits).unwrap(), to);
+            assert_grapheme_aligned(&from, &edits);
+        }
+    }
+
+    #[test]
+    fn test_delta_round_trip_keeps_every_variant() {
+        let source = "The quick brown fox";
+        let edits = EditList::new(
+            vec![
+                Edit::Insert {
+                    pos: 0,
+                    text: "¡".to_string(),
+                },
+                Edit::Replace {
+                    pos: 4,
+                    old_len: 5,
+                    new_text: "slow\tred %".to_string(),
+                },
+                Edit::Delete { pos: 10, len: 6 },
+                Edit::Insert {
+                    pos: 16,
+                    text: "cat".to_string(),
+                },
+            ],
+            source,
+        );
+
+        let delta = edits.to_delta().unwrap();
+        assert_eq!(delta.checksum, edits.checksum);
+        assert_eq!(
+            delta.delta,
+            "+%C2%A1\t=4\t-5\t+slow%09red %25\t=1\t-6\t=0\t+cat"
+        );
+        assert_eq!(EditList::from_delta(&delta).unwrap(), edits);
+
+        // diff-match-patch also emits the trailing equality.
+        let with_tail = DeltaEdits {
+            delta: format!("{}\t=3", delta.delta),
+            ..delta.clone()
+        };
+        assert_eq!(EditList::from_delta(&with_tail).unwrap(), edits);
+
+        let json = serde_json::to_string(&edits).unwrap();
+        assert!(serde_json::to_string(&delta).unwrap().len() < json.len());
+    }
+
+    #[test]
+    fn test_delta_round_trips_random_diffs() {
+        let mut rng = StdRng::seed_from_u64(15);
+        let alphabet = ["a", "b", "\t", "%", "+", "=", "-", "é", "😀", "\n"];
+        for _ in 0..200 {
+            let (from, to) = (
+                random_text(&mut rng, &alphabet, 30),
+                random_text(&mut rng, &alphabet, 30),
+            );
+            let edits = diff(&from, &to);
+            let decoded = EditList::from_delta(&edits.to_delta().unwrap()).unwrap();
+            assert_eq!(decoded, edits);
+        }
+    }
+
+    #[test]
+    fn test_malformed_delta_is_rejected() {
+        for delta in ["=x", "-3\t-1", "+a\t+b", "+a\t-1", "*3", "é", "+%ZZ"] {
+            let delta = DeltaEdits {
+                delta: delta.to_string(),
+                checksum: String::new(),
+            };
+            assert_eq!(
+                EditList::from_delta(&delta),
+                Err(PatchError::InvalidEdit),
+                "{delta:?}"
+            );
+        }
+
+        let unsorted = EditList {
+            edits: vec![
+                Edit::Delete { pos: 5, len: 2 },
+                Edit::Delete { pos: 1, len: 1 },
+            ],
+            checksum: String::new(),
+        };
+        assert_eq!(unsorted.to_delta(), Err(PatchError::InvalidPosition));
     }
 }
@@ -1,8 +1,14 @@
 pub mod diff;
 pub mod document;
+#[cfg(feature = "sqlite")]
 pub mod persistence;
+pub mod store;
 pub mod sync;
 
+#[cfg(feature = "network")]
+pub mod actor;
+#[cfg(feature = "network")]
+pub mod codec;
 #[cfg(feature = "network")]
 pub mod network;
 #[cfg(feature = "network")]
@@ -10,9 +16,15 @@ pub mod session;
 
 pub use diff::*;
 pub use document::*;
+#[cfg(feature = "sqlite")]
 pub use persistence::*;
+pub use store::*;
 pub use sync::*;
 
+#[cfg(feature = "network")]
+pub use actor::*;
+#[cfg(feature = "network")]
+pub use codec::*;
 #[cfg(feature = "network")]
 pub use network::*;
 #[cfg(feature = "network")]
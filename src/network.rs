@@ -1,10 +1,16 @@
-use crate::{Document, DocumentDB, EditList, SyncEngine};
+use crate::{
+    convert_offset, default_diff_options, CodecKind, Compression, CompressionSettings, Compressor,
+    DeltaEdits, DiffGranularity, DiffOptions, Document, DocumentHandle, DocumentStore, EditList,
+    Framing, MemoryStore, OffsetUnit, PatchError, PatchOptions, PatchReport, SyncEngine,
+    SyncPacket,
+};
 use colored::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 
 /// Cursor position and display color for a connected client.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,32 +20,123 @@ pub struct CursorInfo {
     pub color: String,
 }
 
+/// Protocol version spoken by this build. Version 1 is the original handshake,
+/// in which `Connect` carried only a `client_id`; version 2 adds the version,
+/// capability and feature fields of `Connect` and `ConnectOk`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version the server still serves.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features the server supports. A client lists the ones it
+/// understands in `Connect`, and `ConnectOk` returns those both sides support.
+///
+/// - `push`: the server sends a `ServerSync` unprompted when other clients'
+///   edits change the document, instead of waiting for the next `ClientSync`.
+/// - `resync`: the client handles `Resync`. Clients without it get an `Error`
+///   when their shadow diverges.
+pub const SUPPORTED_FEATURES: &[&str] = &["push", "resync"];
+
+/// Peers that predate version negotiation do not send a version.
+fn legacy_protocol_version() -> u32 {
+    1
+}
+
 /// Wire protocol between client and server, serialized as externally-tagged JSON
 /// (serde default).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SyncMessage {
+    /// `protocol_version` is the newest version the client speaks and
+    /// `features` the optional features it understands.
+    /// `offset_unit` selects how the client counts edit positions and cursor
+    /// offsets; the server translates to and from it for this client.
+    /// `compact_edits` asks for bare edit lists in [`DeltaEdits`] form,
+    /// `codec` for the encoding of every message after `ConnectOk`, and
+    /// `compression` for enveloped, possibly compressed payloads.
     Connect {
         client_id: String,
+        #[serde(default = "legacy_protocol_version")]
+        protocol_version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+        #[serde(default)]
+        offset_unit: OffsetUnit,
+        #[serde(default)]
+        compact_edits: bool,
+        #[serde(default)]
+        codec: CodecKind,
+        #[serde(default)]
+        compression: Compression,
     },
 
+    /// Carries either a bare `edits` list or, for clients using guaranteed
+    /// delivery, a versioned `packet` (in which case `edits` is empty). Once
+    /// compact edits are negotiated, the bare list may travel as `delta`
+    /// instead, again leaving `edits` empty.
     ClientSync {
         client_id: String,
+        #[serde(default)]
         edits: EditList,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta: Option<DeltaEdits>,
         client_version: u64,
         #[serde(default)]
         cursor_position: Option<usize>,
+        #[serde(default)]
+        packet: Option<SyncPacket>,
     },
 
+    /// Mirrors the form of the `ClientSync` it answers.
     ServerSync {
+        #[serde(default)]
         edits: EditList,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta: Option<DeltaEdits>,
         server_version: u64,
         #[serde(default)]
         cursors: Vec<CursorInfo>,
+        /// Indices of the client's edits from the preceding `ClientSync` that
+        /// could not be placed in the server document.
+        #[serde(default)]
+        rejected_edits: Vec<usize>,
+        #[serde(default)]
+        packet: Option<SyncPacket>,
+        /// Sent unprompted after other clients' edits rather than in reply to
+        /// a `ClientSync`. Only clients syncing with packets are pushed to; one
+        /// with a `ClientSync` in flight drops the push, since that request
+        /// rolls the server back and its reply carries the same changes.
+        #[serde(default)]
+        pushed: bool,
     },
 
+    /// Confirms the negotiated protocol version and features, the
+    /// `offset_unit` the server will use with this client, whether it will
+    /// reply to bare edit lists in compact form, the codec both sides switch
+    /// to after this message, and the compression whose envelope already
+    /// wraps this message.
     ConnectOk {
         server_version: u64,
         document: Document,
+        #[serde(default = "legacy_protocol_version")]
+        protocol_version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+        #[serde(default)]
+        offset_unit: OffsetUnit,
+        #[serde(default)]
+        compact_edits: bool,
+        #[serde(default)]
+        codec: CodecKind,
+        #[serde(default)]
+        compression: Compression,
+    },
+
+    /// The server's shadow for this client had diverged from the client's and
+    /// has been reset to `document`. The client resets its shadow to match and
+    /// re-applies any local edits it has not synced yet.
+    Resync {
+        server_version: u64,
+        document: Document,
     },
 
     Error {
@@ -54,6 +151,56 @@ pub enum SyncMessage {
     Pong,
 }
 
+/// What a client asked for in `Connect`, or what the server agreed to in
+/// `ConnectOk`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    pub features: Vec<String>,
+    pub offset_unit: OffsetUnit,
+    pub compact_edits: bool,
+    pub codec: CodecKind,
+    pub compression: Compression,
+}
+
+impl Capabilities {
+    /// Settle a client's request against what this server supports.
+    ///
+    /// Clients older than [`MIN_PROTOCOL_VERSION`] are refused. Newer clients
+    /// are downgraded to [`PROTOCOL_VERSION`], and version 1 clients to the
+    /// original protocol with every capability at its default. Features the
+    /// server does not know are dropped.
+    pub fn negotiate(requested: Capabilities) -> Result<Capabilities, String> {
+        if requested.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(format!(
+                "Unsupported protocol version {}: this server speaks versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}",
+                requested.protocol_version
+            ));
+        }
+        let protocol_version = requested.protocol_version.min(PROTOCOL_VERSION);
+        if protocol_version == 1 {
+            return Ok(Capabilities {
+                protocol_version,
+                ..Capabilities::default()
+            });
+        }
+
+        let mut features = requested.features;
+        features.retain(|f| SUPPORTED_FEATURES.contains(&f.as_str()));
+        features.sort();
+        features.dedup();
+        Ok(Capabilities {
+            protocol_version,
+            features,
+            ..requested
+        })
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
 /// Server-side state for a single connected client.
 #[derive(Debug)]
 pub struct ClientSession {
@@ -62,6 +209,27 @@ pub struct ClientSession {
     pub last_seen: Instant,
     pub cursor_position: Option<usize>,
     pub color: String,
+    /// What was agreed with this client in `ConnectOk`.
+    pub capabilities: Capabilities,
+    /// Wakes the client's connection to push it other clients' changes; set
+    /// by [`SyncServer::subscribe`].
+    pub push_signal: Option<mpsc::Sender<()>>,
+    /// How the client sent its last `ClientSync`. Nothing is pushed before
+    /// the first one, since the form of the push depends on it.
+    pub sync_mode: Option<SyncMode>,
+    /// The server's shadow of this client was thrown away by
+    /// [`SyncServer::reload`]; the client's next `ClientSync` is refused as
+    /// diverged, so that it resyncs.
+    pub needs_resync: bool,
+}
+
+/// Form in which a client exchanges edits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Bare edit lists, answered in kind.
+    Bare,
+    /// Versioned packets with guaranteed delivery.
+    Packets,
 }
 
 impl ClientSession {
@@ -74,6 +242,10 @@ impl ClientSession {
             last_seen: Instant::now(),
             cursor_position: None,
             color,
+            capabilities: Capabilities::default(),
+            push_signal: None,
+            sync_mode: None,
+            needs_resync: false,
         }
     }
 }
@@ -82,138 +254,545 @@ impl ClientSession {
 ///
 /// Each client gets its own `SyncEngine` shadow so the server can compute
 /// per-client diffs containing only edits from *other* clients.
+///
+/// The document is kept in memory. Each change is recorded as a revision
+/// before the client is answered, and the document itself is written back
+/// once it has been dirty for `flush_delay`, every `flush_every` versions,
+/// and on drop.
 pub struct SyncServer {
-    pub db: DocumentDB,
+    pub store: Box<dyn DocumentStore>,
     pub document_name: String,
+    document: Document,
+    /// Version of `document` last written to `store`.
+    persisted_version: u64,
+    /// When `document` first changed after it was last written.
+    dirty_since: Option<Instant>,
+    pub flush_delay: Duration,
+    pub flush_every: u64,
     pub clients: HashMap<String, ClientSession>,
     pub version: u64,
+    /// Matching tolerances for fuzzy-patching client edits into the document.
+    pub patch_options: PatchOptions,
+    /// Client edits that could not be placed in the document so far.
+    pub rejected_edits: u64,
+    /// Refuse client edits whose checksum does not match the server's shadow
+    /// of that client, so the client is resynced rather than a diverged
+    /// shadow patched. On by default.
+    pub strict_checksums: bool,
+    pub checksum_mismatches: u64,
+    /// Full resyncs performed after a client's shadow diverged.
+    pub resyncs: u64,
+    /// Documents at least this many bytes long are diffed line by line
+    /// rather than char by char when computing edits for clients.
+    pub line_mode_threshold: usize,
+    /// Compressions offered to clients, and the ratio they achieved.
+    pub compression: CompressionSettings,
+    /// Leave routine client activity (connects, syncs, saves) out of the log.
+    pub quiet: bool,
 }
 
+/// Default for [`SyncServer::line_mode_threshold`].
+pub const DEFAULT_LINE_MODE_THRESHOLD: usize = 64 * 1024;
+/// Default for [`SyncServer::flush_delay`].
+pub const DEFAULT_FLUSH_DELAY: Duration = Duration::from_secs(2);
+/// Default for [`SyncServer::flush_every`].
+pub const DEFAULT_FLUSH_EVERY: u64 = 100;
+
 impl SyncServer {
-    pub fn new_with_db(db: DocumentDB, document_name: String) -> Result<Self, String> {
+    /// Serve `document_name` from `store`, replaying any revisions recorded
+    /// but not yet written back by a previous server.
+    pub fn new_with_store(
+        store: Box<dyn DocumentStore>,
+        document_name: String,
+    ) -> Result<Self, String> {
+        let document = store
+            .recover_document(&document_name)
+            .map_err(|e| format!("Storage error: {e}"))?
+            .ok_or_else(|| format!("Document '{document_name}' not found"))?;
         Ok(Self {
-            db,
+            store,
             document_name,
+            persisted_version: document.version,
+            document,
+            dirty_since: None,
+            flush_delay: DEFAULT_FLUSH_DELAY,
+            flush_every: DEFAULT_FLUSH_EVERY,
             clients: HashMap::new(),
             version: 0,
+            patch_options: PatchOptions::default(),
+            rejected_edits: 0,
+            strict_checksums: true,
+            checksum_mismatches: 0,
+            resyncs: 0,
+            line_mode_threshold: DEFAULT_LINE_MODE_THRESHOLD,
+            compression: CompressionSettings::default(),
+            quiet: false,
         })
     }
 
     pub fn new_in_memory(document_name: String) -> Result<Self, String> {
-        let db = DocumentDB::new_in_memory()
-            .map_err(|e| format!("Failed to create in-memory database: {e}"))?;
-        Self::new_with_db(db, document_name)
+        Self::new_with_store(Box::new(MemoryStore::new()), document_name)
     }
 
     pub fn get_current_document(&self) -> Result<Document, String> {
-        self.db
-            .load_document(&self.document_name)
-            .map_err(|e| format!("Database error: {e}"))?
-            .ok_or_else(|| format!("Document '{}' not found", self.document_name))
+        Ok(self.document.clone())
+    }
+
+    /// Record the change from the current document to `content` as a new
+    /// revision by `author`, diffed with `options`, then make `content` the
+    /// current document. Flushes if `flush_every` versions have built up.
+    fn commit_document(
+        &mut self,
+        content: String,
+        author: Option<&str>,
+        options: &DiffOptions,
+    ) -> Result<(), String> {
+        let edits = crate::diff_with_options(&self.document.content, &content, options);
+        self.store
+            .append_revision(
+                &self.document_name,
+                self.document.version + 1,
+                &edits,
+                &content,
+                author,
+            )
+            .map_err(|e| format!("Failed to record revision: {e}"))?;
+        self.document.update(content);
+        self.dirty_since.get_or_insert_with(Instant::now);
+
+        if self.document.version - self.persisted_version >= self.flush_every {
+            if let Err(e) = self.flush() {
+                eprintln!("{e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the document back to the store if it changed since it was
+    /// last written. On failure the revisions still hold the changes, and the
+    /// next flush is due after another `flush_delay`.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if self.document.version == self.persisted_version {
+            return Ok(());
+        }
+        if let Err(e) = self
+            .store
+            .flush_document(&self.document_name, &self.document)
+        {
+            self.dirty_since = Some(Instant::now());
+            return Err(format!(
+                "Failed to write back document '{}': {e}",
+                self.document_name
+            ));
+        }
+        self.persisted_version = self.document.version;
+        self.dirty_since = None;
+        Ok(())
+    }
+
+    /// Make version `version` of the document current again, as a new
+    /// revision. Every client receives it as an ordinary change.
+    pub fn restore_version(&mut self, version: u64) -> Result<Document, String> {
+        let old = self
+            .store
+            .load_document_at(&self.document_name, version)
+            .map_err(|e| format!("Storage error: {e}"))?
+            .ok_or_else(|| {
+                format!(
+                    "Version {version} of '{}' is not in its history",
+                    self.document_name
+                )
+            })?;
+        let options = DiffOptions {
+            granularity: diff_granularity(&self.document.content, self.line_mode_threshold),
+            ..default_diff_options()
+        };
+        self.commit_document(old.content, None, &options)?;
+        self.version += 1;
+        self.signal_push(None);
+        Ok(self.document.clone())
+    }
+
+    /// Discard the in-memory state after a request failed part way through
+    /// it: reload the document from the store, replaying recorded revisions,
+    /// and make every client resync before its edits are accepted again.
+    pub fn reload(&mut self) -> Result<(), String> {
+        let document = self
+            .store
+            .recover_document(&self.document_name)
+            .map_err(|e| format!("Storage error: {e}"))?
+            .ok_or_else(|| format!("Document '{}' not found", self.document_name))?;
+        self.persisted_version = document.version;
+        self.document = document;
+        self.dirty_since = None;
+        for session in self.clients.values_mut() {
+            session.needs_resync = true;
+        }
+        if !self.quiet {
+            println!(
+                "Reloaded document '{}' (v{})",
+                self.document_name.yellow(),
+                self.document.version
+            );
+        }
+        Ok(())
+    }
+
+    /// When the debounced write-back of the document is due, if it has
+    /// unwritten changes.
+    pub fn flush_due(&self) -> Option<Instant> {
+        self.dirty_since.map(|since| since + self.flush_delay)
     }
 
     pub fn connect_client(&mut self, client_id: String) -> Result<Document, String> {
+        self.connect_client_with_unit(client_id, OffsetUnit::Bytes)
+    }
+
+    /// Connect a client that counts edit positions and cursor offsets in
+    /// `offset_unit`.
+    pub fn connect_client_with_unit(
+        &mut self,
+        client_id: String,
+        offset_unit: OffsetUnit,
+    ) -> Result<Document, String> {
         if self.clients.contains_key(&client_id) {
             return Err(format!("Client {client_id} already connected"));
         }
 
         let current_doc = self.get_current_document()?;
         let color = random_cursor_color();
-        let session = ClientSession::new(client_id.clone(), current_doc.content.clone(), color);
+        let mut session = ClientSession::new(client_id.clone(), current_doc.content.clone(), color);
+        session.sync_engine.strict_checksums = self.strict_checksums;
+        session.sync_engine.offset_unit = offset_unit;
 
         self.clients.insert(client_id.clone(), session);
         self.version += 1;
 
-        println!("Client {} connected (v{})", client_id.green(), self.version);
+        if !self.quiet {
+            println!("Client {} connected (v{})", client_id.green(), self.version);
+        }
         Ok(current_doc)
     }
 
     /// Process a client sync: apply the client's edits to the DB, then diff
     /// the client's shadow against the (possibly updated) DB document to
     /// produce edits containing only changes from *other* clients.
+    ///
+    /// Also returns the indices of client edits that could not be placed in
+    /// the document, so the client can be told about them. Edits that do not
+    /// fit the client's shadow (including, in strict mode, a checksum that does
+    /// not match it) are refused with `ClientSyncError::Diverged` before
+    /// anything is applied; see [`resync_client`](Self::resync_client).
     pub fn sync_with_client(
         &mut self,
         client_id: &str,
         client_edits: EditList,
-    ) -> Result<EditList, String> {
-        if !self.clients.contains_key(client_id) {
-            return Err(format!("Client {client_id} not found"));
-        }
+    ) -> Result<(EditList, Vec<usize>), ClientSyncError> {
+        let rejected =
+            self.apply_client_changes(client_id, |engine| engine.apply_edits(client_edits))?;
 
-        let mut current_doc = self.get_current_document()?;
+        let session = self
+            .clients
+            .get_mut(client_id)
+            .ok_or_else(|| format!("Client {client_id} not found"))?;
+        session.sync_mode = Some(SyncMode::Bare);
+        let server_edits = session.sync_engine.diff_and_update_shadow();
+        if !self.quiet {
+            log_outgoing(client_id, server_edits.len());
+        }
 
-        if !client_edits.is_empty() {
-            let new_content = crate::diff::patch(&current_doc.content, &client_edits)
-                .map_err(|e| format!("Failed to apply client edits: {e}"))?;
+        Ok((server_edits, rejected))
+    }
 
-            current_doc = self
-                .db
-                .update_document(&self.document_name, new_content)
-                .map_err(|e| format!("Failed to save document: {e}"))?;
+    /// Guaranteed-delivery counterpart of [`sync_with_client`](Self::sync_with_client):
+    /// applies the unseen edit lists from the client's packet and replies with
+    /// the server's own unacknowledged stack, so lost or repeated messages in
+    /// either direction are recovered on the next exchange.
+    pub fn sync_packet_with_client(
+        &mut self,
+        client_id: &str,
+        packet: SyncPacket,
+    ) -> Result<(SyncPacket, Vec<usize>), ClientSyncError> {
+        let rejected =
+            self.apply_client_changes(client_id, |engine| engine.receive_packet(packet))?;
 
-            println!(
-                "Client {} updated document (v{})",
-                client_id.green(),
-                current_doc.version
-            );
-            self.version += 1;
+        let session = self
+            .clients
+            .get_mut(client_id)
+            .ok_or_else(|| format!("Client {client_id} not found"))?;
+        session.sync_mode = Some(SyncMode::Packets);
+        let reply = session.sync_engine.make_packet();
+        if !self.quiet {
+            log_outgoing(client_id, reply.edits.iter().map(|v| v.edits.len()).sum());
         }
 
+        Ok((reply, rejected))
+    }
+
+    /// Load the current document into the client's engine, let `apply` fold
+    /// the client's edits into its shadow and the document, and persist the
+    /// document if it changed. Returns the indices of rejected edits.
+    fn apply_client_changes(
+        &mut self,
+        client_id: &str,
+        apply: impl FnOnce(&mut SyncEngine) -> Result<PatchReport, PatchError>,
+    ) -> Result<Vec<usize>, ClientSyncError> {
+        let current_doc = self.get_current_document()?;
         let session = self
             .clients
             .get_mut(client_id)
             .ok_or_else(|| format!("Client {client_id} not found"))?;
 
         session.last_seen = Instant::now();
+        if session.needs_resync {
+            return Err(ClientSyncError::Diverged(PatchError::VersionMismatch));
+        }
+        // The engine's document is the shared document; its shadow is what
+        // this client is known to have.
+        session.sync_engine.document = current_doc.clone();
+        session.sync_engine.diff_options.granularity =
+            diff_granularity(&current_doc.content, self.line_mode_threshold);
+        // The revision is diffed like the engine diffs, within its deadline.
+        let diff_options = session.sync_engine.diff_options.clone();
+
+        let report = match apply(&mut session.sync_engine) {
+            Ok(report) => report,
+            Err(e) => {
+                if e == PatchError::ChecksumMismatch {
+                    self.checksum_mismatches += 1;
+                }
+                if !self.quiet {
+                    println!("Shadow for client {} diverged: {e}", client_id.red());
+                }
+                return Err(ClientSyncError::Diverged(e));
+            }
+        };
 
-        // Keep the client's shadow in sync by applying the same edits
-        if !client_edits.is_empty() {
-            session
-                .sync_engine
-                .apply_edits(client_edits)
-                .map_err(|e| format!("Failed to apply client edits to shadow: {e}"))?;
+        let rejected: Vec<usize> = report.rejected().collect();
+        if !rejected.is_empty() {
+            self.rejected_edits += rejected.len() as u64;
+            if !self.quiet {
+                println!(
+                    "Rejected {} of {} edits from client {}",
+                    rejected.len().to_string().red(),
+                    report.outcomes.len(),
+                    client_id.green()
+                );
+            }
         }
 
-        // Diff shadow against DB document — only other clients' changes remain
-        let server_edits = crate::diff::diff(session.sync_engine.text(), &current_doc.content);
+        if report.text != current_doc.content {
+            if let Err(e) = self.commit_document(report.text, Some(client_id), &diff_options) {
+                // The engine has taken the edits into its shadow and versions
+                // but the document has not, so the client has to resync.
+                if let Some(session) = self.clients.get_mut(client_id) {
+                    session.needs_resync = true;
+                }
+                return Err(e.into());
+            }
+            self.version += 1;
+            if !self.quiet {
+                println!(
+                    "Client {} updated document (v{})",
+                    client_id.green(),
+                    self.document.version
+                );
+            }
+            self.signal_push(Some(client_id));
+        }
 
-        if !server_edits.is_empty() {
-            session.sync_engine.edit(&current_doc.content);
-            println!(
-                "Sending {} edits to client {}",
-                server_edits.len().to_string().cyan(),
-                client_id.green()
-            );
+        Ok(rejected)
+    }
+
+    /// Subscribe `client_id` to pushes, if it agreed to the `push` feature.
+    /// The returned receiver is signalled whenever other clients change the
+    /// document; the connection then sends what
+    /// [`push_for`](Self::push_for) returns.
+    pub fn subscribe(&mut self, client_id: &str) -> Option<mpsc::Receiver<()>> {
+        let session = self.clients.get_mut(client_id)?;
+        if !session.capabilities.has_feature("push") {
+            return None;
         }
+        // One pending signal is enough: the push carries every change since.
+        let (signal, receiver) = mpsc::channel(1);
+        session.push_signal = Some(signal);
+        Some(receiver)
+    }
 
-        Ok(server_edits)
+    /// Wake the connection of every subscribed client that has synced, but
+    /// `from`'s.
+    fn signal_push(&self, from: Option<&str>) {
+        for session in self.clients.values() {
+            let other = from != Some(session.client_id.as_str()) && session.sync_mode.is_some();
+            if let (Some(signal), true) = (&session.push_signal, other) {
+                // A full channel already has a push pending.
+                let _ = signal.try_send(());
+            }
+        }
     }
 
+    /// The `ServerSync` to push to `client_id` now, if it has unseen changes.
+    ///
+    /// Built on the client's own connection, in order with its replies, so a
+    /// push never overtakes a reply computed after it. Clients using packets
+    /// get the changes, but only when nothing is awaiting their
+    /// acknowledgement, so a push that crosses their next `ClientSync` can be
+    /// rolled back. Bare edit lists carry no versions to detect such a
+    /// crossing, so those clients get an empty push asking them to sync.
+    pub fn push_for(&mut self, client_id: &str) -> Option<SyncMessage> {
+        let current_doc = self.get_current_document().ok()?;
+        let granularity = diff_granularity(&current_doc.content, self.line_mode_threshold);
+        let session = self.clients.get_mut(client_id)?;
+        if session.needs_resync || session.push_signal.is_none() {
+            return None;
+        }
+        match session.sync_mode {
+            Some(SyncMode::Packets) if session.sync_engine.can_send_unprompted() => {}
+            Some(SyncMode::Bare) => {
+                return Some(self.server_sync_for(
+                    client_id,
+                    EditList::default(),
+                    None,
+                    Vec::new(),
+                    true,
+                ))
+            }
+            _ => return None,
+        }
+
+        session.sync_engine.document = current_doc;
+        session.sync_engine.diff_options.granularity = granularity;
+        let packet = session.sync_engine.make_packet();
+        if packet.edits.is_empty() {
+            return None;
+        }
+        if !self.quiet {
+            log_outgoing(client_id, packet.edits.iter().map(|v| v.edits.len()).sum());
+        }
+        Some(self.server_sync_for(
+            client_id,
+            EditList::default(),
+            Some(packet),
+            Vec::new(),
+            true,
+        ))
+    }
+
+    /// A `ServerSync` for `client_id` carrying `edits` or `packet`, the other
+    /// clients' cursors, and bare edits in delta form if compact edits were
+    /// agreed.
+    pub fn server_sync_for(
+        &self,
+        client_id: &str,
+        edits: EditList,
+        packet: Option<SyncPacket>,
+        rejected_edits: Vec<usize>,
+        pushed: bool,
+    ) -> SyncMessage {
+        let compact = packet.is_none()
+            && self
+                .clients
+                .get(client_id)
+                .is_some_and(|s| s.capabilities.compact_edits);
+        let delta = compact.then(|| edits.to_delta().ok()).flatten();
+        SyncMessage::ServerSync {
+            edits: match delta {
+                Some(_) => EditList::default(),
+                None => edits,
+            },
+            delta,
+            server_version: self.version,
+            cursors: self.get_cursors_for(client_id),
+            rejected_edits,
+            packet,
+            pushed,
+        }
+    }
+
+    /// Reset the server's shadow for `client_id` to the current document and
+    /// return that document, for sending to the client in a `Resync`.
+    pub fn resync_client(&mut self, client_id: &str) -> Result<Document, String> {
+        let current_doc = self.get_current_document()?;
+        let session = self
+            .clients
+            .get_mut(client_id)
+            .ok_or_else(|| format!("Client {client_id} not found"))?;
+        session.sync_engine.reset(&current_doc.content);
+        session.needs_resync = false;
+        self.resyncs += 1;
+        if !self.quiet {
+            println!("Resynchronized client {}", client_id.yellow());
+        }
+        Ok(current_doc)
+    }
+
+    /// Record a client's cursor, given in the client's offset unit. It is
+    /// stored as a byte offset into the current document, and a move is
+    /// pushed like an edit.
     pub fn update_cursor(&mut self, client_id: &str, position: usize) {
-        if let Some(session) = self.clients.get_mut(client_id) {
-            session.cursor_position = Some(position);
+        let Some(unit) = self.offset_unit_of(client_id) else {
+            return;
+        };
+        let position = match unit {
+            OffsetUnit::Bytes => position,
+            unit => match self.get_current_document() {
+                Ok(doc) => convert_offset(&doc.content, position, unit, OffsetUnit::Bytes)
+                    .unwrap_or(doc.content.len()),
+                Err(_) => return,
+            },
+        };
+        let moved = self
+            .clients
+            .get_mut(client_id)
+            .is_some_and(|session| session.cursor_position.replace(position) != Some(position));
+        // Bare clients only see other cursors in replies, so ask them to sync.
+        if moved {
+            self.signal_push(Some(client_id));
         }
     }
 
-    /// Return cursor info for every client except `exclude_client`.
+    /// Return cursor info for every client except `exclude_client`, with
+    /// positions in `exclude_client`'s offset unit.
     pub fn get_cursors_for(&self, exclude_client: &str) -> Vec<CursorInfo> {
+        let unit = self.offset_unit_of(exclude_client).unwrap_or_default();
+        let content = match unit {
+            OffsetUnit::Bytes => None,
+            _ => self.get_current_document().ok().map(|doc| doc.content),
+        };
         self.clients
             .values()
             .filter_map(|s| {
                 if s.client_id == exclude_client {
                     return None;
                 }
+                let mut position = s.cursor_position?;
+                if let Some(content) = &content {
+                    position = convert_offset(content, position, OffsetUnit::Bytes, unit)?;
+                }
                 Some(CursorInfo {
                     client_id: s.client_id.clone(),
-                    position: s.cursor_position?,
+                    position,
                     color: s.color.clone(),
                 })
             })
             .collect()
     }
 
+    fn offset_unit_of(&self, client_id: &str) -> Option<OffsetUnit> {
+        self.clients
+            .get(client_id)
+            .map(|s| s.sync_engine.offset_unit)
+    }
+
+    /// Mark a client as alive, so stale-client cleanup leaves it connected.
+    pub fn touch_client(&mut self, client_id: &str) {
+        if let Some(session) = self.clients.get_mut(client_id) {
+            session.last_seen = Instant::now();
+        }
+    }
+
     pub fn disconnect_client(&mut self, client_id: &str) {
-        if self.clients.remove(client_id).is_some() {
+        if self.clients.remove(client_id).is_some() && !self.quiet {
             println!("Client {} disconnected", client_id);
         }
     }
@@ -243,8 +822,37 @@ impl SyncServer {
     }
 }
 
-/// Thread-safe handle to the server, shared across async tasks and connections.
-pub type SharedSyncServer = Arc<Mutex<SyncServer>>;
+impl Drop for SyncServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("{e}");
+        }
+    }
+}
+
+/// Why a `ClientSync` could not be processed.
+#[derive(Debug)]
+pub enum ClientSyncError {
+    /// The client's edits do not fit the server's shadow of that client; only
+    /// a full resync can bring the two back together.
+    Diverged(PatchError),
+    Other(String),
+}
+
+impl std::fmt::Display for ClientSyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Diverged(e) => write!(f, "Client shadow diverged: {e}"),
+            Self::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl From<String> for ClientSyncError {
+    fn from(msg: String) -> Self {
+        Self::Other(msg)
+    }
+}
 
 /// Serialize a `SyncMessage` to newline-delimited JSON bytes (for TCP framing).
 pub fn serialize_message(msg: &SyncMessage) -> Result<Vec<u8>, serde_json::Error> {
@@ -259,72 +867,321 @@ pub fn deserialize_message(data: &[u8]) -> Result<SyncMessage, String> {
     serde_json::from_str(json_str).map_err(|e| e.to_string())
 }
 
-/// Route an incoming message to the appropriate server handler and return the
-/// response (if any). Shared by both TCP and WebSocket transports.
+impl SyncServer {
+    /// Route an incoming message to the appropriate handler and return the
+    /// response (if any). `client_id` is the connection's client, set once its
+    /// `Connect` succeeds.
+    pub fn handle_message(
+        &mut self,
+        message: SyncMessage,
+        client_id: &mut Option<String>,
+    ) -> Option<SyncMessage> {
+        match message {
+            SyncMessage::Connect {
+                client_id: id,
+                protocol_version,
+                features,
+                offset_unit,
+                compact_edits,
+                codec,
+                compression,
+            } => {
+                if !self.quiet {
+                    println!("Client {} requesting connection", id.green());
+                }
+                let mut agreed = match Capabilities::negotiate(Capabilities {
+                    protocol_version,
+                    features,
+                    offset_unit,
+                    compact_edits,
+                    codec,
+                    compression,
+                }) {
+                    Ok(agreed) => agreed,
+                    Err(message) => {
+                        eprintln!("Refusing client {id}: {message}");
+                        return Some(SyncMessage::Error { message });
+                    }
+                };
+                agreed.compression = self.compression.accept(agreed.compression);
+                match self.connect_client_with_unit(id.clone(), agreed.offset_unit) {
+                    Ok(document) => {
+                        if let Some(session) = self.clients.get_mut(&id) {
+                            session.capabilities = agreed.clone();
+                        }
+                        *client_id = Some(id);
+                        Some(SyncMessage::ConnectOk {
+                            server_version: self.version,
+                            document,
+                            protocol_version: agreed.protocol_version,
+                            features: agreed.features,
+                            offset_unit: agreed.offset_unit,
+                            compact_edits: agreed.compact_edits,
+                            codec: agreed.codec,
+                            compression: agreed.compression,
+                        })
+                    }
+                    Err(e) => Some(SyncMessage::Error { message: e }),
+                }
+            }
+
+            SyncMessage::ClientSync {
+                client_id: id,
+                edits,
+                delta,
+                cursor_position,
+                packet,
+                ..
+            } => {
+                let edits = match delta.as_ref().map(EditList::from_delta) {
+                    None => edits,
+                    Some(Ok(edits)) => edits,
+                    Some(Err(e)) => {
+                        return Some(SyncMessage::Error {
+                            message: format!("Invalid delta: {e}"),
+                        })
+                    }
+                };
+
+                if let Some(pos) = cursor_position {
+                    self.update_cursor(&id, pos);
+                }
+
+                if !edits.is_empty() && !self.quiet {
+                    println!(
+                        "Client {} syncing {} edits",
+                        id.cyan(),
+                        edits.len().to_string().yellow()
+                    );
+                }
+
+                let result = match packet {
+                    Some(packet) => self
+                        .sync_packet_with_client(&id, packet)
+                        .map(|(reply, rejected)| (EditList::default(), Some(reply), rejected)),
+                    None => self
+                        .sync_with_client(&id, edits)
+                        .map(|(server_edits, rejected)| (server_edits, None, rejected)),
+                };
+
+                match result {
+                    Ok((server_edits, packet, rejected_edits)) => {
+                        Some(self.server_sync_for(&id, server_edits, packet, rejected_edits, false))
+                    }
+                    Err(ClientSyncError::Diverged(e))
+                        if !self
+                            .clients
+                            .get(&id)
+                            .is_some_and(|s| s.capabilities.has_feature("resync")) =>
+                    {
+                        Some(SyncMessage::Error {
+                            message: format!("{e}; reconnect to reload the document"),
+                        })
+                    }
+                    Err(ClientSyncError::Diverged(_)) => match self.resync_client(&id) {
+                        Ok(document) => Some(SyncMessage::Resync {
+                            server_version: self.version,
+                            document,
+                        }),
+                        Err(e) => Some(SyncMessage::Error { message: e }),
+                    },
+                    Err(e) => Some(SyncMessage::Error {
+                        message: e.to_string(),
+                    }),
+                }
+            }
+
+            SyncMessage::Disconnect { client_id: id } => {
+                self.disconnect_client(&id);
+                None
+            }
+
+            SyncMessage::Ping => {
+                // Clients that rely on pushes may only ping while idle.
+                if let Some(id) = client_id {
+                    self.touch_client(id);
+                }
+                Some(SyncMessage::Pong)
+            }
+
+            _ => Some(SyncMessage::Error {
+                message: "Unexpected message type".to_string(),
+            }),
+        }
+    }
+}
+
+/// Route an incoming message to the document's actor and return the response
+/// (if any). Shared by both TCP and WebSocket transports.
 pub async fn handle_sync_message(
     message: SyncMessage,
-    server: &SharedSyncServer,
+    server: &DocumentHandle,
     client_id: &mut Option<String>,
 ) -> Option<SyncMessage> {
-    match message {
-        SyncMessage::Connect { client_id: id } => {
-            println!("Client {} requesting connection", id.green());
-            let mut server_lock = server.lock().await;
-            match server_lock.connect_client(id.clone()) {
-                Ok(document) => {
-                    *client_id = Some(id);
-                    Some(SyncMessage::ConnectOk {
-                        server_version: server_lock.version,
-                        document,
-                    })
-                }
-                Err(e) => Some(SyncMessage::Error { message: e }),
-            }
+    let mut id = client_id.clone();
+    let result = server
+        .call(move |server| {
+            let response = server.handle_message(message, &mut id);
+            (response, id)
+        })
+        .await;
+    match result {
+        Ok((response, id)) => {
+            *client_id = id;
+            response
         }
+        Err(message) => Some(SyncMessage::Error { message }),
+    }
+}
 
-        SyncMessage::ClientSync {
-            client_id: id,
-            edits,
-            cursor_position,
-            ..
-        } => {
-            let mut server_lock = server.lock().await;
-
-            if let Some(pos) = cursor_position {
-                server_lock.update_cursor(&id, pos);
+/// Serve one TCP connection until the peer disconnects. Shared by the TCP
+/// listeners of both server binaries.
+///
+/// The framing is detected from the first byte: newline-delimited JSON, or
+/// length-prefixed frames. `Connect` and `ConnectOk` are always JSON; the codec
+/// agreed in `ConnectOk` is used from then on. A binary codec requested over
+/// line framing is downgraded to JSON, since its payloads may contain newlines.
+///
+/// Clients subscribed to pushes are also sent a `ServerSync` whenever the
+/// server signals that other clients changed the document.
+pub async fn handle_tcp_client(stream: TcpStream, server: DocumentHandle) -> Result<(), String> {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let Some(framing) = Framing::detect(&mut reader)
+        .await
+        .map_err(|e| e.to_string())?
+    else {
+        return Ok(());
+    };
+    // Frames are read on their own task, since a read cut short by a push
+    // would lose the bytes already taken from the stream.
+    let (frame_tx, mut frames) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let frame = framing.read_frame(&mut reader).await;
+            let last = !matches!(frame, Ok(Some(_)));
+            if frame_tx.send(frame).await.is_err() || last {
+                break;
             }
+        }
+    });
+    let mut codec = CodecKind::Json;
+    let mut compressor: Option<Compressor> = None;
+    // What the last `Connect` asked for: its `ConnectOk` is enveloped even if
+    // the server declines.
+    let mut requested = Compression::None;
+    let mut client_id: Option<String> = None;
+    let mut push_signal: Option<mpsc::Receiver<()>> = None;
 
-            if !edits.is_empty() {
-                println!(
-                    "Client {} syncing {} edits",
-                    id.cyan(),
-                    edits.len().to_string().yellow()
-                );
-            }
+    loop {
+        let response = tokio::select! {
+            frame = frames.recv() => {
+                let frame = match frame {
+                    Some(Ok(Some(frame))) => frame,
+                    Some(Err(e)) => return Err(e.to_string()),
+                    Some(Ok(None)) | None => {
+                        if let Some(id) = client_id {
+                            let _ = server.call(move |server| server.disconnect_client(&id)).await;
+                        }
+                        break;
+                    }
+                };
 
-            match server_lock.sync_with_client(&id, edits) {
-                Ok(server_edits) => {
-                    let cursors = server_lock.get_cursors_for(&id);
-                    Some(SyncMessage::ServerSync {
-                        edits: server_edits,
-                        server_version: server_lock.version,
-                        cursors,
-                    })
+                let payload = match &compressor {
+                    Some(compressor) => compressor.open(&frame),
+                    None => Ok(frame),
+                };
+                match payload.and_then(|payload| codec.codec().decode(&payload)) {
+                    Ok(message) => {
+                        if let SyncMessage::Connect { compression, .. } = &message {
+                            requested = *compression;
+                        }
+                        handle_sync_message(message, &server, &mut client_id).await
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to parse TCP message: {e}");
+                        Some(SyncMessage::Error {
+                            message: format!("Invalid message format: {e}"),
+                        })
+                    }
                 }
-                Err(e) => Some(SyncMessage::Error { message: e }),
             }
+            () = push_signalled(&mut push_signal) => match client_id.clone() {
+                Some(id) => server.call(move |server| server.push_for(&id)).await.ok().flatten(),
+                None => None,
+            },
+        };
+        let Some(mut response) = response else {
+            continue;
+        };
+
+        let reply_codec = codec;
+        let mut declined_envelope = false;
+        if let SyncMessage::ConnectOk {
+            codec: agreed,
+            compression,
+            ..
+        } = &mut response
+        {
+            // Lines carry neither binary payloads nor envelopes.
+            if framing == Framing::Lines {
+                *agreed = CodecKind::Json;
+                *compression = Compression::None;
+            }
+            codec = *agreed;
+            let (compression, id) = (*compression, client_id.clone());
+            (compressor, push_signal) = server
+                .call(move |server| {
+                    let compressor = server.compression.compressor(compression);
+                    (compressor, id.and_then(|id| server.subscribe(&id)))
+                })
+                .await?;
+            declined_envelope = compressor.is_none()
+                && framing == Framing::LengthPrefixed
+                && requested != Compression::None;
+        }
+        let mut data = reply_codec.codec().encode(&response)?;
+        match &compressor {
+            Some(compressor) => data = compressor.seal(&data),
+            None if declined_envelope => data = Compression::seal_bare(&data),
+            None => {}
         }
+        framing
+            .write_frame(&mut write_half, &data)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
 
-        SyncMessage::Disconnect { client_id: id } => {
-            server.lock().await.disconnect_client(&id);
-            None
+/// Resolves each time the server signals a push on `signal`; never resolves
+/// for a connection that is not subscribed.
+pub async fn push_signalled(signal: &mut Option<mpsc::Receiver<()>>) {
+    if let Some(receiver) = signal {
+        if receiver.recv().await.is_some() {
+            return;
         }
+    }
+    std::future::pending().await
+}
 
-        SyncMessage::Ping => Some(SyncMessage::Pong),
+/// Char diffs for small documents, line diffs from `line_mode_threshold` bytes.
+fn diff_granularity(content: &str, line_mode_threshold: usize) -> DiffGranularity {
+    if content.len() >= line_mode_threshold {
+        DiffGranularity::Line
+    } else {
+        DiffGranularity::Char
+    }
+}
 
-        _ => Some(SyncMessage::Error {
-            message: "Unexpected message type".to_string(),
-        }),
+fn log_outgoing(client_id: &str, edit_count: usize) {
+    if edit_count > 0 {
+        println!(
+            "Sending {} edits to client {}",
+            edit_count.to_string().cyan(),
+            client_id.green()
+        );
     }
 }
 
@@ -335,3 +1192,666 @@ fn random_cursor_color() -> String {
     let lit = 45 + (rand::random::<u8>() % 15);
     format!("hsl({hue}, {sat}%, {lit}%)")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_server() -> DocumentHandle {
+        DocumentHandle::spawn(SyncServer::new_in_memory("main".to_string()).unwrap()).unwrap()
+    }
+
+    async fn send(
+        server: &DocumentHandle,
+        client_id: &mut Option<String>,
+        json: &str,
+    ) -> SyncMessage {
+        let message = serde_json::from_str(json).unwrap();
+        handle_sync_message(message, server, client_id)
+            .await
+            .unwrap()
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_acknowledged_edits_survive_a_crash_before_write_back() {
+        use crate::DocumentDB;
+
+        let path =
+            std::env::temp_dir().join(format!("diff-sync-journal-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || {
+            SyncServer::new_with_store(
+                Box::new(DocumentDB::new(&path).unwrap()),
+                "main".to_string(),
+            )
+        };
+
+        let mut server = open().unwrap();
+        server.flush_every = 3;
+        let initial = server.connect_client("a".to_string()).unwrap().content;
+        let mut content = initial.clone();
+        for word in [" one", " two"] {
+            let edited = format!("{content}{word}");
+            server
+                .sync_with_client("a", crate::diff(&content, &edited))
+                .unwrap();
+            content = edited;
+        }
+        // Acknowledged, but only journaled so far.
+        let stored = DocumentDB::new(&path).unwrap();
+        assert_eq!(
+            stored.load_document("main").unwrap().unwrap().content,
+            initial
+        );
+        assert!(server.flush_due().is_some());
+        std::mem::forget(server);
+
+        let mut server = open().unwrap();
+        assert_eq!(server.get_document_content().unwrap(), content);
+        assert_eq!(server.flush_due(), None);
+        assert_eq!(
+            stored.load_document("main").unwrap().unwrap().content,
+            content
+        );
+
+        // Every `flush_every` versions the document is written back at once.
+        server.flush_every = 1;
+        server.connect_client("b".to_string()).unwrap();
+        let edited = format!("{content} three");
+        server
+            .sync_with_client("b", crate::diff(&content, &edited))
+            .unwrap();
+        assert_eq!(
+            stored.load_document("main").unwrap().unwrap().content,
+            edited
+        );
+        drop(server);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_restored_version_reaches_clients_as_a_change() {
+        let mut server = SyncServer::new_in_memory("main".to_string()).unwrap();
+        let initial = server.connect_client("a".to_string()).unwrap().content;
+        let edited = format!("{initial} Edited.");
+        server
+            .sync_with_client("a", crate::diff(&initial, &edited))
+            .unwrap();
+
+        let restored = server.restore_version(0).unwrap();
+        assert_eq!(restored, Document::new_with_version(initial.clone(), 2));
+        let (edits, _) = server.sync_with_client("a", EditList::default()).unwrap();
+        assert_eq!(crate::patch(&edited, &edits).unwrap(), initial);
+        assert!(server.restore_version(3).is_err());
+
+        let authors: Vec<_> = server
+            .store
+            .list_revisions("main")
+            .unwrap()
+            .into_iter()
+            .map(|r| r.author)
+            .collect();
+        assert_eq!(authors, [None, Some("a".to_string()), None]);
+    }
+
+    /// `Connect` as sent by each generation of client, and what the server
+    /// agrees to: (protocol version, features, offset unit, compact, codec).
+    #[tokio::test]
+    async fn test_handshake_compatibility_matrix() {
+        type Agreed = (u32, Vec<&'static str>, OffsetUnit, bool, CodecKind);
+        let legacy: Agreed = (1, vec![], OffsetUnit::Bytes, false, CodecKind::Json);
+        let cases: [(&str, Result<Agreed, &str>); 5] = [
+            // Version 1: only a client id.
+            (r#"{"Connect":{"client_id":"v1"}}"#, Ok(legacy.clone())),
+            // Version 1 fields from a later version are not honoured.
+            (
+                r#"{"Connect":{"client_id":"v1x","offset_unit":"Utf16","codec":"Cbor"}}"#,
+                Ok(legacy),
+            ),
+            // Current version: everything honoured, unknown features dropped.
+            (
+                r#"{"Connect":{"client_id":"v2","protocol_version":2,"features":["telepathy","resync"],
+                    "offset_unit":"Chars","compact_edits":true,"codec":"MessagePack"}}"#,
+                Ok((
+                    2,
+                    vec!["resync"],
+                    OffsetUnit::Chars,
+                    true,
+                    CodecKind::MessagePack,
+                )),
+            ),
+            // A newer client is downgraded to what the server speaks.
+            (
+                r#"{"Connect":{"client_id":"v9","protocol_version":9,"features":["resync"],"codec":"Cbor"}}"#,
+                Ok((2, vec!["resync"], OffsetUnit::Bytes, false, CodecKind::Cbor)),
+            ),
+            // Below the minimum: refused with a clear error.
+            (
+                r#"{"Connect":{"client_id":"v0","protocol_version":0}}"#,
+                Err("Unsupported protocol version 0: this server speaks versions 1 to 2"),
+            ),
+        ];
+
+        let server = test_server();
+        for (json, expected) in cases {
+            let mut client_id = None;
+            match (send(&server, &mut client_id, json).await, expected) {
+                (
+                    SyncMessage::ConnectOk {
+                        protocol_version,
+                        features,
+                        offset_unit,
+                        compact_edits,
+                        codec,
+                        ..
+                    },
+                    Ok(agreed),
+                ) => {
+                    assert_eq!(
+                        (
+                            protocol_version,
+                            features,
+                            offset_unit,
+                            compact_edits,
+                            codec
+                        ),
+                        (
+                            agreed.0,
+                            agreed.1.iter().map(|f| f.to_string()).collect(),
+                            agreed.2,
+                            agreed.3,
+                            agreed.4
+                        ),
+                        "{json}"
+                    );
+                    assert!(client_id.is_some());
+                }
+                (SyncMessage::Error { message }, Err(expected)) => {
+                    assert_eq!(message, expected);
+                    assert!(client_id.is_none());
+                }
+                (other, expected) => panic!("{json}: got {other:?}, expected {expected:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_connect_ok_reads_as_legacy_when_fields_are_missing() {
+        let json = r#"{"ConnectOk":{"server_version":1,"document":{"content":"hi","version":0}}}"#;
+        let SyncMessage::ConnectOk {
+            protocol_version,
+            features,
+            codec,
+            ..
+        } = serde_json::from_str(json).unwrap()
+        else {
+            panic!("not a ConnectOk");
+        };
+        assert_eq!((protocol_version, codec), (1, CodecKind::Json));
+        assert!(features.is_empty());
+    }
+
+    /// A [`MemoryStore`] whose revision appends fail while `failing` is set.
+    struct FailingStore {
+        inner: MemoryStore,
+        failing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl DocumentStore for FailingStore {
+        fn load_document(&self, name: &str) -> crate::StoreResult<Option<Document>> {
+            self.inner.load_document(name)
+        }
+
+        fn flush_document(&self, name: &str, document: &Document) -> crate::StoreResult<()> {
+            self.inner.flush_document(name, document)
+        }
+
+        fn insert_revision(
+            &self,
+            name: &str,
+            revision: &crate::StoredRevision,
+        ) -> crate::StoreResult<()> {
+            if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(crate::StoreError::Corrupt("disk full".to_string()));
+            }
+            self.inner.insert_revision(name, revision)
+        }
+
+        fn revisions_between(
+            &self,
+            name: &str,
+            from: u64,
+            to: u64,
+        ) -> crate::StoreResult<Vec<crate::StoredRevision>> {
+            self.inner.revisions_between(name, from, to)
+        }
+
+        fn last_snapshot(&self, name: &str, version: u64) -> crate::StoreResult<Option<u64>> {
+            self.inner.last_snapshot(name, version)
+        }
+
+        fn create_session(
+            &self,
+            token: &str,
+            creator_secret: &str,
+            initial_content: &str,
+        ) -> crate::StoreResult<()> {
+            self.inner
+                .create_session(token, creator_secret, initial_content)
+        }
+
+        fn get_session(&self, token: &str) -> crate::StoreResult<Option<crate::Session>> {
+            self.inner.get_session(token)
+        }
+
+        fn close_session(&self, token: &str, creator_secret: &str) -> crate::StoreResult<bool> {
+            self.inner.close_session(token, creator_secret)
+        }
+    }
+
+    #[test]
+    fn test_edits_survive_a_failed_revision_append() {
+        let failing = std::sync::Arc::default();
+        let store = FailingStore {
+            inner: MemoryStore::new(),
+            failing: std::sync::Arc::clone(&failing),
+        };
+        let mut server = SyncServer::new_with_store(Box::new(store), "main".to_string()).unwrap();
+        let initial = server.connect_client("a".to_string()).unwrap().content;
+        let mut client = SyncEngine::new(initial.clone());
+        client.edit(&format!("{initial} and more"));
+        let packet = client.make_packet();
+
+        failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(matches!(
+            server.sync_packet_with_client("a", packet.clone()),
+            Err(ClientSyncError::Other(_))
+        ));
+        failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(server.get_document_content().unwrap(), initial);
+
+        // The retry is not taken as a repeat of edits already applied.
+        assert!(matches!(
+            server.sync_packet_with_client("a", packet),
+            Err(ClientSyncError::Diverged(_))
+        ));
+        let document = server.resync_client("a").unwrap();
+        client.resync(&document.content);
+        server
+            .sync_packet_with_client("a", client.make_packet())
+            .unwrap();
+        assert_eq!(
+            server.get_document_content().unwrap(),
+            format!("{initial} and more")
+        );
+    }
+
+    #[test]
+    fn test_checksum_mismatch_is_refused_by_default() {
+        let mut server = SyncServer::new_in_memory("main".to_string()).unwrap();
+        let initial = server.connect_client("a".to_string()).unwrap().content;
+        // Edits that would patch cleanly, computed against some other shadow.
+        let mut edits = crate::diff(&initial, &format!("{initial}!"));
+        edits.checksum = crate::checksum("a shadow the server never had");
+
+        assert!(matches!(
+            server.sync_with_client("a", edits),
+            Err(ClientSyncError::Diverged(PatchError::ChecksumMismatch))
+        ));
+        assert_eq!(server.get_document_content().unwrap(), initial);
+        assert_eq!(server.checksum_mismatches, 1);
+    }
+
+    #[tokio::test]
+    async fn test_diverged_client_gets_resync_only_with_the_feature() {
+        let server = test_server();
+        let bad_sync = |id: &str| {
+            format!(
+                r#"{{"ClientSync":{{"client_id":"{id}","client_version":0,
+                    "edits":{{"edits":[{{"Delete":{{"pos":9999,"len":1}}}}],"checksum":""}}}}}}"#
+            )
+        };
+
+        let mut old = None;
+        send(&server, &mut old, r#"{"Connect":{"client_id":"old"}}"#).await;
+        match send(&server, &mut old, &bad_sync("old")).await {
+            SyncMessage::Error { message } => assert!(message.contains("reconnect"), "{message}"),
+            other => panic!("expected Error, got {other:?}"),
+        }
+
+        let mut new = None;
+        let connect =
+            r#"{"Connect":{"client_id":"new","protocol_version":2,"features":["resync"]}}"#;
+        send(&server, &mut new, connect).await;
+        assert!(matches!(
+            send(&server, &mut new, &bad_sync("new")).await,
+            SyncMessage::Resync { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_other_clients_edits_are_pushed_to_subscribers() {
+        let server = test_server();
+        let connect = |id: &str, features: &str| {
+            format!(
+                r#"{{"Connect":{{"client_id":"{id}","protocol_version":2,"features":[{features}]}}}}"#
+            )
+        };
+        let mut ids: Vec<Option<String>> = vec![None; 4];
+        for (i, (id, features)) in [
+            ("a", ""),
+            ("bare", r#""push""#),
+            ("packets", r#""push""#),
+            ("polling", ""),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            send(&server, &mut ids[i], &connect(id, features)).await;
+        }
+        let (initial, polling, mut signals) = server
+            .call(|server| {
+                let initial = server.get_document_content().unwrap();
+                let polling = server.subscribe("polling");
+                let signals = vec![
+                    server.subscribe("bare").unwrap(),
+                    server.subscribe("packets").unwrap(),
+                ];
+                (initial, polling, signals)
+            })
+            .await
+            .unwrap();
+        assert!(polling.is_none());
+
+        // Nothing is pushed until a client's first ClientSync shows its form.
+        let mut packets = SyncEngine::new(initial.clone());
+        let sync =
+            |id: &str, edits: EditList, packet: Option<SyncPacket>| SyncMessage::ClientSync {
+                client_id: id.to_string(),
+                edits,
+                delta: None,
+                client_version: 0,
+                cursor_position: None,
+                packet,
+            };
+        let mut bare_id = ids[1].clone();
+        handle_sync_message(
+            sync("bare", EditList::default(), None),
+            &server,
+            &mut bare_id,
+        )
+        .await;
+        let mut packets_id = ids[2].clone();
+        let reply = handle_sync_message(
+            sync("packets", EditList::default(), Some(packets.make_packet())),
+            &server,
+            &mut packets_id,
+        )
+        .await;
+        let Some(SyncMessage::ServerSync {
+            packet: Some(packet),
+            pushed: false,
+            ..
+        }) = reply
+        else {
+            panic!("expected a packet reply, got {reply:?}");
+        };
+        packets.receive_packet(packet).unwrap();
+
+        let edited = format!("{initial} Pushed.");
+        let mut a_id = ids[0].clone();
+        handle_sync_message(
+            sync("a", crate::diff(&initial, &edited), None),
+            &server,
+            &mut a_id,
+        )
+        .await;
+        assert!(signals[0].try_recv().is_ok());
+        assert!(signals[1].try_recv().is_ok());
+
+        let pushes = server
+            .call(|server| ["bare", "packets", "a", "polling"].map(|id| server.push_for(id)))
+            .await
+            .unwrap();
+        let [bare, packets_push, a, polling] = pushes;
+        // Bare clients are only asked to sync.
+        match bare {
+            Some(SyncMessage::ServerSync {
+                edits,
+                packet: None,
+                pushed: true,
+                ..
+            }) => assert!(edits.is_empty()),
+            other => panic!("expected an empty push, got {other:?}"),
+        }
+        match packets_push {
+            Some(SyncMessage::ServerSync {
+                packet: Some(packet),
+                pushed: true,
+                ..
+            }) => {
+                packets.receive_packet(packet).unwrap();
+                assert_eq!(packets.text(), edited);
+            }
+            other => panic!("expected a pushed packet, got {other:?}"),
+        }
+        assert!(a.is_none());
+        assert!(polling.is_none());
+
+        // The packet client must acknowledge before it is pushed to again.
+        let again = format!("{edited} Again.");
+        handle_sync_message(
+            sync("a", crate::diff(&edited, &again), None),
+            &server,
+            &mut a_id,
+        )
+        .await;
+        let push = server
+            .call(|server| server.push_for("packets"))
+            .await
+            .unwrap();
+        assert!(push.is_none());
+        let reply = handle_sync_message(
+            sync("packets", EditList::default(), Some(packets.make_packet())),
+            &server,
+            &mut packets_id,
+        )
+        .await;
+        let Some(SyncMessage::ServerSync {
+            packet: Some(packet),
+            ..
+        }) = reply
+        else {
+            panic!("expected a packet reply, got {reply:?}");
+        };
+        packets.receive_packet(packet).unwrap();
+        assert_eq!(packets.text(), format!("{edited} Again."));
+    }
+
+    #[tokio::test]
+    async fn test_bare_client_shadows_survive_a_change_during_its_request() {
+        let server = test_server();
+        let (mut a_id, mut bare_id) = (None, None);
+        for (id, client_id) in [("a", &mut a_id), ("bare", &mut bare_id)] {
+            let connect = format!(
+                r#"{{"Connect":{{"client_id":"{id}","protocol_version":2,"features":["push"]}}}}"#
+            );
+            send(&server, client_id, &connect).await;
+        }
+        let (initial, mut signal) = server
+            .call(|server| {
+                let initial = server.get_document_content().unwrap();
+                (initial, server.subscribe("bare").unwrap())
+            })
+            .await
+            .unwrap();
+        let sync = |id: &str, edits: EditList| SyncMessage::ClientSync {
+            client_id: id.to_string(),
+            edits,
+            delta: None,
+            client_version: 0,
+            cursor_position: None,
+            packet: None,
+        };
+        handle_sync_message(sync("bare", EditList::default()), &server, &mut bare_id).await;
+
+        // The bare client's request is on its way when "a" edits before it.
+        let mut bare = SyncEngine::new(initial.clone());
+        bare.edit(&format!("{initial} Mine."));
+        let in_flight = bare.diff_and_update_shadow();
+        handle_sync_message(
+            sync("a", crate::diff(&initial, &format!("Theirs. {initial}"))),
+            &server,
+            &mut a_id,
+        )
+        .await;
+        // The push only asks for a sync, so the server's shadow stays where
+        // the request in flight expects it.
+        assert!(signal.try_recv().is_ok());
+        let push = server.call(|server| server.push_for("bare")).await.unwrap();
+        assert!(matches!(
+            push,
+            Some(SyncMessage::ServerSync { edits, pushed: true, .. }) if edits.is_empty()
+        ));
+
+        let reply = handle_sync_message(sync("bare", in_flight), &server, &mut bare_id).await;
+        let Some(SyncMessage::ServerSync {
+            edits,
+            pushed: false,
+            ..
+        }) = reply
+        else {
+            panic!("expected a reply, got {reply:?}");
+        };
+        bare.apply_edits(edits).unwrap();
+        let (document, shadow) = server
+            .call(|server| {
+                (
+                    server.get_document_content().unwrap(),
+                    server.clients["bare"].sync_engine.shadow_checksum(),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(document, format!("Theirs. {initial} Mine."));
+        assert_eq!(bare.text(), document);
+        assert_eq!(bare.shadow_checksum(), shadow);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_payloads_are_enveloped_once_compression_is_agreed() {
+        use tokio::io::AsyncWriteExt;
+
+        let server = test_server();
+        server
+            .call(|server| {
+                server.compression.allowed = vec![Compression::Zstd];
+                server.compression.threshold = 64;
+            })
+            .await
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let accepting = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_tcp_client(stream, accepting.clone()));
+            }
+        });
+
+        let framing = Framing::LengthPrefixed;
+        let connect = |id: &str, compression: &str| {
+            format!(
+                r#"{{"Connect":{{"client_id":"{id}","protocol_version":2,"compression":"{compression}"}}}}"#
+            )
+        };
+
+        // Deflate is not offered: `ConnectOk` still comes in an envelope,
+        // with tag 0, and everything after it is bare.
+        let mut stream = BufReader::new(TcpStream::connect(address).await.unwrap());
+        framing
+            .write_frame(stream.get_mut(), connect("plain", "Deflate").as_bytes())
+            .await
+            .unwrap();
+        let reply = framing.read_frame(&mut stream).await.unwrap().unwrap();
+        assert_eq!(reply.first(), Some(&0));
+        assert!(matches!(
+            CodecKind::Json
+                .codec()
+                .decode(&Compression::open(&reply).unwrap()),
+            Ok(SyncMessage::ConnectOk {
+                compression: Compression::None,
+                ..
+            })
+        ));
+        framing
+            .write_frame(stream.get_mut(), br#""Ping""#)
+            .await
+            .unwrap();
+        let reply = framing.read_frame(&mut stream).await.unwrap().unwrap();
+        assert_eq!(reply, br#""Pong""#);
+
+        // Zstd is, so `ConnectOk` and everything after it is enveloped.
+        let mut stream = BufReader::new(TcpStream::connect(address).await.unwrap());
+        framing
+            .write_frame(stream.get_mut(), connect("zipped", "Zstd").as_bytes())
+            .await
+            .unwrap();
+        let reply = framing.read_frame(&mut stream).await.unwrap().unwrap();
+        let document = match CodecKind::Json
+            .codec()
+            .decode(&Compression::open(&reply).unwrap())
+        {
+            Ok(SyncMessage::ConnectOk {
+                compression,
+                document,
+                ..
+            }) => {
+                assert_eq!(compression, Compression::Zstd);
+                document
+            }
+            other => panic!("expected ConnectOk, got {other:?}"),
+        };
+
+        let client = Compressor::new(Compression::Zstd, 64);
+        let paste = "All work and no play makes Jack a dull boy. ".repeat(200);
+        let edits = crate::diff(&document.content, &(document.content.clone() + &paste));
+        let sync = SyncMessage::ClientSync {
+            client_id: "zipped".to_string(),
+            edits,
+            delta: None,
+            client_version: 0,
+            cursor_position: None,
+            packet: None,
+        };
+        let payload = CodecKind::Json.codec().encode(&sync).unwrap();
+        let envelope = client.seal(&payload);
+        assert!(envelope.len() * 10 < payload.len());
+        framing
+            .write_frame(stream.get_mut(), &envelope)
+            .await
+            .unwrap();
+        let reply = framing.read_frame(&mut stream).await.unwrap().unwrap();
+        let reply = CodecKind::Json
+            .codec()
+            .decode(&client.open(&reply).unwrap());
+        assert!(
+            matches!(reply, Ok(SyncMessage::ServerSync { .. })),
+            "{reply:?}"
+        );
+        stream.get_mut().shutdown().await.unwrap();
+
+        let (report, content) = server
+            .call(|server| {
+                let report = server.compression.stats.report();
+                (report, server.get_document_content().unwrap())
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.messages, 3);
+        assert!(report.compressed_messages >= 1);
+        assert!(report.ratio < 0.5, "{report:?}");
+        assert!(content.contains("dull boy"));
+    }
+}
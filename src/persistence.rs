@@ -1,19 +1,120 @@
-use crate::Document;
-use rusqlite::{params, Connection, Result as SqlResult};
-use serde::{Deserialize, Serialize};
+use crate::store::{current_timestamp, DEFAULT_CONTENT};
+use crate::{
+    Document, DocumentStore, EditList, Revision, Session, StoreError, StoreResult, StoredRevision,
+};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, Error as SqlError, Result as SqlResult, TransactionBehavior};
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Session {
-    pub token: String,
-    pub document_name: String,
-    pub status: String,
-    pub created_at: i64,
-    pub closed_at: Option<i64>,
+use std::time::UNIX_EPOCH;
+
+/// A step from one schema version to the next, run inside the upgrade's
+/// transaction.
+type Migration = fn(&Connection) -> SqlResult<()>;
+
+/// Every schema change, oldest first; a database at version `n` has had the
+/// first `n` applied. Databases from before `schema_version` existed count as
+/// version 0, so each step must also cope with its change already being
+/// there. Append new steps; never edit released ones.
+const MIGRATIONS: &[Migration] = &[
+    create_documents_and_sessions,
+    create_revisions,
+    add_revision_authors,
+];
+
+/// Schema version this build creates and understands.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+fn create_documents_and_sessions(conn: &Connection) -> SqlResult<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS documents (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE NOT NULL,
+            content TEXT NOT NULL,
+            version INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            token TEXT PRIMARY KEY,
+            creator_secret TEXT NOT NULL,
+            document_name TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'active',
+            created_at INTEGER NOT NULL,
+            closed_at INTEGER
+        );",
+    )
 }
 
-const DEFAULT_CONTENT: &str = "Welcome to collaborative editing with persistence!";
+/// Each version's edits, and now and then its full content. Written before a
+/// change is acknowledged, so they also cover versions a caching server has
+/// not yet written to `documents`. Existing documents get a snapshot of
+/// their current version to start their history from.
+fn create_revisions(conn: &Connection) -> SqlResult<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS revisions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            document_name TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            edits TEXT NOT NULL,
+            snapshot TEXT,
+            created_at INTEGER NOT NULL,
+            UNIQUE (document_name, version)
+        )",
+        [],
+    )?;
+    let no_edits = serde_json::to_string(&EditList::default())
+        .map_err(|e| SqlError::ToSqlConversionFailure(Box::new(e)))?;
+    conn.execute(
+        "INSERT INTO revisions (document_name, version, edits, snapshot, created_at)
+         SELECT name, version, ?1, content, updated_at FROM documents
+         WHERE name NOT IN (SELECT document_name FROM revisions)",
+        [no_edits],
+    )?;
+    Ok(())
+}
+
+/// The client whose sync produced each revision.
+fn add_revision_authors(conn: &Connection) -> SqlResult<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info('revisions') WHERE name = 'author'")?
+        .exists([])?;
+    if !exists {
+        conn.execute("ALTER TABLE revisions ADD COLUMN author TEXT", [])?;
+    }
+    Ok(())
+}
+
+/// Bring the schema of `conn` up to [`SCHEMA_VERSION`], refusing databases
+/// written by a newer build.
+fn migrate(conn: &mut Connection) -> StoreResult<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        [],
+    )?;
+    // Immediate, so two processes opening the same file upgrade it once.
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let current: u32 = tx
+        .query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get::<_, Option<u32>>(0)
+        })?
+        .unwrap_or(0);
+    if current > SCHEMA_VERSION {
+        return Err(StoreError::UnsupportedSchema(current));
+    }
+    if current == SCHEMA_VERSION {
+        return Ok(());
+    }
+    for migration in &MIGRATIONS[current as usize..] {
+        migration(&tx)?;
+    }
+    tx.execute("DELETE FROM schema_version", [])?;
+    tx.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        [SCHEMA_VERSION],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
 
 /// SQLite-backed persistent storage for documents.
 pub struct DocumentDB {
@@ -21,55 +122,85 @@ pub struct DocumentDB {
 }
 
 impl DocumentDB {
-    pub fn new<P: AsRef<Path>>(db_path: P) -> SqlResult<Self> {
-        let conn = Connection::open(db_path)?;
-        let db = Self { conn };
-        db.init_schema()?;
-        Ok(db)
+    /// Open the database at `db_path`, creating it or upgrading its schema
+    /// as needed.
+    pub fn new<P: AsRef<Path>>(db_path: P) -> StoreResult<Self> {
+        Self::open(Connection::open(db_path)?)
+    }
+
+    pub fn new_in_memory() -> StoreResult<Self> {
+        Self::open(Connection::open_in_memory()?)
     }
 
-    pub fn new_in_memory() -> SqlResult<Self> {
-        let conn = Connection::open_in_memory()?;
+    fn open(mut conn: Connection) -> StoreResult<Self> {
+        migrate(&mut conn)?;
         let db = Self { conn };
-        db.init_schema()?;
+        db.create_default_document()?;
         Ok(db)
     }
 
-    fn init_schema(&self) -> SqlResult<()> {
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS documents (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT UNIQUE NOT NULL,
-                content TEXT NOT NULL,
-                version INTEGER NOT NULL DEFAULT 0,
-                created_at INTEGER NOT NULL,
-                updated_at INTEGER NOT NULL
-            )",
-            [],
-        )?;
-
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS sessions (
-                token TEXT PRIMARY KEY,
-                creator_secret TEXT NOT NULL,
-                document_name TEXT NOT NULL,
-                status TEXT NOT NULL DEFAULT 'active',
-                created_at INTEGER NOT NULL,
-                closed_at INTEGER
-            )",
-            [],
-        )?;
-
+    fn create_default_document(&self) -> StoreResult<()> {
         let now = current_timestamp();
-        self.conn.execute(
+        let created = self.conn.execute(
             "INSERT OR IGNORE INTO documents (name, content, version, created_at, updated_at)
              VALUES (?1, ?2, 0, ?3, ?3)",
             params!["main", DEFAULT_CONTENT, now],
         )?;
+        if created > 0 {
+            self.append_revision("main", 0, &EditList::default(), DEFAULT_CONTENT, None)?;
+        }
         Ok(())
     }
 
-    pub fn load_document(&self, name: &str) -> SqlResult<Option<Document>> {
+    pub fn save_document(&self, name: &str, document: &Document) -> SqlResult<()> {
+        let now = current_timestamp();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO documents (name, content, version, created_at, updated_at)
+             VALUES (?1, ?2, ?3,
+                     COALESCE((SELECT created_at FROM documents WHERE name = ?1), ?4),
+                     ?4)",
+            params![name, document.content, document.version, now],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_documents(&self) -> SqlResult<Vec<(String, u64, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, version, created_at FROM documents ORDER BY updated_at DESC")?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u64>(1)?,
+                format_timestamp(row.get(2)?),
+            ))
+        })?;
+
+        rows.collect()
+    }
+
+    pub fn get_stats(&self) -> SqlResult<DocumentStats> {
+        let count: u64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0))?;
+
+        let latest_update: Option<i64> = self
+            .conn
+            .query_row("SELECT MAX(updated_at) FROM documents", [], |row| {
+                row.get(0)
+            })
+            .ok();
+
+        Ok(DocumentStats {
+            total_documents: count,
+            latest_update: latest_update.map(format_timestamp),
+        })
+    }
+}
+
+impl DocumentStore for DocumentDB {
+    fn load_document(&self, name: &str) -> StoreResult<Option<Document>> {
         let mut stmt = self
             .conn
             .prepare("SELECT content, version FROM documents WHERE name = ?1")?;
@@ -84,61 +215,108 @@ impl DocumentDB {
         }
     }
 
-    pub fn save_document(&self, name: &str, document: &Document) -> SqlResult<()> {
+    fn flush_document(&self, name: &str, document: &Document) -> StoreResult<()> {
         let now = current_timestamp();
         self.conn.execute(
-            "INSERT OR REPLACE INTO documents (name, content, version, created_at, updated_at)
-             VALUES (?1, ?2, ?3,
-                     COALESCE((SELECT created_at FROM documents WHERE name = ?1), ?4),
-                     ?4)",
+            "INSERT INTO documents (name, content, version, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT(name) DO UPDATE SET
+                 content = excluded.content,
+                 version = excluded.version,
+                 updated_at = excluded.updated_at
+             WHERE excluded.version > documents.version",
             params![name, document.content, document.version, now],
         )?;
         Ok(())
     }
 
-    pub fn update_document(&self, name: &str, new_content: String) -> SqlResult<Document> {
-        let current_version: u64 = self
-            .conn
-            .query_row(
-                "SELECT version FROM documents WHERE name = ?1",
-                [name],
-                |row| row.get(0),
-            )
-            .unwrap_or(0);
-
-        let new_version = current_version + 1;
-        let now = current_timestamp();
+    fn insert_revision(&self, name: &str, revision: &StoredRevision) -> StoreResult<()> {
+        let edits = serde_json::to_string(&revision.edits)?;
+        let inserted = self.conn.execute(
+            "INSERT INTO revisions (document_name, version, edits, snapshot, author, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                name,
+                revision.version,
+                edits,
+                revision.snapshot,
+                revision.author,
+                revision.created_at
+            ],
+        );
+        match inserted {
+            Ok(_) => Ok(()),
+            Err(SqlError::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(StoreError::RevisionExists(
+                    name.to_string(),
+                    revision.version,
+                ))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        self.conn.execute(
-            "UPDATE documents SET content = ?1, version = ?2, updated_at = ?3 WHERE name = ?4",
-            params![new_content, new_version, now, name],
+    fn revisions_between(
+        &self,
+        name: &str,
+        from: u64,
+        to: u64,
+    ) -> StoreResult<Vec<StoredRevision>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT version, edits, snapshot, author, created_at FROM revisions
+             WHERE document_name = ?1 AND version >= ?2 AND version <= ?3
+             ORDER BY version",
         )?;
-
-        Ok(Document::new_with_version(new_content, new_version))
+        let to = to.min(i64::MAX as u64);
+        let rows = stmt.query_map(params![name, from, to], |row| {
+            let edits: String = row.get(1)?;
+            let edits = serde_json::from_str(&edits)
+                .map_err(|e| SqlError::FromSqlConversionFailure(1, Type::Text, Box::new(e)))?;
+            Ok(StoredRevision {
+                version: row.get(0)?,
+                edits,
+                snapshot: row.get(2)?,
+                author: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })?;
+        Ok(rows.collect::<SqlResult<_>>()?)
     }
 
-    pub fn list_documents(&self) -> SqlResult<Vec<(String, u64, String)>> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT name, version, created_at FROM documents ORDER BY updated_at DESC")?;
+    fn last_snapshot(&self, name: &str, version: u64) -> StoreResult<Option<u64>> {
+        Ok(self.conn.query_row(
+            "SELECT MAX(version) FROM revisions
+             WHERE document_name = ?1 AND version <= ?2 AND snapshot IS NOT NULL",
+            params![name, version.min(i64::MAX as u64)],
+            |row| row.get(0),
+        )?)
+    }
 
-        let rows = stmt.query_map([], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, u64>(1)?,
-                format_timestamp(row.get(2)?),
-            ))
+    /// Without reading back every revision's edits.
+    fn list_revisions(&self, name: &str) -> StoreResult<Vec<Revision>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT version, author, snapshot IS NOT NULL, created_at FROM revisions
+             WHERE document_name = ?1 ORDER BY version",
+        )?;
+        let rows = stmt.query_map([name], |row| {
+            Ok(Revision {
+                version: row.get(0)?,
+                author: row.get(1)?,
+                snapshot: row.get(2)?,
+                created_at: row.get(3)?,
+            })
         })?;
-
-        rows.collect()
+        Ok(rows.collect::<SqlResult<_>>()?)
     }
 
-    pub fn create_session(
+    fn create_session(
         &self,
         token: &str,
         creator_secret: &str,
         initial_content: &str,
-    ) -> SqlResult<()> {
+    ) -> StoreResult<()> {
         let now = current_timestamp();
         let document_name = format!("session_{token}");
 
@@ -147,6 +325,13 @@ impl DocumentDB {
              VALUES (?1, ?2, 0, ?3, ?3)",
             params![document_name, initial_content, now],
         )?;
+        self.append_revision(
+            &document_name,
+            0,
+            &EditList::default(),
+            initial_content,
+            None,
+        )?;
 
         self.conn.execute(
             "INSERT INTO sessions (token, creator_secret, document_name, status, created_at)
@@ -157,7 +342,7 @@ impl DocumentDB {
         Ok(())
     }
 
-    pub fn get_session(&self, token: &str) -> SqlResult<Option<Session>> {
+    fn get_session(&self, token: &str) -> StoreResult<Option<Session>> {
         let mut stmt = self.conn.prepare(
             "SELECT token, document_name, status, created_at, closed_at
              FROM sessions WHERE token = ?1",
@@ -179,7 +364,7 @@ impl DocumentDB {
         }
     }
 
-    pub fn close_session(&self, token: &str, creator_secret: &str) -> SqlResult<bool> {
+    fn close_session(&self, token: &str, creator_secret: &str) -> StoreResult<bool> {
         let now = current_timestamp();
         let rows_updated = self.conn.execute(
             "UPDATE sessions SET status = 'closed', closed_at = ?1
@@ -189,31 +374,13 @@ impl DocumentDB {
         Ok(rows_updated > 0)
     }
 
-    pub fn is_session_active(&self, token: &str) -> SqlResult<bool> {
+    fn is_session_active(&self, token: &str) -> StoreResult<bool> {
         let mut stmt = self
             .conn
             .prepare("SELECT 1 FROM sessions WHERE token = ?1 AND status = 'active'")?;
         let exists = stmt.exists([token])?;
         Ok(exists)
     }
-
-    pub fn get_stats(&self) -> SqlResult<DocumentStats> {
-        let count: u64 = self
-            .conn
-            .query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0))?;
-
-        let latest_update: Option<i64> = self
-            .conn
-            .query_row("SELECT MAX(updated_at) FROM documents", [], |row| {
-                row.get(0)
-            })
-            .ok();
-
-        Ok(DocumentStats {
-            total_documents: count,
-            latest_update: latest_update.map(format_timestamp),
-        })
-    }
 }
 
 #[derive(Debug)]
@@ -222,13 +389,6 @@ pub struct DocumentStats {
     pub latest_update: Option<String>,
 }
 
-fn current_timestamp() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("system clock before UNIX epoch")
-        .as_secs() as i64
-}
-
 fn format_timestamp(timestamp: i64) -> String {
     let datetime = UNIX_EPOCH + std::time::Duration::from_secs(timestamp as u64);
     format!("{datetime:?}")
@@ -237,6 +397,7 @@ fn format_timestamp(timestamp: i64) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{diff, patch, Edit, SNAPSHOT_INTERVAL};
 
     #[test]
     fn test_document_persistence() {
@@ -257,6 +418,117 @@ mod tests {
         assert_eq!(reloaded.version, 1);
     }
 
+    #[test]
+    fn test_unwritten_revisions_are_replayed_on_recovery() {
+        let db = DocumentDB::new_in_memory().unwrap();
+        db.save_document("doc", &Document::new("one".to_string()))
+            .unwrap();
+        db.append_revision("doc", 0, &EditList::default(), "one", None)
+            .unwrap();
+        for (version, from, to) in [(1, "one", "one two"), (2, "one two", "one two three")] {
+            db.append_revision("doc", version, &diff(from, to), to, Some("a"))
+                .unwrap();
+        }
+
+        let recovered = db.recover_document("doc").unwrap().unwrap();
+        assert_eq!(
+            recovered,
+            Document::new_with_version("one two three".to_string(), 2)
+        );
+        assert_eq!(db.load_document("doc").unwrap(), Some(recovered));
+
+        // A stale writer cannot roll the stored document back.
+        db.flush_document("doc", &Document::new_with_version("one".to_string(), 1))
+            .unwrap();
+        assert_eq!(db.load_document("doc").unwrap().unwrap().version, 2);
+    }
+
+    #[test]
+    fn test_history_loads_any_version_and_restores_as_a_new_one() {
+        let db = DocumentDB::new_in_memory().unwrap();
+        let versions = SNAPSHOT_INTERVAL + 20;
+        for version in 1..=versions {
+            db.update_document("main", format!("version {version}"))
+                .unwrap();
+        }
+
+        let revisions = db.list_revisions("main").unwrap();
+        assert_eq!(revisions.len() as u64, versions + 1);
+        let snapshots: Vec<u64> = revisions
+            .iter()
+            .filter(|r| r.snapshot)
+            .map(|r| r.version)
+            .collect();
+        assert_eq!(snapshots, vec![0, SNAPSHOT_INTERVAL]);
+
+        let at = |version| db.load_document_at("main", version).unwrap();
+        assert_eq!(at(0).unwrap().content, DEFAULT_CONTENT);
+        assert_eq!(at(7).unwrap().content, "version 7");
+        assert_eq!(at(SNAPSHOT_INTERVAL + 5).unwrap().content, "version 105");
+        assert_eq!(at(versions + 1), None);
+
+        let restored = db.restore_document("main", 7).unwrap().unwrap();
+        assert_eq!(
+            restored,
+            Document::new_with_version("version 7".to_string(), versions + 1)
+        );
+        assert_eq!(db.load_document("main").unwrap(), Some(restored));
+        assert_eq!(at(versions).unwrap().content, format!("version {versions}"));
+        assert!(db.restore_document("main", versions + 5).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_blame_attributes_each_range_to_its_last_writer() {
+        let db = DocumentDB::new_in_memory().unwrap();
+        db.create_session("tok", "secret", "hello world").unwrap();
+        let doc = "session_tok";
+        let revisions = [
+            (
+                "hello world",
+                Edit::Insert {
+                    pos: 5,
+                    text: " big".to_string(),
+                },
+                "a",
+            ),
+            (
+                "hello big world",
+                Edit::Replace {
+                    pos: 10,
+                    old_len: 5,
+                    new_text: "there".to_string(),
+                },
+                "b",
+            ),
+            ("hello big there", Edit::Delete { pos: 6, len: 2 }, "b"),
+        ];
+        for (version, (source, edit, author)) in (1..).zip(revisions) {
+            let edits = EditList::new(vec![edit], source);
+            let content = patch(source, &edits).unwrap();
+            db.append_revision(doc, version, &edits, &content, Some(author))
+                .unwrap();
+        }
+
+        let spans: Vec<_> = db
+            .blame(doc)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.start, s.text, s.author, s.version))
+            .collect();
+        let a = Some("a".to_string());
+        assert_eq!(
+            spans,
+            [
+                (0, "hello".to_string(), None, 0),
+                (5, " g".to_string(), a, 1),
+                (7, " ".to_string(), None, 0),
+                (8, "there".to_string(), Some("b".to_string()), 2),
+            ]
+        );
+        assert!(db.blame("missing").unwrap().is_none());
+    }
+
     #[test]
     fn test_document_stats() {
         let db = DocumentDB::new_in_memory().unwrap();
@@ -303,4 +575,103 @@ mod tests {
         assert!(db.get_session("nope").unwrap().is_none());
         assert!(!db.is_session_active("nope").unwrap());
     }
+
+    /// Tables as `DocumentDB::new` created them before `schema_version`: the
+    /// original documents and sessions, then revisions and their authors.
+    const UNVERSIONED_TABLES: &str = "
+        CREATE TABLE documents (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE NOT NULL,
+            content TEXT NOT NULL,
+            version INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        );
+        CREATE TABLE sessions (
+            token TEXT PRIMARY KEY,
+            creator_secret TEXT NOT NULL,
+            document_name TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'active',
+            created_at INTEGER NOT NULL,
+            closed_at INTEGER
+        );
+        INSERT INTO documents (name, content, version, created_at, updated_at)
+            VALUES ('main', 'old text', 5, 1, 2);
+        INSERT INTO sessions (token, creator_secret, document_name, created_at)
+            VALUES ('tok', 'secret', 'main', 1);";
+    const REVISION_TABLE: &str = "
+        CREATE TABLE revisions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            document_name TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            edits TEXT NOT NULL,
+            snapshot TEXT,
+            author TEXT,
+            created_at INTEGER NOT NULL,
+            UNIQUE (document_name, version)
+        );
+        INSERT INTO revisions (document_name, version, edits, snapshot, author, created_at)
+            VALUES ('main', 5, '{\"edits\":[],\"checksum\":\"\"}', 'old text', 'a', 2);";
+
+    fn fixture(name: &str, sql: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("diff-sync-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Connection::open(&path).unwrap().execute_batch(sql).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_unversioned_databases_are_upgraded_in_place() {
+        let original = fixture("original", UNVERSIONED_TABLES);
+        let current = fixture(
+            "unversioned",
+            &format!("{UNVERSIONED_TABLES}{REVISION_TABLE}"),
+        );
+        for (path, author) in [(&original, None), (&current, Some("a".to_string()))] {
+            let db = DocumentDB::new(path).unwrap();
+            let version: u32 = db
+                .conn
+                .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(version, SCHEMA_VERSION);
+
+            assert!(db.is_session_active("tok").unwrap());
+            let revisions = db.list_revisions("main").unwrap();
+            assert_eq!(revisions.len(), 1);
+            assert_eq!(revisions[0].author, author);
+            assert_eq!(
+                db.load_document_at("main", 5).unwrap().unwrap().content,
+                "old text"
+            );
+            db.update_document("main", "new text".to_string()).unwrap();
+            drop(db);
+
+            // Already current: reopening changes nothing.
+            let db = DocumentDB::new(path).unwrap();
+            assert_eq!(
+                db.load_document("main").unwrap().unwrap(),
+                Document::new_with_version("new text".to_string(), 6)
+            );
+            assert_eq!(db.list_revisions("main").unwrap().len(), 2);
+            drop(db);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_databases_from_a_newer_build_are_refused() {
+        let path = fixture(
+            "newer",
+            &format!(
+                "CREATE TABLE schema_version (version INTEGER NOT NULL);
+                 INSERT INTO schema_version VALUES ({});",
+                SCHEMA_VERSION + 1
+            ),
+        );
+        assert!(matches!(
+            DocumentDB::new(&path),
+            Err(StoreError::UnsupportedSchema(v)) if v == SCHEMA_VERSION + 1
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
 }
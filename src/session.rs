@@ -1,51 +1,120 @@
-use crate::{DocumentDB, SharedSyncServer, SyncServer};
+use crate::{
+    BlameSpan, Compression, CompressionSettings, CompressionStats, Document, DocumentHandle,
+    DocumentStore, Revision, Session, Storage, StoreError, SyncServer, DEFAULT_FLUSH_DELAY,
+    DEFAULT_FLUSH_EVERY, DEFAULT_LINE_MODE_THRESHOLD,
+};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::time::{Duration, Instant};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::broadcast;
 
+/// Starts a document actor per session, plus one for the default document,
+/// and hands out handles to them. Syncing goes straight to the actors, and
+/// the session table is only locked to look handles up or add and remove
+/// them: storage work and actor calls run with it released.
 pub struct SessionManager {
-    db_path: String,
-    sessions: HashMap<String, SessionEntry>,
-    default_server: Option<SharedSyncServer>,
+    storage: Storage,
+    strict_checksums: bool,
+    line_mode_threshold: usize,
+    compression: CompressionSettings,
+    flush_delay: Duration,
+    flush_every: u64,
+    quiet: bool,
+    sessions: Mutex<HashMap<String, SessionEntry>>,
+    default_server: Mutex<Option<DocumentHandle>>,
 }
 
 struct SessionEntry {
-    server: SharedSyncServer,
+    server: DocumentHandle,
     last_active: Instant,
     shutdown: broadcast::Sender<()>,
 }
 
-pub type SharedSessionManager = Arc<Mutex<SessionManager>>;
+pub type SharedSessionManager = Arc<SessionManager>;
 
 impl SessionManager {
-    pub fn new(db_path: String) -> Self {
+    pub fn new(storage: Storage) -> Self {
         Self {
-            db_path,
-            sessions: HashMap::new(),
-            default_server: None,
+            storage,
+            strict_checksums: true,
+            line_mode_threshold: DEFAULT_LINE_MODE_THRESHOLD,
+            compression: CompressionSettings::default(),
+            flush_delay: DEFAULT_FLUSH_DELAY,
+            flush_every: DEFAULT_FLUSH_EVERY,
+            quiet: false,
+            sessions: Mutex::default(),
+            default_server: Mutex::default(),
         }
     }
 
-    pub fn default_server(&mut self) -> Result<SharedSyncServer, String> {
-        if let Some(ref server) = self.default_server {
-            return Ok(Arc::clone(server));
+    /// Make every server started from now on verify client checksums.
+    pub fn set_strict_checksums(&mut self, strict: bool) {
+        self.strict_checksums = strict;
+    }
+
+    /// Document size in bytes from which new servers diff line by line.
+    pub fn set_line_mode_threshold(&mut self, bytes: usize) {
+        self.line_mode_threshold = bytes;
+    }
+
+    /// Compressions new servers offer and the payload size from which they
+    /// compress. Every server records into the same statistics.
+    pub fn set_compression(&mut self, allowed: Vec<Compression>, threshold: usize) {
+        self.compression.allowed = allowed;
+        self.compression.threshold = threshold;
+    }
+
+    /// How long new servers let their document stay dirty, and how many
+    /// versions they let build up, before writing it back.
+    pub fn set_write_behind(&mut self, flush_delay: Duration, flush_every: u64) {
+        self.flush_delay = flush_delay;
+        self.flush_every = flush_every;
+    }
+
+    /// Leave routine client activity out of new servers' logs.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    pub fn compression_stats(&self) -> Arc<CompressionStats> {
+        Arc::clone(&self.compression.stats)
+    }
+
+    pub fn default_server(&self) -> Result<DocumentHandle, String> {
+        if let Some(server) = lock(&self.default_server).clone() {
+            return Ok(server);
         }
-        let db =
-            DocumentDB::new(&self.db_path).map_err(|e| format!("Failed to open database: {e}"))?;
-        let server: SharedSyncServer =
-            Arc::new(Mutex::new(SyncServer::new_with_db(db, "main".to_string())?));
-        self.default_server = Some(Arc::clone(&server));
-        Ok(server)
+        let store = self
+            .storage
+            .open()
+            .map_err(|e| format!("Failed to open storage: {e}"))?;
+        let server = SyncServer::new_with_store(store, "main".to_string())?;
+        let server = DocumentHandle::spawn(self.configure(server))?;
+        // Another caller may have started one meanwhile; keep theirs, and
+        // let ours stop when its handle drops.
+        Ok(lock(&self.default_server).get_or_insert(server).clone())
+    }
+
+    fn configure(&self, mut server: SyncServer) -> SyncServer {
+        server.strict_checksums = self.strict_checksums;
+        server.line_mode_threshold = self.line_mode_threshold;
+        server.compression = self.compression.clone();
+        server.flush_delay = self.flush_delay;
+        server.flush_every = self.flush_every;
+        server.quiet = self.quiet;
+        server
     }
 
     pub fn create_session(&self, initial_content: &str) -> Result<(String, String), String> {
         let token = generate_token();
         let creator_secret = generate_token();
 
-        let db =
-            DocumentDB::new(&self.db_path).map_err(|e| format!("Failed to open database: {e}"))?;
-        db.create_session(&token, &creator_secret, initial_content)
+        let store = self
+            .storage
+            .open()
+            .map_err(|e| format!("Failed to open storage: {e}"))?;
+        store
+            .create_session(&token, &creator_secret, initial_content)
             .map_err(|e| format!("Failed to create session: {e}"))?;
 
         Ok((token, creator_secret))
@@ -53,65 +122,66 @@ impl SessionManager {
 
     /// Returns the session's server and a receiver that fires when it's closed.
     pub fn get_or_start_session(
-        &mut self,
+        &self,
         token: &str,
-    ) -> Result<(SharedSyncServer, broadcast::Receiver<()>), SessionError> {
-        if let Some(entry) = self.sessions.get_mut(token) {
-            entry.last_active = Instant::now();
-            return Ok((Arc::clone(&entry.server), entry.shutdown.subscribe()));
+    ) -> Result<(DocumentHandle, broadcast::Receiver<()>), SessionError> {
+        if let Some(running) = self.touch_session(token) {
+            return Ok(running);
         }
 
-        let db = DocumentDB::new(&self.db_path)
-            .map_err(|e| SessionError::Internal(format!("Failed to open database: {e}")))?;
+        let store = self.open_store()?;
 
-        let session = db
+        let session = store
             .get_session(token)
-            .map_err(|e| SessionError::Internal(format!("Database error: {e}")))?
+            .map_err(storage_error)?
             .ok_or(SessionError::NotFound)?;
 
         if session.status != "active" {
             return Err(SessionError::Closed);
         }
 
-        let server: SharedSyncServer = Arc::new(Mutex::new(
-            SyncServer::new_with_db(db, session.document_name)
-                .map_err(|e| SessionError::Internal(e))?,
-        ));
-
-        let (shutdown, rx) = broadcast::channel(1);
-
-        self.sessions.insert(
-            token.to_string(),
-            SessionEntry {
-                server: Arc::clone(&server),
+        let server = SyncServer::new_with_store(store, session.document_name)
+            .map_err(SessionError::Internal)?;
+        let server =
+            DocumentHandle::spawn(self.configure(server)).map_err(SessionError::Internal)?;
+
+        // Another caller may have started the session meanwhile; join theirs.
+        let mut sessions = lock(&self.sessions);
+        let entry = sessions
+            .entry(token.to_string())
+            .or_insert_with(|| SessionEntry {
+                server,
                 last_active: Instant::now(),
-                shutdown,
-            },
-        );
+                shutdown: broadcast::channel(1).0,
+            });
+        entry.last_active = Instant::now();
+        Ok((entry.server.clone(), entry.shutdown.subscribe()))
+    }
 
-        Ok((server, rx))
+    /// The running session's server and a closing receiver, marking it
+    /// active.
+    fn touch_session(&self, token: &str) -> Option<(DocumentHandle, broadcast::Receiver<()>)> {
+        let mut sessions = lock(&self.sessions);
+        let entry = sessions.get_mut(token)?;
+        entry.last_active = Instant::now();
+        Some((entry.server.clone(), entry.shutdown.subscribe()))
     }
 
-    pub async fn close_session(
-        &mut self,
-        token: &str,
-        creator_secret: &str,
-    ) -> Result<(), SessionError> {
-        let db = DocumentDB::new(&self.db_path)
-            .map_err(|e| SessionError::Internal(format!("Failed to open database: {e}")))?;
+    pub fn close_session(&self, token: &str, creator_secret: &str) -> Result<(), SessionError> {
+        let store = self.open_store()?;
 
-        let session = db
+        let session = store
             .get_session(token)
-            .map_err(|e| SessionError::Internal(format!("Database error: {e}")))?
+            .map_err(storage_error)?
             .ok_or(SessionError::NotFound)?;
 
         if session.status != "active" {
             return Err(SessionError::Closed);
         }
 
-        let closed = db
+        let closed = store
             .close_session(token, creator_secret)
-            .map_err(|e| SessionError::Internal(format!("Database error: {e}")))?;
+            .map_err(storage_error)?;
 
         if !closed {
             return Err(SessionError::Forbidden);
@@ -119,52 +189,138 @@ impl SessionManager {
 
         // Wake connected clients so they can notify the user and close. `send`
         // errors only when nobody is listening, which is fine.
-        if let Some(entry) = self.sessions.remove(token) {
+        if let Some(entry) = lock(&self.sessions).remove(token) {
             let _ = entry.shutdown.send(());
         }
 
         Ok(())
     }
 
-    pub fn get_session(&self, token: &str) -> Result<crate::persistence::Session, SessionError> {
-        let db = DocumentDB::new(&self.db_path)
-            .map_err(|e| SessionError::Internal(format!("Failed to open database: {e}")))?;
+    pub fn get_session(&self, token: &str) -> Result<Session, SessionError> {
+        self.open_store()?
+            .get_session(token)
+            .map_err(storage_error)?
+            .ok_or(SessionError::NotFound)
+    }
+
+    /// The history of document `name`.
+    pub fn list_revisions(&self, name: &str) -> Result<Vec<Revision>, SessionError> {
+        let store = self.open_store()?;
+        if store.load_document(name).map_err(storage_error)?.is_none() {
+            return Err(SessionError::NotFound);
+        }
+        store.list_revisions(name).map_err(storage_error)
+    }
 
-        db.get_session(token)
-            .map_err(|e| SessionError::Internal(format!("Database error: {e}")))?
+    /// Version `version` of document `name`.
+    pub fn load_document_at(&self, name: &str, version: u64) -> Result<Document, SessionError> {
+        self.open_store()?
+            .load_document_at(name, version)
+            .map_err(storage_error)?
             .ok_or(SessionError::NotFound)
     }
 
-    pub async fn cleanup_idle_sessions(&mut self, timeout: Duration) {
-        let now = Instant::now();
-        let mut to_remove = Vec::new();
+    /// The latest text of document `name`, split by who last wrote it.
+    pub fn blame(&self, name: &str) -> Result<Vec<BlameSpan>, SessionError> {
+        self.open_store()?
+            .blame(name)
+            .map_err(storage_error)?
+            .ok_or(SessionError::NotFound)
+    }
 
-        for (token, entry) in &self.sessions {
-            if now.duration_since(entry.last_active) > timeout {
-                let server = entry.server.lock().await;
-                if server.get_connected_clients().is_empty() {
-                    to_remove.push(token.clone());
+    /// Make version `version` of document `name` current again, through the
+    /// server serving it if there is one, so its clients receive the change.
+    pub async fn restore_document(
+        &self,
+        name: &str,
+        version: u64,
+    ) -> Result<Document, SessionError> {
+        self.load_document_at(name, version)?;
+        let server = self
+            .servers()
+            .into_iter()
+            .find(|s| s.document_name() == name);
+        if let Some(server) = server {
+            return server
+                .call(move |server| server.restore_version(version))
+                .await
+                .and_then(|restored| restored)
+                .map_err(SessionError::Internal);
+        }
+        self.open_store()?
+            .restore_document(name, version)
+            .map_err(storage_error)?
+            .ok_or(SessionError::NotFound)
+    }
+
+    pub async fn cleanup_idle_sessions(&self, timeout: Duration) {
+        let idle_since = |entry: &SessionEntry| entry.last_active.elapsed() > timeout;
+        let candidates: Vec<(String, DocumentHandle)> = lock(&self.sessions)
+            .iter()
+            .filter(|(_, entry)| idle_since(entry))
+            .map(|(token, entry)| (token.clone(), entry.server.clone()))
+            .collect();
+
+        for (token, server) in candidates {
+            let idle = server
+                .call(|server| server.get_connected_clients().is_empty())
+                .await
+                .unwrap_or(true);
+            if idle {
+                // Skip sessions someone joined while we were asking.
+                let mut sessions = lock(&self.sessions);
+                if sessions.get(&token).is_some_and(idle_since) {
+                    sessions.remove(&token);
                 }
             }
         }
+    }
 
-        for token in to_remove {
-            self.sessions.remove(&token);
+    pub async fn cleanup_stale_clients(&self, timeout_secs: u64) {
+        for server in self.servers() {
+            let _ = server
+                .call(move |server| server.cleanup_stale_clients(timeout_secs))
+                .await;
         }
     }
 
-    pub async fn cleanup_stale_clients(&mut self, timeout_secs: u64) {
-        for entry in self.sessions.values() {
-            entry
-                .server
-                .lock()
-                .await
-                .cleanup_stale_clients(timeout_secs);
-        }
-        if let Some(ref server) = self.default_server {
-            server.lock().await.cleanup_stale_clients(timeout_secs);
+    /// Write every running server's document back to storage, for
+    /// shutdown. Returns the errors of those that failed.
+    pub async fn flush_all(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for server in self.servers() {
+            match server.call(|server| server.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) | Err(e) => errors.push(e),
+            }
         }
+        errors
     }
+
+    fn open_store(&self) -> Result<Box<dyn DocumentStore>, SessionError> {
+        self.storage
+            .open()
+            .map_err(|e| SessionError::Internal(format!("Failed to open storage: {e}")))
+    }
+
+    /// Handles to every running server, taken so they can be called without
+    /// holding the session table.
+    fn servers(&self) -> Vec<DocumentHandle> {
+        let mut servers: Vec<DocumentHandle> = lock(&self.sessions)
+            .values()
+            .map(|entry| entry.server.clone())
+            .collect();
+        servers.extend(lock(&self.default_server).clone());
+        servers
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn storage_error(e: StoreError) -> SessionError {
+    SessionError::Internal(format!("Storage error: {e}"))
 }
 
 #[derive(Debug)]
@@ -194,7 +350,7 @@ fn generate_token() -> String {
 fn base64url_encode(input: &[u8]) -> String {
     const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
 
-    let mut result = String::with_capacity((input.len() * 4 + 2) / 3);
+    let mut result = String::with_capacity((input.len() * 4).div_ceil(3));
     for chunk in input.chunks(3) {
         let b0 = chunk[0] as u32;
         let b1 = chunk.get(1).copied().unwrap_or(0) as u32;
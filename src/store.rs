@@ -0,0 +1,1055 @@
+use crate::{checksum, diff, patch, Document, EditList};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+    pub document_name: String,
+    pub status: String,
+    pub created_at: i64,
+    pub closed_at: Option<i64>,
+}
+
+/// One entry in a document's history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub version: u64,
+    /// Client whose edits produced this revision; `None` for the initial
+    /// content and for changes made outside a sync, such as restores.
+    pub author: Option<String>,
+    /// Whether the full content is stored with this revision.
+    pub snapshot: bool,
+    pub created_at: i64,
+}
+
+/// A range of a document's current text and the revision that last wrote
+/// it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlameSpan {
+    /// Byte offsets into the current text.
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    pub author: Option<String>,
+    pub version: u64,
+    pub created_at: i64,
+}
+
+/// A revision as a [`DocumentStore`] keeps it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRevision {
+    pub version: u64,
+    /// Edits from the previous version to this one.
+    pub edits: EditList,
+    /// Full content, kept every [`SNAPSHOT_INTERVAL`] versions.
+    pub snapshot: Option<String>,
+    pub author: Option<String>,
+    pub created_at: i64,
+}
+
+pub(crate) const DEFAULT_CONTENT: &str = "Welcome to collaborative editing with persistence!";
+
+/// Revisions between full snapshots in a document's history.
+pub const SNAPSHOT_INTERVAL: u64 = 100;
+
+#[derive(Debug)]
+pub enum StoreError {
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+    Io(io::Error),
+    /// Stored data that cannot be read back.
+    Corrupt(String),
+    /// A document name the store cannot hold.
+    InvalidName(String),
+    NotFound(String),
+    AlreadyExists(String),
+    /// A revision of the document at this version is already recorded.
+    RevisionExists(String, u64),
+    /// A database written by a newer build, at this schema version.
+    #[cfg(feature = "sqlite")]
+    UnsupportedSchema(u32),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(e) => write!(f, "{e}"),
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Corrupt(e) => write!(f, "Corrupt data: {e}"),
+            Self::InvalidName(name) => write!(f, "Invalid document name '{name}'"),
+            Self::NotFound(name) => write!(f, "Document '{name}' not found"),
+            Self::AlreadyExists(name) => write!(f, "Document '{name}' already exists"),
+            Self::RevisionExists(name, version) => {
+                write!(f, "Revision {version} of '{name}' already exists")
+            }
+            #[cfg(feature = "sqlite")]
+            Self::UnsupportedSchema(version) => write!(
+                f,
+                "Database schema version {version} is newer than this build supports ({})",
+                crate::SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Corrupt(e.to_string())
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Where documents, their histories and sessions are kept.
+///
+/// Implementations provide storage primitives; history, restores and blame
+/// are built on top of them here. Each document is written by one server at
+/// a time, which appends its revisions in version order.
+pub trait DocumentStore: Send {
+    /// The stored document `name`, which may trail its latest revision.
+    fn load_document(&self, name: &str) -> StoreResult<Option<Document>>;
+
+    /// Write `document` back unless a newer version is already stored.
+    fn flush_document(&self, name: &str, document: &Document) -> StoreResult<()>;
+
+    /// Add `revision` to the history of `name`, failing with
+    /// [`StoreError::RevisionExists`] if its version is already there.
+    fn insert_revision(&self, name: &str, revision: &StoredRevision) -> StoreResult<()>;
+
+    /// Revisions `from` to `to` (both inclusive) of `name`, oldest first.
+    fn revisions_between(&self, name: &str, from: u64, to: u64)
+        -> StoreResult<Vec<StoredRevision>>;
+
+    /// The latest version of `name` up to `version` stored with its full
+    /// content.
+    fn last_snapshot(&self, name: &str, version: u64) -> StoreResult<Option<u64>>;
+
+    /// Create the document `session_{token}` holding `initial_content`, and an
+    /// active session on it.
+    fn create_session(
+        &self,
+        token: &str,
+        creator_secret: &str,
+        initial_content: &str,
+    ) -> StoreResult<()>;
+
+    fn get_session(&self, token: &str) -> StoreResult<Option<Session>>;
+
+    /// Close an active session if `creator_secret` matches. Returns whether
+    /// it was closed.
+    fn close_session(&self, token: &str, creator_secret: &str) -> StoreResult<bool>;
+
+    fn is_session_active(&self, token: &str) -> StoreResult<bool> {
+        Ok(self
+            .get_session(token)?
+            .is_some_and(|session| session.status == "active"))
+    }
+
+    /// Record revision `version` of a document: the `edits` by `author` that
+    /// took the previous version to `content`. Every [`SNAPSHOT_INTERVAL`]
+    /// versions, and for a document's first revision, `content` is stored as
+    /// well.
+    fn append_revision(
+        &self,
+        name: &str,
+        version: u64,
+        edits: &EditList,
+        content: &str,
+        author: Option<&str>,
+    ) -> StoreResult<()> {
+        let snapshot = match self.last_snapshot(name, u64::MAX)? {
+            Some(last) if version < last + SNAPSHOT_INTERVAL => None,
+            _ => Some(content.to_string()),
+        };
+        self.insert_revision(
+            name,
+            &StoredRevision {
+                version,
+                edits: edits.clone(),
+                snapshot,
+                author: author.map(str::to_string),
+                created_at: current_timestamp(),
+            },
+        )
+    }
+
+    /// Revisions of `name`, oldest first.
+    fn list_revisions(&self, name: &str) -> StoreResult<Vec<Revision>> {
+        Ok(self
+            .revisions_between(name, 0, u64::MAX)?
+            .into_iter()
+            .map(|revision| Revision {
+                version: revision.version,
+                author: revision.author,
+                snapshot: revision.snapshot.is_some(),
+                created_at: revision.created_at,
+            })
+            .collect())
+    }
+
+    /// Version `version` of `name`, rebuilt from the nearest snapshot at or
+    /// before it. `None` if the history does not reach that version.
+    fn load_document_at(&self, name: &str, version: u64) -> StoreResult<Option<Document>> {
+        let Some(from) = self.last_snapshot(name, version)? else {
+            return Ok(None);
+        };
+        let mut revisions = self.revisions_between(name, from, version)?.into_iter();
+        let Some(snapshot) = revisions.next() else {
+            return Ok(None);
+        };
+        let start =
+            Document::new_with_version(snapshot.snapshot.unwrap_or_default(), snapshot.version);
+        let document = replay(start, revisions)?;
+        Ok((document.version == version).then_some(document))
+    }
+
+    /// Load a document with any revisions recorded after it was last written
+    /// replayed on top, writing the result back if there were some.
+    fn recover_document(&self, name: &str) -> StoreResult<Option<Document>> {
+        let Some(document) = self.load_document(name)? else {
+            return Ok(None);
+        };
+        let revisions = self.revisions_between(name, document.version + 1, u64::MAX)?;
+        if revisions.is_empty() {
+            return Ok(Some(document));
+        }
+        let document = replay(document, revisions)?;
+        self.flush_document(name, &document)?;
+        Ok(Some(document))
+    }
+
+    /// Replace the content of `name` as a new revision.
+    fn update_document(&self, name: &str, new_content: String) -> StoreResult<Document> {
+        let current = self
+            .recover_document(name)?
+            .ok_or_else(|| StoreError::NotFound(name.to_string()))?;
+        let updated = Document::new_with_version(new_content, current.version + 1);
+        let edits = diff(&current.content, &updated.content);
+        self.append_revision(name, updated.version, &edits, &updated.content, None)?;
+        self.flush_document(name, &updated)?;
+        Ok(updated)
+    }
+
+    /// Make version `version` of `name` current again. The restore is itself
+    /// recorded as a new revision, which is returned. `None` if the history
+    /// does not reach that version.
+    ///
+    /// Only for documents no `SyncServer` is serving; a running server
+    /// restores through `SyncServer::restore_version`.
+    fn restore_document(&self, name: &str, version: u64) -> StoreResult<Option<Document>> {
+        let (Some(old), Some(current)) = (
+            self.load_document_at(name, version)?,
+            self.recover_document(name)?,
+        ) else {
+            return Ok(None);
+        };
+        let restored = Document::new_with_version(old.content, current.version + 1);
+        let edits = diff(&current.content, &restored.content);
+        self.append_revision(name, restored.version, &edits, &restored.content, None)?;
+        self.flush_document(name, &restored)?;
+        Ok(Some(restored))
+    }
+
+    /// Split the latest text of `name` into spans, each attributed to the
+    /// revision that last wrote it. Covers the history from its first
+    /// snapshot; `None` if there is none.
+    fn blame(&self, name: &str) -> StoreResult<Option<Vec<BlameSpan>>> {
+        let mut revisions = self.revisions_between(name, 0, u64::MAX)?;
+        let Some(first) = revisions.iter().position(|r| r.snapshot.is_some()) else {
+            return Ok(None);
+        };
+        revisions.drain(..first);
+
+        // Owned by indices into `revisions`.
+        let mut text = revisions[0].snapshot.clone().unwrap_or_default();
+        let mut spans = vec![(text.len(), 0)];
+        for (index, revision) in revisions.iter().enumerate().skip(1) {
+            text = patch_stored(&text, &revision.edits)?;
+            // Back to front, so each edit's source offsets are still valid.
+            for edit in revision.edits.edits.iter().rev() {
+                let (pos, old_len, new_text) = edit.parts();
+                let (mut head, tail) = split_spans(spans, pos);
+                let (_, rest) = split_spans(tail, old_len);
+                head.push((new_text.len(), index));
+                head.extend(rest);
+                spans = head;
+            }
+        }
+
+        let mut blame: Vec<BlameSpan> = Vec::new();
+        let mut start = 0;
+        for (len, index) in spans.into_iter().filter(|&(len, _)| len > 0) {
+            let end = start + len;
+            let revision = &revisions[index];
+            match blame.last_mut() {
+                Some(last) if last.version == revision.version => {
+                    last.end = end;
+                    last.text.push_str(&text[start..end]);
+                }
+                _ => blame.push(BlameSpan {
+                    start,
+                    end,
+                    text: text[start..end].to_string(),
+                    author: revision.author.clone(),
+                    version: revision.version,
+                    created_at: revision.created_at,
+                }),
+            }
+            start = end;
+        }
+        Ok(Some(blame))
+    }
+}
+
+/// Replay `revisions` onto `document`, which must be at the version before
+/// the first of them.
+fn replay(
+    mut document: Document,
+    revisions: impl IntoIterator<Item = StoredRevision>,
+) -> StoreResult<Document> {
+    for revision in revisions {
+        let content = match revision.snapshot {
+            Some(content) => content,
+            None => patch_stored(&document.content, &revision.edits)?,
+        };
+        document = Document::new_with_version(content, revision.version);
+    }
+    Ok(document)
+}
+
+/// Apply stored revision edits, which must fit `text` exactly.
+fn patch_stored(text: &str, edits: &EditList) -> StoreResult<String> {
+    patch(text, edits).map_err(|e| StoreError::Corrupt(e.to_string()))
+}
+
+/// `(byte length, owner)` runs covering a text in order.
+type Spans = Vec<(usize, usize)>;
+
+/// Split `spans` at byte offset `at`.
+fn split_spans(spans: Spans, at: usize) -> (Spans, Spans) {
+    let mut head = Vec::new();
+    let mut tail = Vec::new();
+    let mut offset = 0;
+    for (len, owner) in spans {
+        if offset + len <= at {
+            head.push((len, owner));
+        } else if offset >= at {
+            tail.push((len, owner));
+        } else {
+            head.push((at - offset, owner));
+            tail.push((offset + len - at, owner));
+        }
+        offset += len;
+    }
+    (head, tail)
+}
+
+pub(crate) fn current_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before UNIX epoch")
+        .as_secs() as i64
+}
+
+/// Keeps everything in process memory, with no SQLite or files involved.
+/// Clones share the same data.
+#[derive(Clone)]
+pub struct MemoryStore {
+    data: Arc<Mutex<MemoryData>>,
+}
+
+#[derive(Default)]
+struct MemoryData {
+    documents: HashMap<String, Document>,
+    revisions: HashMap<String, Vec<StoredRevision>>,
+    /// Sessions by token, with their creator's secret.
+    sessions: HashMap<String, (Session, String)>,
+}
+
+impl MemoryStore {
+    /// A store holding only the default document.
+    pub fn new() -> Self {
+        let store = Self {
+            data: Arc::default(),
+        };
+        store
+            .create_document("main", DEFAULT_CONTENT)
+            .expect("empty memory store accepts a document");
+        store
+    }
+
+    fn data(&self) -> MutexGuard<'_, MemoryData> {
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn create_document(&self, name: &str, content: &str) -> StoreResult<()> {
+        {
+            let mut data = self.data();
+            if data.documents.contains_key(name) {
+                return Err(StoreError::AlreadyExists(name.to_string()));
+            }
+            data.documents
+                .insert(name.to_string(), Document::new(content.to_string()));
+        }
+        self.append_revision(name, 0, &EditList::default(), content, None)
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DocumentStore for MemoryStore {
+    fn load_document(&self, name: &str) -> StoreResult<Option<Document>> {
+        Ok(self.data().documents.get(name).cloned())
+    }
+
+    fn flush_document(&self, name: &str, document: &Document) -> StoreResult<()> {
+        let mut data = self.data();
+        match data.documents.get(name) {
+            Some(stored) if stored.version >= document.version => {}
+            _ => {
+                data.documents.insert(name.to_string(), document.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_revision(&self, name: &str, revision: &StoredRevision) -> StoreResult<()> {
+        let mut data = self.data();
+        let revisions = data.revisions.entry(name.to_string()).or_default();
+        match revisions.binary_search_by_key(&revision.version, |r| r.version) {
+            Ok(_) => Err(StoreError::RevisionExists(
+                name.to_string(),
+                revision.version,
+            )),
+            Err(index) => {
+                revisions.insert(index, revision.clone());
+                Ok(())
+            }
+        }
+    }
+
+    fn revisions_between(
+        &self,
+        name: &str,
+        from: u64,
+        to: u64,
+    ) -> StoreResult<Vec<StoredRevision>> {
+        Ok(self
+            .data()
+            .revisions
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|r| (from..=to).contains(&r.version))
+            .cloned()
+            .collect())
+    }
+
+    fn last_snapshot(&self, name: &str, version: u64) -> StoreResult<Option<u64>> {
+        Ok(self
+            .data()
+            .revisions
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|r| r.version <= version && r.snapshot.is_some())
+            .map(|r| r.version)
+            .max())
+    }
+
+    fn create_session(
+        &self,
+        token: &str,
+        creator_secret: &str,
+        initial_content: &str,
+    ) -> StoreResult<()> {
+        let document_name = format!("session_{token}");
+        self.create_document(&document_name, initial_content)?;
+        let session = Session {
+            token: token.to_string(),
+            document_name,
+            status: "active".to_string(),
+            created_at: current_timestamp(),
+            closed_at: None,
+        };
+        self.data()
+            .sessions
+            .insert(token.to_string(), (session, creator_secret.to_string()));
+        Ok(())
+    }
+
+    fn get_session(&self, token: &str) -> StoreResult<Option<Session>> {
+        Ok(self
+            .data()
+            .sessions
+            .get(token)
+            .map(|(session, _)| session.clone()))
+    }
+
+    fn close_session(&self, token: &str, creator_secret: &str) -> StoreResult<bool> {
+        let mut data = self.data();
+        let Some((session, secret)) = data.sessions.get_mut(token) else {
+            return Ok(false);
+        };
+        if secret != creator_secret || session.status != "active" {
+            return Ok(false);
+        }
+        session.status = "closed".to_string();
+        session.closed_at = Some(current_timestamp());
+        Ok(true)
+    }
+}
+
+/// Keeps each document as plain files in a directory: `documents/<name>.txt`
+/// holds its text, the `<name>.json` sidecar its version, and
+/// `<name>.history.jsonl` its revisions, one per line. Sessions are kept in
+/// `sessions.json`.
+pub struct FileStore {
+    dir: PathBuf,
+    /// Histories read so far, by document name.
+    histories: Mutex<HashMap<String, History>>,
+}
+
+/// The parsed start of a history file. The file is only ever appended to,
+/// so a later read parses just the lines added since.
+#[derive(Default)]
+struct History {
+    /// Bytes of the file parsed so far.
+    len: u64,
+    revisions: Vec<StoredRevision>,
+}
+
+/// The `<name>.json` sidecar of a [`FileStore`] document.
+#[derive(Default, Serialize, Deserialize)]
+struct Sidecar {
+    version: u64,
+    /// Of the text file, which a crash may leave out of step with this.
+    checksum: String,
+    created_at: i64,
+    updated_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionRecord {
+    #[serde(flatten)]
+    session: Session,
+    creator_secret: String,
+}
+
+impl FileStore {
+    /// Open the store in `dir`, creating it with the default document if
+    /// needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> StoreResult<Self> {
+        let store = Self {
+            dir: dir.as_ref().to_path_buf(),
+            histories: Mutex::default(),
+        };
+        fs::create_dir_all(store.dir.join("documents"))?;
+        match store.create_document("main", DEFAULT_CONTENT) {
+            Ok(()) | Err(StoreError::AlreadyExists(_)) => Ok(store),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Path of `name`'s file with `extension`. Names are limited to ASCII
+    /// letters, digits, `_` and `-`, so they stay inside the store.
+    fn path(&self, name: &str, extension: &str) -> StoreResult<PathBuf> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            return Err(StoreError::InvalidName(name.to_string()));
+        }
+        Ok(self
+            .dir
+            .join("documents")
+            .join(format!("{name}.{extension}")))
+    }
+
+    fn read_sidecar(&self, name: &str) -> StoreResult<Option<Sidecar>> {
+        match read_optional(&self.path(name, "json")?)? {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write_sidecar(&self, name: &str, sidecar: &Sidecar) -> StoreResult<()> {
+        write_atomically(
+            &self.path(name, "json")?,
+            &serde_json::to_vec_pretty(sidecar)?,
+        )?;
+        Ok(())
+    }
+
+    /// Write the text first, so the sidecar never describes text that is
+    /// not there yet. The rest of `sidecar` is kept.
+    fn write_document(
+        &self,
+        name: &str,
+        document: &Document,
+        mut sidecar: Sidecar,
+    ) -> StoreResult<()> {
+        write_atomically(&self.path(name, "txt")?, document.content.as_bytes())?;
+        sidecar.version = document.version;
+        sidecar.checksum = checksum(&document.content);
+        sidecar.updated_at = current_timestamp();
+        self.write_sidecar(name, &sidecar)
+    }
+
+    fn create_document(&self, name: &str, content: &str) -> StoreResult<()> {
+        if self.read_sidecar(name)?.is_some() {
+            return Err(StoreError::AlreadyExists(name.to_string()));
+        }
+        self.append_revision(name, 0, &EditList::default(), content, None)?;
+        self.write_document(
+            name,
+            &Document::new(content.to_string()),
+            Sidecar {
+                created_at: current_timestamp(),
+                ..Sidecar::default()
+            },
+        )
+    }
+
+    /// Call `read` with the history of `name`, after parsing whatever was
+    /// appended to its file since the last call. Names the store cannot hold
+    /// have no history.
+    fn with_history<T>(
+        &self,
+        name: &str,
+        read: impl FnOnce(&[StoredRevision]) -> T,
+    ) -> StoreResult<T> {
+        let Ok(path) = self.path(name, "history.jsonl") else {
+            return Ok(read(&[]));
+        };
+        let mut histories = self
+            .histories
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let history = histories.entry(name.to_string()).or_default();
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                *history = History::default();
+                return Ok(read(&[]));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let file_len = file.metadata()?.len();
+        if file_len < history.len {
+            // Replaced rather than appended to: start over.
+            *history = History::default();
+        }
+        if file_len > history.len {
+            file.seek(SeekFrom::Start(history.len))?;
+            let mut added = Vec::new();
+            file.read_to_end(&mut added)?;
+            // A line still being written is left for the next call.
+            let complete = added.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+            let revisions = added[..complete]
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(serde_json::from_slice)
+                .collect::<Result<Vec<StoredRevision>, _>>()?;
+            history.revisions.extend(revisions);
+            history.len += complete as u64;
+        }
+        Ok(read(&history.revisions))
+    }
+
+    fn read_sessions(&self) -> StoreResult<BTreeMap<String, SessionRecord>> {
+        match read_optional(&self.dir.join("sessions.json"))? {
+            Some(json) => Ok(serde_json::from_str(&json)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    fn write_sessions(&self, sessions: &BTreeMap<String, SessionRecord>) -> StoreResult<()> {
+        let json = serde_json::to_vec_pretty(sessions)?;
+        write_atomically(&self.dir.join("sessions.json"), &json)?;
+        Ok(())
+    }
+}
+
+impl DocumentStore for FileStore {
+    fn load_document(&self, name: &str) -> StoreResult<Option<Document>> {
+        let Ok(path) = self.path(name, "txt") else {
+            return Ok(None);
+        };
+        let Some(sidecar) = self.read_sidecar(name)? else {
+            return Ok(None);
+        };
+        let content = read_optional(&path)?.unwrap_or_default();
+        if checksum(&content) == sidecar.checksum {
+            return Ok(Some(Document::new_with_version(content, sidecar.version)));
+        }
+        // Interrupted mid write-back: rebuild the version the sidecar names.
+        self.load_document_at(name, sidecar.version)?
+            .map(Some)
+            .ok_or_else(|| StoreError::Corrupt(format!("'{name}' does not match its history")))
+    }
+
+    fn flush_document(&self, name: &str, document: &Document) -> StoreResult<()> {
+        let sidecar = match self.read_sidecar(name)? {
+            Some(stored) if stored.version >= document.version => return Ok(()),
+            Some(stored) => stored,
+            None => Sidecar {
+                created_at: current_timestamp(),
+                ..Sidecar::default()
+            },
+        };
+        self.write_document(name, document, sidecar)
+    }
+
+    /// Revisions are appended in version order, so any version not after
+    /// the latest one is refused, not just a repeated one.
+    fn insert_revision(&self, name: &str, revision: &StoredRevision) -> StoreResult<()> {
+        let latest = self.with_history(name, |revisions| revisions.last().map(|r| r.version))?;
+        if latest.is_some_and(|latest| revision.version <= latest) {
+            return Err(StoreError::RevisionExists(
+                name.to_string(),
+                revision.version,
+            ));
+        }
+
+        let mut line = serde_json::to_vec(revision)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(name, "history.jsonl")?)?;
+        file.write_all(&line)?;
+        // The revision is what a client's acknowledgement rests on.
+        file.sync_data()?;
+        Ok(())
+    }
+
+    fn revisions_between(
+        &self,
+        name: &str,
+        from: u64,
+        to: u64,
+    ) -> StoreResult<Vec<StoredRevision>> {
+        self.with_history(name, |revisions| {
+            revisions
+                .iter()
+                .filter(|r| (from..=to).contains(&r.version))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn last_snapshot(&self, name: &str, version: u64) -> StoreResult<Option<u64>> {
+        self.with_history(name, |revisions| {
+            revisions
+                .iter()
+                .rev()
+                .find(|r| r.version <= version && r.snapshot.is_some())
+                .map(|r| r.version)
+        })
+    }
+
+    fn create_session(
+        &self,
+        token: &str,
+        creator_secret: &str,
+        initial_content: &str,
+    ) -> StoreResult<()> {
+        let document_name = format!("session_{token}");
+        self.create_document(&document_name, initial_content)?;
+        let mut sessions = self.read_sessions()?;
+        sessions.insert(
+            token.to_string(),
+            SessionRecord {
+                session: Session {
+                    token: token.to_string(),
+                    document_name,
+                    status: "active".to_string(),
+                    created_at: current_timestamp(),
+                    closed_at: None,
+                },
+                creator_secret: creator_secret.to_string(),
+            },
+        );
+        self.write_sessions(&sessions)
+    }
+
+    fn get_session(&self, token: &str) -> StoreResult<Option<Session>> {
+        Ok(self
+            .read_sessions()?
+            .remove(token)
+            .map(|record| record.session))
+    }
+
+    fn close_session(&self, token: &str, creator_secret: &str) -> StoreResult<bool> {
+        let mut sessions = self.read_sessions()?;
+        let Some(record) = sessions.get_mut(token) else {
+            return Ok(false);
+        };
+        if record.creator_secret != creator_secret || record.session.status != "active" {
+            return Ok(false);
+        }
+        record.session.status = "closed".to_string();
+        record.session.closed_at = Some(current_timestamp());
+        self.write_sessions(&sessions)?;
+        Ok(true)
+    }
+}
+
+/// The contents of `path`, or `None` if it does not exist.
+fn read_optional(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace `path` with `contents` by renaming a temporary file over it, so
+/// readers see either the old or the new contents.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
+
+/// Which [`DocumentStore`] to keep documents in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    Memory,
+    Files,
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite => write!(f, "sqlite"),
+            Self::Memory => write!(f, "memory"),
+            Self::Files => write!(f, "files"),
+        }
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            "files" => Ok(Self::Files),
+            _ => Err(format!(
+                "Unknown storage '{s}' (expected sqlite, memory or files)"
+            )),
+        }
+    }
+}
+
+/// A configured store that servers each open their own [`DocumentStore`]
+/// on.
+#[derive(Clone)]
+pub enum Storage {
+    /// A SQLite database file.
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+    /// Shared by every store opened on it, and gone with the process.
+    Memory(MemoryStore),
+    /// A [`FileStore`] directory.
+    Files(PathBuf),
+}
+
+impl Storage {
+    /// `path` is the database file or store directory; memory storage
+    /// ignores it.
+    pub fn new<P: Into<PathBuf>>(kind: StorageKind, path: P) -> Self {
+        match kind {
+            #[cfg(feature = "sqlite")]
+            StorageKind::Sqlite => Self::Sqlite(path.into()),
+            StorageKind::Memory => Self::Memory(MemoryStore::new()),
+            StorageKind::Files => Self::Files(path.into()),
+        }
+    }
+
+    pub fn open(&self) -> StoreResult<Box<dyn DocumentStore>> {
+        Ok(match self {
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(path) => Box::new(crate::DocumentDB::new(path)?),
+            Self::Memory(store) => Box::new(store.clone()),
+            Self::Files(dir) => Box::new(FileStore::new(dir)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store_shares_documents_and_history_between_clones() {
+        let store = MemoryStore::new();
+        let other = store.clone();
+        store.update_document("main", "one".to_string()).unwrap();
+        store
+            .update_document("main", "one two".to_string())
+            .unwrap();
+
+        assert_eq!(
+            other.load_document("main").unwrap(),
+            Some(Document::new_with_version("one two".to_string(), 2))
+        );
+        assert_eq!(
+            other.load_document_at("main", 0).unwrap().unwrap().content,
+            DEFAULT_CONTENT
+        );
+        assert_eq!(other.list_revisions("main").unwrap().len(), 3);
+
+        other.create_session("tok", "secret", "hi").unwrap();
+        assert!(store.is_session_active("tok").unwrap());
+        assert!(!store.close_session("tok", "wrong").unwrap());
+        assert!(store.close_session("tok", "secret").unwrap());
+        assert!(!other.is_session_active("tok").unwrap());
+    }
+
+    #[test]
+    fn test_file_store_reopens_and_survives_an_interrupted_write_back() {
+        let dir = std::env::temp_dir().join(format!("diff-sync-files-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = FileStore::new(&dir).unwrap();
+        store.update_document("main", "one".to_string()).unwrap();
+        store.create_session("tok", "secret", "hi").unwrap();
+        store
+            .append_revision("main", 2, &diff("one", "one two"), "one two", Some("a"))
+            .unwrap();
+        drop(store);
+
+        let store = FileStore::new(&dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("documents/main.txt")).unwrap(),
+            "one"
+        );
+        let recovered = store.recover_document("main").unwrap().unwrap();
+        assert_eq!(
+            recovered,
+            Document::new_with_version("one two".to_string(), 2)
+        );
+        assert_eq!(
+            store.get_session("tok").unwrap().unwrap().document_name,
+            "session_tok"
+        );
+        assert_eq!(
+            store.load_document("session_tok").unwrap().unwrap().content,
+            "hi"
+        );
+
+        // Text written but the sidecar not yet: the sidecar's version wins.
+        fs::write(dir.join("documents/main.txt"), "one two three").unwrap();
+        assert_eq!(store.load_document("main").unwrap(), Some(recovered));
+
+        // Names cannot reach outside the store.
+        assert!(store.load_document("../main").unwrap().is_none());
+        assert!(store
+            .flush_document("../escape", &Document::new(String::new()))
+            .is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_file_store_reads_appends_from_other_stores_and_reports_bad_history() {
+        let dir = std::env::temp_dir().join(format!("diff-sync-tail-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileStore::new(&dir).unwrap();
+        let other = FileStore::new(&dir).unwrap();
+        assert_eq!(store.list_revisions("main").unwrap().len(), 1);
+
+        // Appended behind the first store's back: it still sees the version.
+        other
+            .append_revision("main", 1, &diff(DEFAULT_CONTENT, "one"), "one", None)
+            .unwrap();
+        assert!(matches!(
+            store.append_revision("main", 1, &diff(DEFAULT_CONTENT, "two"), "two", None),
+            Err(StoreError::RevisionExists(_, 1))
+        ));
+        assert_eq!(
+            store.load_document_at("main", 1).unwrap().unwrap().content,
+            "one"
+        );
+
+        let mut history = OpenOptions::new()
+            .append(true)
+            .open(dir.join("documents/main.history.jsonl"))
+            .unwrap();
+        history.write_all(b"not a revision\n").unwrap();
+        assert!(matches!(
+            store.last_snapshot("main", u64::MAX),
+            Err(StoreError::Corrupt(_))
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_every_store_refuses_a_repeated_revision() {
+        let dir = std::env::temp_dir().join(format!("diff-sync-repeat-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let stores: Vec<Box<dyn DocumentStore>> = vec![
+            Box::new(MemoryStore::new()),
+            Box::new(FileStore::new(dir.join("files")).unwrap()),
+            #[cfg(feature = "sqlite")]
+            Box::new(crate::DocumentDB::new_in_memory().unwrap()),
+        ];
+        for store in stores {
+            store.update_document("main", "one".to_string()).unwrap();
+            let repeated = store.append_revision("main", 1, &diff("", "two"), "two", None);
+            assert!(matches!(
+                repeated,
+                Err(StoreError::RevisionExists(name, 1)) if name == "main"
+            ));
+            store
+                .append_revision("main", 2, &diff("one", "one two"), "one two", None)
+                .unwrap();
+            assert_eq!(store.list_revisions("main").unwrap().len(), 3);
+            assert_eq!(store.last_snapshot("main", u64::MAX).unwrap(), Some(0));
+            assert_eq!(
+                store.recover_document("main").unwrap().unwrap().content,
+                "one two"
+            );
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
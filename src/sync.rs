@@ -1,23 +1,99 @@
-use crate::{diff, patch, Document, EditList, PatchError};
+use crate::{
+    apply_hunks, checksum_with, diff_with_report, make_hunks, patch, verify_checksum, DiffCleanup,
+    DiffOptions, Document, EditList, HashAlgorithm, OffsetUnit, PatchError, PatchOptions,
+    PatchReport,
+};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::time::Duration;
 
 /// Core differential synchronization engine.
 ///
 /// Maintains three copies of the document per Neil Fraser's algorithm:
 /// - **document**: the live working copy that users edit
 /// - **shadow**: last state agreed upon with the remote peer
-/// - **backup_shadow** (optional): safety net for guaranteed delivery on the server side
+/// - **backup_shadow** (optional): safety net for guaranteed delivery
 ///
 /// A sync cycle diffs document against shadow to produce outgoing edits, then
 /// applies incoming edits to both shadow and document.
+///
+/// For lossy or reordering transports, [`make_packet`](Self::make_packet) and
+/// [`receive_packet`](Self::receive_packet) implement Fraser's guaranteed
+/// delivery variant: outgoing edits are tagged with shadow versions and kept
+/// on a stack until the peer acknowledges them.
 #[derive(Debug, Clone)]
 pub struct SyncEngine {
     pub document: Document,
     shadow: Document,
-    backup_shadow: Option<Document>,
-    pending_edits: Vec<EditList>,
+    /// Number of our own edit lists folded into the shadow.
+    local_version: u64,
+    /// Number of the peer's edit lists folded into the shadow.
+    remote_version: u64,
+    backup_shadow: Option<ShadowBackup>,
+    /// Sent edit lists the peer has not acknowledged yet, oldest first.
+    pending_edits: Vec<VersionedEdits>,
+    /// The shadow each pending edit list was diffed against, so a resync can
+    /// re-apply edits the peer never took in.
+    pending_bases: Vec<Document>,
     pub node_id: String,
+    /// Matching tolerances used when incoming edits are fuzzy-patched into a
+    /// document that has diverged from the shadow.
+    pub patch_options: PatchOptions,
+    /// How local changes are diffed against the shadow. Defaults to char
+    /// mode with an efficiency cleanup, so scattered one-char equalities do
+    /// not split a change into many tiny edits, and a deadline of
+    /// [`DEFAULT_DIFF_DEADLINE`] so a huge paste cannot stall the caller.
+    pub diff_options: DiffOptions,
+    /// Hash used for the shadow checksum attached to outgoing edits.
+    pub hash_algorithm: HashAlgorithm,
+    /// Unit of the positions in edit lists exchanged with the peer. The
+    /// engine works in bytes and converts at the boundary.
+    pub offset_unit: OffsetUnit,
+    /// Verify each incoming edit list's checksum against the shadow before
+    /// applying it, instead of patching a shadow that may have diverged.
+    pub strict_checksums: bool,
+    /// Incoming edits that could not be placed in the document so far.
+    rejected_edits: u64,
+    checksum_mismatches: u64,
+    /// Diffs cut short by `diff_options.deadline`.
+    deadline_hits: u64,
+}
+
+/// Copy of the shadow and its versions as of the last packet received.
+#[derive(Debug, Clone)]
+struct ShadowBackup {
+    shadow: Document,
+    local_version: u64,
+    remote_version: u64,
+}
+
+/// An edit list tagged with the sender's version number for it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionedEdits {
+    pub version: u64,
+    pub edits: EditList,
+}
+
+/// One guaranteed-delivery message: every unacknowledged edit list, plus how
+/// many of the receiver's edit lists the sender has applied.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncPacket {
+    pub ack_version: u64,
+    pub edits: Vec<VersionedEdits>,
+}
+
+/// Time budget for diffing local changes in a [`SyncEngine`].
+pub const DEFAULT_DIFF_DEADLINE: Duration = Duration::from_millis(100);
+
+/// Diff options a [`SyncEngine`] starts with: char mode with an efficiency
+/// cleanup, grapheme-safe, and bounded by [`DEFAULT_DIFF_DEADLINE`].
+pub fn default_diff_options() -> DiffOptions {
+    DiffOptions {
+        cleanup: DiffCleanup::Efficiency,
+        deadline: Some(DEFAULT_DIFF_DEADLINE),
+        grapheme_safe: true,
+        ..DiffOptions::default()
+    }
 }
 
 /// Result of one direction of a sync cycle.
@@ -27,6 +103,10 @@ pub struct SyncResult {
     pub shadow_checksum: String,
     pub success: bool,
     pub message: Option<String>,
+    /// Indices of edits that were applied to the shadow but could not be
+    /// placed in the receiver's document.
+    #[serde(default)]
+    pub rejected_edits: Vec<usize>,
 }
 
 impl SyncEngine {
@@ -36,9 +116,20 @@ impl SyncEngine {
         Self {
             document,
             shadow,
+            local_version: 0,
+            remote_version: 0,
             backup_shadow: None,
             pending_edits: Vec::new(),
+            pending_bases: Vec::new(),
             node_id: format!("node_{}", rand::random::<u32>()),
+            patch_options: PatchOptions::default(),
+            diff_options: default_diff_options(),
+            hash_algorithm: HashAlgorithm::default(),
+            offset_unit: OffsetUnit::default(),
+            strict_checksums: false,
+            rejected_edits: 0,
+            checksum_mismatches: 0,
+            deadline_hits: 0,
         }
     }
 
@@ -46,13 +137,28 @@ impl SyncEngine {
     pub fn new_server(content: String, node_id: String) -> Self {
         let document = Document::new(content.clone());
         let shadow = Document::new(content.clone());
-        let backup_shadow = Some(Document::new(content));
+        let backup_shadow = Some(ShadowBackup {
+            shadow: Document::new(content),
+            local_version: 0,
+            remote_version: 0,
+        });
         Self {
             document,
             shadow,
+            local_version: 0,
+            remote_version: 0,
             backup_shadow,
             pending_edits: Vec::new(),
+            pending_bases: Vec::new(),
             node_id,
+            patch_options: PatchOptions::default(),
+            diff_options: default_diff_options(),
+            hash_algorithm: HashAlgorithm::default(),
+            offset_unit: OffsetUnit::default(),
+            strict_checksums: false,
+            rejected_edits: 0,
+            checksum_mismatches: 0,
+            deadline_hits: 0,
         }
     }
 
@@ -69,82 +175,284 @@ impl SyncEngine {
     }
 
     pub fn shadow_checksum(&self) -> String {
-        crate::diff::checksum(&self.shadow.content)
+        checksum_with(&self.shadow.content, self.hash_algorithm)
     }
 
     /// Diff document against shadow and advance the shadow to match.
     /// Returns the edits representing local changes since the last sync.
     pub fn diff_and_update_shadow(&mut self) -> EditList {
-        let edits = diff(&self.shadow.content, &self.document.content);
+        let mut edits = self
+            .diff_local_changes()
+            .convert_offsets(&self.shadow.content, OffsetUnit::Bytes, self.offset_unit)
+            .expect("diff edits lie on char boundaries of the shadow");
+        edits.checksum = self.shadow_checksum();
         self.shadow = self.document.clone();
         edits
     }
 
+    fn diff_local_changes(&mut self) -> EditList {
+        let report = diff_with_report(
+            &self.shadow.content,
+            &self.document.content,
+            &self.diff_options,
+        );
+        if report.deadline_hit {
+            self.deadline_hits += 1;
+        }
+        report.edits
+    }
+
     /// Apply incoming edits from a remote peer to both shadow and document.
-    pub fn apply_edits(&mut self, edit_list: EditList) -> Result<(), PatchError> {
+    ///
+    /// The shadow matches the text the edits were computed against, so they
+    /// apply exactly; failing that is an error. The document may carry local
+    /// changes, so the edits are anchored with context from the shadow and
+    /// fuzzy-matched into it. The returned report says how each edit fared in
+    /// the document; rejected edits are dropped from it but not the shadow.
+    ///
+    /// In strict mode the list's checksum must match the shadow; an empty list
+    /// may instead carry no checksum at all. If the checksum matches the
+    /// backup shadow, the peer never saw our last edits, so the shadow is
+    /// rolled back and the edits applied on top of the backup. Otherwise the
+    /// shadows have diverged and `ChecksumMismatch` is returned with nothing
+    /// applied, so the caller can resynchronize.
+    pub fn apply_edits(&mut self, edit_list: EditList) -> Result<PatchReport, PatchError> {
+        // An empty list without a checksum claims nothing about the shadow.
+        let unchecked = edit_list.is_empty() && edit_list.checksum.is_empty();
+        if self.strict_checksums
+            && !unchecked
+            && !verify_checksum(&self.shadow.content, &edit_list.checksum)
+        {
+            self.checksum_mismatches += 1;
+            match &self.backup_shadow {
+                Some(backup) if verify_checksum(&backup.shadow.content, &edit_list.checksum) => {
+                    self.shadow = backup.shadow.clone();
+                }
+                _ => return Err(PatchError::ChecksumMismatch),
+            }
+        }
+
+        self.patch_shadow_and_document(edit_list)
+    }
+
+    fn patch_shadow_and_document(
+        &mut self,
+        edit_list: EditList,
+    ) -> Result<PatchReport, PatchError> {
         if edit_list.is_empty() {
-            return Ok(());
+            return Ok(PatchReport {
+                text: self.document.content.clone(),
+                outcomes: Vec::new(),
+            });
         }
 
+        let edit_list =
+            edit_list.convert_offsets(&self.shadow.content, self.offset_unit, OffsetUnit::Bytes)?;
+        let hunks = make_hunks(&self.shadow.content, &edit_list, &self.patch_options);
         let new_shadow = patch(&self.shadow.content, &edit_list)?;
         self.shadow.update(new_shadow);
 
-        let new_doc = patch(&self.document.content, &edit_list)?;
-        self.document.update(new_doc);
+        let report = apply_hunks(&self.document.content, &hunks, &self.patch_options);
+        self.rejected_edits += report.rejected_count() as u64;
+        self.document.update(report.text.clone());
+
+        Ok(report)
+    }
+
+    /// Diff document against shadow, push any changes onto the pending stack
+    /// and return a packet carrying the whole unacknowledged stack.
+    ///
+    /// Call again to retransmit if no reply arrives; the receiver discards
+    /// edit lists it has already applied.
+    pub fn make_packet(&mut self) -> SyncPacket {
+        let base = self.shadow.clone();
+        let edits = self.diff_and_update_shadow();
+        if !edits.is_empty() {
+            self.pending_bases.push(base);
+            self.pending_edits.push(VersionedEdits {
+                version: self.local_version,
+                edits,
+            });
+            self.local_version += 1;
+        }
+
+        SyncPacket {
+            ack_version: self.remote_version,
+            edits: self.pending_edits.clone(),
+        }
+    }
 
-        Ok(())
+    /// Whether nothing has been sent since the last packet from the peer, so
+    /// a packet sent now unprompted can still be rolled back to the backup if
+    /// it crosses one from the peer.
+    pub fn can_send_unprompted(&self) -> bool {
+        self.backup_shadow
+            .as_ref()
+            .is_some_and(|backup| backup.local_version == self.local_version)
+    }
+
+    /// Process a packet from the peer.
+    ///
+    /// Drops our acknowledged edits from the pending stack. If the peer has
+    /// not seen our latest edits (our previous packet or its reply was lost),
+    /// rolls the shadow back to the backup taken when the peer was last heard
+    /// from; the dropped edits are still in the document and will be diffed
+    /// again. Edit lists already applied are skipped, the rest applied in
+    /// order. The report covers every newly applied edit, in order.
+    ///
+    /// A packet acknowledging only some of our pending edits, such as a
+    /// delayed or retransmitted one, just drops those edits. It must not
+    /// carry new edits: they would be against a shadow we no longer have.
+    pub fn receive_packet(&mut self, packet: SyncPacket) -> Result<PatchReport, PatchError> {
+        if packet.ack_version != self.local_version {
+            match &self.backup_shadow {
+                Some(backup) if backup.local_version == packet.ack_version => {
+                    self.shadow = backup.shadow.clone();
+                    self.local_version = backup.local_version;
+                    self.remote_version = backup.remote_version;
+                    self.pending_edits.clear();
+                    self.pending_bases.clear();
+                }
+                _ if packet.ack_version < self.local_version
+                    && packet
+                        .edits
+                        .iter()
+                        .all(|incoming| incoming.version < self.remote_version) =>
+                {
+                    self.drop_acknowledged(packet.ack_version);
+                    return Ok(PatchReport {
+                        text: self.document.content.clone(),
+                        outcomes: Vec::new(),
+                    });
+                }
+                _ => return Err(PatchError::VersionMismatch),
+            }
+        }
+        self.drop_acknowledged(packet.ack_version);
+
+        let mut report = PatchReport {
+            text: self.document.content.clone(),
+            outcomes: Vec::new(),
+        };
+        for incoming in packet.edits {
+            if incoming.version < self.remote_version {
+                continue;
+            }
+            if incoming.version > self.remote_version {
+                return Err(PatchError::VersionMismatch);
+            }
+            if self.strict_checksums
+                && !verify_checksum(&self.shadow.content, &incoming.edits.checksum)
+            {
+                self.checksum_mismatches += 1;
+                return Err(PatchError::ChecksumMismatch);
+            }
+
+            let applied = self.patch_shadow_and_document(incoming.edits)?;
+            report.text = applied.text;
+            report.outcomes.extend(applied.outcomes);
+            self.remote_version += 1;
+        }
+
+        self.backup_shadow();
+        Ok(report)
+    }
+
+    /// Drop pending edit lists the peer has taken in up to `ack_version`.
+    fn drop_acknowledged(&mut self, ack_version: u64) {
+        let acknowledged = self
+            .pending_edits
+            .iter()
+            .take_while(|pending| pending.version < ack_version)
+            .count();
+        self.pending_edits.drain(..acknowledged);
+        self.pending_bases.drain(..acknowledged);
+    }
+
+    /// Replace both document and shadow with `content` and forget all
+    /// delivery state, as after a fresh connect.
+    pub fn reset(&mut self, content: &str) {
+        self.document.update(content.to_string());
+        self.reset_shadow(content);
+    }
+
+    /// Adopt `content` as the state agreed with the peer after the shadows
+    /// diverged, keeping local edits the peer has not seen yet.
+    ///
+    /// Changes between the document and the shadow before the oldest
+    /// unacknowledged edit list are re-applied on top of `content`, so the
+    /// next diff sends them again. The report says how each of them fared.
+    pub fn resync(&mut self, content: &str) -> PatchReport {
+        let base = self
+            .pending_bases
+            .first()
+            .unwrap_or(&self.shadow)
+            .content
+            .clone();
+        let diff = diff_with_report(&base, &self.document.content, &self.diff_options);
+        if diff.deadline_hit {
+            self.deadline_hits += 1;
+        }
+        let hunks = make_hunks(&base, &diff.edits, &self.patch_options);
+        self.reset_shadow(content);
+
+        let report = apply_hunks(content, &hunks, &self.patch_options);
+        self.document.update(report.text.clone());
+        report
+    }
+
+    fn reset_shadow(&mut self, content: &str) {
+        self.shadow.update(content.to_string());
+        self.local_version = 0;
+        self.remote_version = 0;
+        self.pending_edits.clear();
+        self.pending_bases.clear();
+        self.backup_shadow();
     }
 
     /// Run a full bidirectional sync cycle between `self` and `other`.
     pub fn sync_with(&mut self, other: &mut SyncEngine) -> (SyncResult, SyncResult) {
         let client_edits = self.diff_and_update_shadow();
-        let server_result = match other.apply_edits(client_edits.clone()) {
-            Ok(()) => SyncResult {
-                edits: client_edits,
-                shadow_checksum: other.shadow_checksum(),
-                success: true,
-                message: None,
-            },
-            Err(e) => SyncResult {
-                edits: client_edits,
-                shadow_checksum: other.shadow_checksum(),
-                success: false,
-                message: Some(e.to_string()),
-            },
-        };
+        let server_result = SyncResult::from_apply(
+            client_edits.clone(),
+            other.apply_edits(client_edits),
+            other.shadow_checksum(),
+        );
 
         let server_edits = other.diff_and_update_shadow();
-        let client_result = match self.apply_edits(server_edits.clone()) {
-            Ok(()) => SyncResult {
-                edits: server_edits,
-                shadow_checksum: self.shadow_checksum(),
-                success: true,
-                message: None,
-            },
-            Err(e) => SyncResult {
-                edits: server_edits,
-                shadow_checksum: self.shadow_checksum(),
-                success: false,
-                message: Some(e.to_string()),
-            },
-        };
+        let client_result = SyncResult::from_apply(
+            server_edits.clone(),
+            self.apply_edits(server_edits),
+            self.shadow_checksum(),
+        );
 
         (server_result, client_result)
     }
 
     pub fn backup_shadow(&mut self) {
-        self.backup_shadow = Some(self.shadow.clone());
+        self.backup_shadow = Some(ShadowBackup {
+            shadow: self.shadow.clone(),
+            local_version: self.local_version,
+            remote_version: self.remote_version,
+        });
     }
 
     pub fn restore_shadow(&mut self) -> bool {
         if let Some(backup) = self.backup_shadow.take() {
-            self.shadow = backup;
+            self.shadow = backup.shadow;
+            self.local_version = backup.local_version;
+            self.remote_version = backup.remote_version;
             true
         } else {
             false
         }
     }
 
+    /// Shadow versions as (our edit lists applied, peer's edit lists applied).
+    pub fn shadow_versions(&self) -> (u64, u64) {
+        (self.local_version, self.remote_version)
+    }
+
     pub fn stats(&self) -> SyncStats {
         SyncStats {
             document_version: self.document.version,
@@ -152,6 +460,39 @@ impl SyncEngine {
             shadow_checksum: self.shadow_checksum(),
             has_backup: self.backup_shadow.is_some(),
             pending_edits: self.pending_edits.len(),
+            rejected_edits: self.rejected_edits,
+            checksum_mismatches: self.checksum_mismatches,
+            deadline_hits: self.deadline_hits,
+        }
+    }
+}
+
+impl SyncResult {
+    fn from_apply(
+        edits: EditList,
+        applied: Result<PatchReport, PatchError>,
+        shadow_checksum: String,
+    ) -> Self {
+        match applied {
+            Ok(report) => {
+                let rejected_edits: Vec<usize> = report.rejected().collect();
+                let message = (!rejected_edits.is_empty())
+                    .then(|| format!("{} edits could not be placed", rejected_edits.len()));
+                Self {
+                    edits,
+                    shadow_checksum,
+                    success: true,
+                    message,
+                    rejected_edits,
+                }
+            }
+            Err(e) => Self {
+                edits,
+                shadow_checksum,
+                success: false,
+                message: Some(e.to_string()),
+                rejected_edits: Vec::new(),
+            },
         }
     }
 }
@@ -163,13 +504,19 @@ pub struct SyncStats {
     pub shadow_checksum: String,
     pub has_backup: bool,
     pub pending_edits: usize,
+    pub rejected_edits: u64,
+    pub checksum_mismatches: u64,
+    pub deadline_hits: u64,
 }
 
 impl fmt::Display for SyncEngine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let content = crate::truncate_text(&self.document.content, 50);
         let checksum = self.shadow_checksum();
-        let short_checksum = &checksum[..checksum.len().min(8)];
+        let digest = checksum
+            .split_once(':')
+            .map_or(checksum.as_str(), |(_, d)| d);
+        let short_checksum = &digest[..digest.len().min(8)];
         write!(
             f,
             "SyncEngine[{}]: doc='{content}' (v{}), shadow_checksum={short_checksum}",
@@ -215,6 +562,283 @@ mod tests {
         assert_eq!(client.text(), server.text());
     }
 
+    #[test]
+    fn test_edits_land_in_drifted_document() {
+        let mut client = SyncEngine::new("The cat sat on the mat".to_string());
+        let mut server = SyncEngine::new("The cat sat on the mat".to_string());
+
+        client.edit("Yesterday the cat sat on the mat");
+        server.edit("The cat sat on the red mat");
+
+        let edits = server.diff_and_update_shadow();
+        let report = client.apply_edits(edits).unwrap();
+
+        assert!(report.is_clean());
+        assert_eq!(client.text(), "Yesterday the cat sat on the red mat");
+    }
+
+    #[test]
+    fn test_rejected_edits_are_reported() {
+        let mut client = SyncEngine::new("Meeting at noon in room 4".to_string());
+        let mut server = SyncEngine::new("Meeting at noon in room 4".to_string());
+
+        client.edit("Cancelled, see the new invite");
+        server.edit("Meeting at noon in room 12");
+
+        let report = client.apply_edits(server.diff_and_update_shadow()).unwrap();
+        assert_eq!(report.rejected().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(client.text(), "Cancelled, see the new invite");
+        assert_eq!(client.stats().rejected_edits, 1);
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_diverged_shadow() {
+        let mut client = SyncEngine::new("Shared text".to_string());
+        let mut server = SyncEngine::new("Shared text, diverged".to_string());
+        server.strict_checksums = true;
+
+        client.edit("Shared text!");
+        let edits = client.diff_and_update_shadow();
+
+        assert_eq!(
+            server.apply_edits(edits.clone()),
+            Err(PatchError::ChecksumMismatch)
+        );
+        assert_eq!(server.text(), "Shared text, diverged");
+        assert_eq!(server.stats().checksum_mismatches, 1);
+
+        server.strict_checksums = false;
+        assert!(server.apply_edits(edits).is_ok());
+    }
+
+    #[test]
+    fn test_strict_mode_recovers_from_backup_shadow() {
+        let initial = "The meeting is on Monday. Bring notes.";
+        let mut client = SyncEngine::new(initial.to_string());
+        let mut server = SyncEngine::new_server(initial.to_string(), "server".to_string());
+        server.strict_checksums = true;
+
+        // The server's reply to the client is lost: its shadow moves on, the
+        // client's does not.
+        server.edit("The meeting is on Tuesday. Bring notes.");
+        server.backup_shadow();
+        let _lost = server.diff_and_update_shadow();
+
+        client.edit("The meeting is on Monday. Bring notes. Snacks provided.");
+        let edits = client.diff_and_update_shadow();
+        server.apply_edits(edits).unwrap();
+
+        assert_eq!(
+            server.text(),
+            "The meeting is on Tuesday. Bring notes. Snacks provided."
+        );
+    }
+
+    /// Deliver `packet` to `receiver` and return its reply.
+    fn exchange(receiver: &mut SyncEngine, packet: SyncPacket) -> SyncPacket {
+        receiver.receive_packet(packet).unwrap();
+        receiver.make_packet()
+    }
+
+    #[test]
+    fn test_guaranteed_delivery_survives_lost_request() {
+        let mut client = SyncEngine::new("one".to_string());
+        let mut server = SyncEngine::new_server("one".to_string(), "server".to_string());
+
+        client.edit("one two");
+        let _lost = client.make_packet();
+
+        client.edit("one two three");
+        let reply = exchange(&mut server, client.make_packet());
+        client.receive_packet(reply).unwrap();
+
+        assert_eq!(server.text(), "one two three");
+        assert_eq!(client.text(), "one two three");
+        assert_eq!(client.stats().pending_edits, 0);
+    }
+
+    #[test]
+    fn test_guaranteed_delivery_survives_lost_reply() {
+        let mut client = SyncEngine::new("The cat sat on the mat.".to_string());
+        let mut server =
+            SyncEngine::new_server("The cat sat on the mat.".to_string(), "server".to_string());
+
+        server.edit("The cat sat on the red mat.");
+        client.edit("The big cat sat on the mat.");
+        let _lost = exchange(&mut server, client.make_packet());
+
+        // The client never heard back, so it retransmits alongside new edits.
+        client.edit("The big cat sat on the mat!");
+        let reply = exchange(&mut server, client.make_packet());
+        client.receive_packet(reply).unwrap();
+
+        let ack = client.make_packet();
+        server.receive_packet(ack).unwrap();
+
+        assert_eq!(client.text(), "The big cat sat on the red mat!");
+        assert_eq!(server.text(), client.text());
+        assert_eq!(server.stats().pending_edits, 0);
+    }
+
+    #[test]
+    fn test_partial_ack_drops_only_the_acknowledged_edits() {
+        let mut client = SyncEngine::new("one".to_string());
+        let mut server = SyncEngine::new_server("one".to_string(), "server".to_string());
+
+        client.edit("one two");
+        let first = client.make_packet();
+        // Retransmitted with a further edit before the first reply arrives.
+        client.edit("one two three");
+        let second = client.make_packet();
+
+        let late_reply = exchange(&mut server, first);
+        client.receive_packet(late_reply.clone()).unwrap();
+        assert_eq!(client.stats().pending_edits, 1);
+
+        let reply = exchange(&mut server, second);
+        client.receive_packet(reply).unwrap();
+        assert_eq!(client.stats().pending_edits, 0);
+
+        // A duplicate of the older reply changes nothing.
+        client.receive_packet(late_reply).unwrap();
+        assert_eq!(server.text(), "one two three");
+        assert_eq!(client.text(), "one two three");
+        assert_eq!(client.stats().pending_edits, 0);
+    }
+
+    #[test]
+    fn test_unprompted_packet_may_cross_a_request() {
+        let mut client = SyncEngine::new("shared notes".to_string());
+        let mut server = SyncEngine::new_server("shared notes".to_string(), "server".to_string());
+        let reply = exchange(&mut server, client.make_packet());
+        client.receive_packet(reply).unwrap();
+        assert!(server.can_send_unprompted());
+
+        // The server pushes while the client's next request is on its way;
+        // the client drops the push, and its request rolls the server back.
+        server.edit("shared notes, v2");
+        let _dropped = server.make_packet();
+        assert!(!server.can_send_unprompted());
+        client.edit("my shared notes");
+        let reply = exchange(&mut server, client.make_packet());
+        client.receive_packet(reply).unwrap();
+        assert_eq!(client.text(), "my shared notes, v2");
+
+        server.receive_packet(client.make_packet()).unwrap();
+        assert_eq!(server.text(), client.text());
+        assert!(server.can_send_unprompted());
+    }
+
+    #[test]
+    fn test_guaranteed_delivery_discards_duplicates() {
+        let mut client = SyncEngine::new("abc".to_string());
+        let mut server = SyncEngine::new_server("abc".to_string(), "server".to_string());
+
+        client.edit("abcd");
+        let packet = client.make_packet();
+        server.receive_packet(packet.clone()).unwrap();
+        let report = server.receive_packet(packet).unwrap();
+
+        assert!(report.outcomes.is_empty());
+        assert_eq!(server.text(), "abcd");
+        assert_eq!(server.shadow_versions(), (0, 1));
+    }
+
+    #[test]
+    fn test_guaranteed_delivery_rejects_gaps() {
+        let mut server = SyncEngine::new_server("abc".to_string(), "server".to_string());
+        let packet = SyncPacket {
+            ack_version: 0,
+            edits: vec![VersionedEdits {
+                version: 3,
+                edits: EditList::empty("abc"),
+            }],
+        };
+        assert_eq!(
+            server.receive_packet(packet),
+            Err(PatchError::VersionMismatch)
+        );
+    }
+
+    #[test]
+    fn test_resync_keeps_local_edits() {
+        let mut client = SyncEngine::new("Draft: intro, body".to_string());
+        client.make_packet();
+        client.edit("Draft: intro, body, conclusion");
+
+        let report = client.resync("Final: intro, body");
+
+        assert!(report.is_clean());
+        assert_eq!(client.text(), "Final: intro, body, conclusion");
+        assert_eq!(client.shadow_versions(), (0, 0));
+        assert_eq!(client.stats().pending_edits, 0);
+
+        let mut server = SyncEngine::new_server("Final: intro, body".to_string(), "s".to_string());
+        server.receive_packet(client.make_packet()).unwrap();
+        assert_eq!(server.text(), "Final: intro, body, conclusion");
+    }
+
+    #[test]
+    fn test_resync_keeps_unacknowledged_edits() {
+        let mut client = SyncEngine::new("Draft: intro, body".to_string());
+        client.edit("Draft: intro, body, conclusion");
+        client.make_packet();
+        client.edit("Draft: intro, body, conclusion.");
+
+        let report = client.resync("Final: intro, body");
+
+        assert!(report.is_clean());
+        assert_eq!(client.text(), "Final: intro, body, conclusion.");
+        assert_eq!(client.stats().pending_edits, 0);
+    }
+
+    #[test]
+    fn test_diff_cleanup_is_configurable() {
+        let mut engine = SyncEngine::new("abxyzcd".to_string());
+        engine.edit("12xyz34");
+        assert_eq!(engine.diff_and_update_shadow().len(), 1);
+
+        let mut engine = SyncEngine::new("abxyzcd".to_string());
+        engine.diff_options.cleanup = DiffCleanup::None;
+        engine.edit("12xyz34");
+        assert_eq!(engine.diff_and_update_shadow().len(), 2);
+    }
+
+    #[test]
+    fn test_deadline_hits_are_counted() {
+        let mut engine = SyncEngine::new("abc XdefY ghi".to_string());
+        engine.diff_options.deadline = Some(Duration::ZERO);
+        engine.edit("abc ZdefW ghi");
+
+        let edits = engine.diff_and_update_shadow();
+        assert_eq!(patch("abc XdefY ghi", &edits).unwrap(), "abc ZdefW ghi");
+        assert_eq!(engine.stats().deadline_hits, 1);
+    }
+
+    #[test]
+    fn test_sync_in_utf16_units() {
+        let mut client = SyncEngine::new("😀 hi".to_string());
+        let mut server = SyncEngine::new("😀 hi".to_string());
+        client.offset_unit = OffsetUnit::Utf16;
+        server.offset_unit = OffsetUnit::Utf16;
+
+        client.edit("😀 hi there");
+        let edits = client.diff_and_update_shadow();
+        assert_eq!(
+            edits.edits,
+            vec![crate::diff::Edit::Insert {
+                pos: 5,
+                text: " there".to_string(),
+            }]
+        );
+        server.apply_edits(edits).unwrap();
+        assert_eq!(server.text(), "😀 hi there");
+
+        server.edit("😀😀 hi there");
+        client.apply_edits(server.diff_and_update_shadow()).unwrap();
+        assert_eq!(client.text(), "😀😀 hi there");
+    }
+
     #[test]
     fn test_shadow_consistency() {
         let mut engine = SyncEngine::new("Test content".to_string());
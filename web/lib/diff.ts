@@ -1,27 +1,13 @@
 /**
  * Diff and patch engine matching the Rust implementation in src/diff.rs.
  *
- * All positions (pos, len, old_len) are byte offsets — Rust strings are UTF-8
- * byte arrays. We use TextEncoder/TextDecoder to produce compatible offsets
- * so edits can cross the language boundary.
+ * All positions (pos, len, old_len) are UTF-16 code unit offsets, the same
+ * indices JS strings and CodeMirror use. The client negotiates the `Utf16`
+ * offset unit in `Connect`, so the server converts to and from its own UTF-8
+ * byte offsets.
  */
 
 const encoder = new TextEncoder();
-const decoder = new TextDecoder();
-
-/** Convert a UTF-8 byte offset to a JS string character index. */
-export function byteToCharOffset(text: string, byteOffset: number): number {
-  const bytes = encoder.encode(text);
-  const clampedOffset = Math.min(byteOffset, bytes.length);
-  const prefix = decoder.decode(bytes.slice(0, clampedOffset));
-  return prefix.length;
-}
-
-/** Convert a JS string character index to a UTF-8 byte offset. */
-export function charToByteOffset(text: string, charOffset: number): number {
-  const clamped = Math.max(0, Math.min(charOffset, text.length));
-  return encoder.encode(text.slice(0, clamped)).length;
-}
 
 export type Edit =
   | { Insert: { pos: number; text: string } }
@@ -58,7 +44,7 @@ export function diff(from: string, to: string): EditList {
 
   if (to.length === 0) {
     return {
-      edits: [{ Delete: { pos: 0, len: encoder.encode(from).length } }],
+      edits: [{ Delete: { pos: 0, len: from.length } }],
       checksum: checksum(from),
     };
   }
@@ -85,35 +71,32 @@ export function diff(from: string, to: string): EditList {
     commonEnd++;
   }
 
-  const prefixBytes = encoder.encode(
-    fromChars.slice(0, commonStart).join("")
-  ).length;
+  // Compared by code point so a surrogate pair is never split; measured in
+  // UTF-16 code units.
+  const prefix = fromChars.slice(0, commonStart).join("").length;
 
-  const suffixBytes =
+  const suffix =
     commonEnd > 0
-      ? encoder.encode(
-          fromChars.slice(fromChars.length - commonEnd).join("")
-        ).length
+      ? fromChars.slice(fromChars.length - commonEnd).join("").length
       : 0;
 
-  const fromMiddleBytes =
-    encoder.encode(from).length - prefixBytes - suffixBytes;
+  const fromMiddle = from.length - prefix - suffix;
   const toMiddle = toChars
     .slice(commonStart, toChars.length - commonEnd || undefined)
     .join("");
 
   const edits: Edit[] = [];
 
-  if (fromMiddleBytes > 0 || toMiddle.length > 0) {
-    if (fromMiddleBytes === 0) {
-      edits.push({ Insert: { pos: prefixBytes, text: toMiddle } });
+  if (fromMiddle > 0 || toMiddle.length > 0) {
+    if (fromMiddle === 0) {
+      edits.push({ Insert: { pos: prefix, text: toMiddle } });
     } else if (toMiddle.length === 0) {
-      edits.push({ Delete: { pos: prefixBytes, len: fromMiddleBytes } });
+      edits.push({ Delete: { pos: prefix, len: fromMiddle } });
     } else {
       edits.push({
         Replace: {
-          pos: prefixBytes,
-          old_len: fromMiddleBytes,
+          pos: prefix,
+          old_len: fromMiddle,
           new_text: toMiddle,
         },
       });
@@ -126,61 +109,40 @@ export function diff(from: string, to: string): EditList {
 /**
  * Apply edits to `text`, returning the transformed result.
  * Edits are applied in reverse order to avoid cascading position shifts.
- * Positions are clamped to byte-array bounds for fuzzy-patch tolerance.
+ * Positions are clamped to the text's bounds for fuzzy-patch tolerance.
  */
 export function patch(text: string, editList: EditList): string {
-  if (editList.edits.length === 0) {
-    return text;
-  }
-
-  let bytes = encoder.encode(text);
+  let result = text;
 
   for (let i = editList.edits.length - 1; i >= 0; i--) {
     const edit = editList.edits[i];
 
     if ("Insert" in edit) {
-      const pos = Math.min(edit.Insert.pos, bytes.length);
-      const insertBytes = encoder.encode(edit.Insert.text);
-      const result = new Uint8Array(bytes.length + insertBytes.length);
-      result.set(bytes.slice(0, pos));
-      result.set(insertBytes, pos);
-      result.set(bytes.slice(pos), pos + insertBytes.length);
-      bytes = result;
+      const pos = Math.min(edit.Insert.pos, result.length);
+      result = result.slice(0, pos) + edit.Insert.text + result.slice(pos);
     } else if ("Delete" in edit) {
-      const start = Math.min(edit.Delete.pos, bytes.length);
-      const end = Math.min(start + edit.Delete.len, bytes.length);
-      if (start < end) {
-        const result = new Uint8Array(bytes.length - (end - start));
-        result.set(bytes.slice(0, start));
-        result.set(bytes.slice(end), start);
-        bytes = result;
-      }
+      const start = Math.min(edit.Delete.pos, result.length);
+      const end = Math.min(start + edit.Delete.len, result.length);
+      result = result.slice(0, start) + result.slice(end);
     } else if ("Replace" in edit) {
-      const start = Math.min(edit.Replace.pos, bytes.length);
-      const end = Math.min(start + edit.Replace.old_len, bytes.length);
-      const newTextBytes = encoder.encode(edit.Replace.new_text);
-      const result = new Uint8Array(
-        bytes.length - (end - start) + newTextBytes.length
-      );
-      result.set(bytes.slice(0, start));
-      result.set(newTextBytes, start);
-      result.set(bytes.slice(end), start + newTextBytes.length);
-      bytes = result;
+      const start = Math.min(edit.Replace.pos, result.length);
+      const end = Math.min(start + edit.Replace.old_len, result.length);
+      result = result.slice(0, start) + edit.Replace.new_text + result.slice(end);
     }
   }
 
-  return decoder.decode(bytes);
+  return result;
 }
 
 /**
- * Map a byte offset through an edit list, returning where that offset lands in
- * the patched text. Edit positions are byte offsets in the *original* text, so
+ * Map an offset through an edit list, returning where that offset lands in
+ * the patched text. Edit positions are offsets in the *original* text, so
  * we compare every edit against the original `offset` and accumulate the net
  * length delta of edits that fall before it. Used to re-anchor a remote cursor
  * (expressed in server-document coordinates) into the local document, which may
  * carry un-synced local edits.
  */
-export function mapByteOffsetThroughEdits(
+export function mapOffsetThroughEdits(
   offset: number,
   editList: EditList
 ): number {
@@ -188,7 +150,7 @@ export function mapByteOffsetThroughEdits(
   for (const edit of editList.edits) {
     if ("Insert" in edit) {
       if (edit.Insert.pos <= offset) {
-        delta += encoder.encode(edit.Insert.text).length;
+        delta += edit.Insert.text.length;
       }
     } else if ("Delete" in edit) {
       const { pos, len } = edit.Delete;
@@ -199,7 +161,7 @@ export function mapByteOffsetThroughEdits(
       }
     } else {
       const { pos, old_len, new_text } = edit.Replace;
-      const newLen = encoder.encode(new_text).length;
+      const newLen = new_text.length;
       if (pos + old_len <= offset) {
         delta += newLen - old_len; // replacement entirely before the cursor
       } else if (pos < offset) {
@@ -209,3 +171,86 @@ export function mapByteOffsetThroughEdits(
   }
   return Math.max(0, offset + delta);
 }
+
+/** Compact wire form of an EditList, matching `DeltaEdits` in src/diff.rs. */
+export interface DeltaEdits {
+  delta: string;
+  checksum: string;
+}
+
+/**
+ * Encode an edit list as tab-separated `=n` (skip), `-n` (remove) and `+text`
+ * (insert, URI-encoded) tokens. Consecutive edits are always separated by an
+ * `=` token so the Rust side recovers the exact Edit variants.
+ */
+export function toDelta(editList: EditList): DeltaEdits {
+  const tokens: string[] = [];
+  const encode = (text: string) => "+" + encodeURI(text).replace(/%20/g, " ");
+  let cursor = 0;
+  editList.edits.forEach((edit, i) => {
+    const [pos, oldLen] =
+      "Insert" in edit
+        ? [edit.Insert.pos, 0]
+        : "Delete" in edit
+          ? [edit.Delete.pos, edit.Delete.len]
+          : [edit.Replace.pos, edit.Replace.old_len];
+    if (i > 0 || pos > cursor) tokens.push(`=${pos - cursor}`);
+    if ("Insert" in edit) {
+      tokens.push(encode(edit.Insert.text));
+    } else if ("Delete" in edit) {
+      tokens.push(`-${oldLen}`);
+    } else {
+      tokens.push(`-${oldLen}`, encode(edit.Replace.new_text));
+    }
+    cursor = pos + oldLen;
+  });
+  return { delta: tokens.join("\t"), checksum: editList.checksum };
+}
+
+/** Inverse of `toDelta`. Throws on malformed input. */
+export function fromDelta({ delta, checksum }: DeltaEdits): EditList {
+  const edits: Edit[] = [];
+  let cursor = 0;
+  let removed: number | null = null;
+  let inserted: string | null = null;
+  let open = false;
+
+  const flush = () => {
+    if (!open) return;
+    if (inserted === null) {
+      edits.push({ Delete: { pos: cursor, len: removed! } });
+    } else if (removed === null) {
+      edits.push({ Insert: { pos: cursor, text: inserted } });
+    } else {
+      edits.push({
+        Replace: { pos: cursor, old_len: removed, new_text: inserted },
+      });
+    }
+    cursor += removed ?? 0;
+    removed = inserted = null;
+    open = false;
+  };
+  const count = (token: string) => {
+    if (!/^\d+$/.test(token)) throw new Error(`Invalid delta token: ${token}`);
+    return Number(token);
+  };
+
+  for (const token of delta.split("\t")) {
+    if (token === "") continue;
+    const rest = token.slice(1);
+    if (token[0] === "=") {
+      flush();
+      cursor += count(rest);
+    } else if (token[0] === "-" && !open) {
+      removed = count(rest);
+      open = true;
+    } else if (token[0] === "+" && inserted === null) {
+      inserted = decodeURIComponent(rest);
+      open = true;
+    } else {
+      throw new Error(`Invalid delta token: ${token}`);
+    }
+  }
+  flush();
+  return { edits, checksum };
+}
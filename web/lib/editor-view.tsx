@@ -33,7 +33,7 @@ function getWsUrl(): string {
 
 function cursorsToCharOffsets(
   cursors: CursorInfo[],
-  mapPosition: (serverByteOffset: number) => number
+  mapPosition: (serverOffset: number) => number
 ): { clientId: string; position: number; color: string }[] {
   return cursors.map((c) => ({
     clientId: c.client_id,
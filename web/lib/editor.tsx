@@ -11,7 +11,7 @@ import { EditorState, Annotation, Compartment, type ChangeSpec } from "@codemirr
 import { EditorView, keymap } from "@codemirror/view";
 import { markdown } from "@codemirror/lang-markdown";
 import { oneDark } from "@codemirror/theme-one-dark";
-import { byteToCharOffset, type Edit, type EditList } from "./diff";
+import type { Edit, EditList } from "./diff";
 import {
   remoteCursorsExtension,
   type RemoteCursorState,
@@ -19,23 +19,24 @@ import {
 
 const remoteAnnotation = Annotation.define<boolean>();
 
+// Edit offsets are UTF-16 code units, as CodeMirror counts; clamped to the
+// document like `patch` does.
 function editToChangeSpec(edit: Edit, doc: string): ChangeSpec {
+  const clamp = (offset: number) => Math.min(offset, doc.length);
   if ("Insert" in edit) {
-    const charPos = byteToCharOffset(doc, edit.Insert.pos);
-    return { from: charPos, insert: edit.Insert.text };
+    return { from: clamp(edit.Insert.pos), insert: edit.Insert.text };
   }
   if ("Delete" in edit) {
-    const charFrom = byteToCharOffset(doc, edit.Delete.pos);
-    const charTo = byteToCharOffset(doc, edit.Delete.pos + edit.Delete.len);
-    return { from: charFrom, to: charTo };
+    const from = clamp(edit.Delete.pos);
+    return { from, to: clamp(from + edit.Delete.len) };
   }
   // Replace
-  const charFrom = byteToCharOffset(doc, edit.Replace.pos);
-  const charTo = byteToCharOffset(
-    doc,
-    edit.Replace.pos + edit.Replace.old_len
-  );
-  return { from: charFrom, to: charTo, insert: edit.Replace.new_text };
+  const from = clamp(edit.Replace.pos);
+  return {
+    from,
+    to: clamp(from + edit.Replace.old_len),
+    insert: edit.Replace.new_text,
+  };
 }
 
 export interface EditorHandle {
@@ -6,7 +6,19 @@
  *   SyncMessage::Ping                  → "Ping"
  */
 
-import type { EditList } from "./diff";
+import type { DeltaEdits, EditList } from "./diff";
+
+/** Unit of edit and cursor offsets; the server defaults to UTF-8 bytes. */
+export type OffsetUnit = "Bytes" | "Chars" | "Utf16";
+
+/** Encoding after the handshake; the web client stays on JSON text frames. */
+export type CodecKind = "Json" | "MessagePack" | "Cbor";
+
+/** Payload compression. Once agreed, every WebSocket frame is a binary envelope. */
+export type Compression = "None" | "Deflate" | "Zstd";
+
+/** Protocol version this client speaks; see PROTOCOL_VERSION in network.rs. */
+export const PROTOCOL_VERSION = 2;
 
 export interface Document {
   content: string;
@@ -20,11 +32,23 @@ export interface CursorInfo {
 }
 
 export type SyncMessage =
-  | { Connect: { client_id: string } }
+  | {
+      Connect: {
+        client_id: string;
+        protocol_version?: number;
+        features?: string[];
+        offset_unit?: OffsetUnit;
+        compact_edits?: boolean;
+        codec?: CodecKind;
+        compression?: Compression;
+      };
+    }
   | {
       ClientSync: {
         client_id: string;
         edits: EditList;
+        /** Replaces `edits` once compact edits are negotiated. */
+        delta?: DeltaEdits;
         client_version: number;
         cursor_position: number | null;
       };
@@ -32,11 +56,27 @@ export type SyncMessage =
   | {
       ServerSync: {
         edits: EditList;
+        delta?: DeltaEdits;
         server_version: number;
         cursors: CursorInfo[];
+        rejected_edits?: number[];
+        /** Sent unprompted after other clients' edits. */
+        pushed?: boolean;
+      };
+    }
+  | {
+      ConnectOk: {
+        server_version: number;
+        document: Document;
+        protocol_version?: number;
+        features?: string[];
+        offset_unit?: OffsetUnit;
+        compact_edits?: boolean;
+        codec?: CodecKind;
+        compression?: Compression;
       };
     }
-  | { ConnectOk: { server_version: number; document: Document } }
+  | { Resync: { server_version: number; document: Document } }
   | { Error: { message: string } }
   | { Disconnect: { client_id: string } }
   | "SessionClosed"
@@ -12,7 +12,7 @@ import {
 
 export interface RemoteCursorState {
   clientId: string;
-  position: number; // character offset (already converted from bytes)
+  position: number; // UTF-16 offset into the local document
   color: string;
 }
 
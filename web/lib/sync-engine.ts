@@ -6,7 +6,7 @@
  * changes; incoming edits from the server are applied to both.
  */
 
-import { diff, patch, mapByteOffsetThroughEdits, type EditList } from "./diff";
+import { diff, patch, mapOffsetThroughEdits, type EditList } from "./diff";
 
 export class SyncEngine {
   private document: string;
@@ -41,15 +41,26 @@ export class SyncEngine {
   }
 
   /**
-   * Map a byte offset expressed in server-document coordinates (the shadow,
-   * i.e. the last state agreed with the server) into the local document's
-   * byte-offset space, accounting for un-synced local edits. Used to place
-   * remote cursors correctly while the local user is mid-edit.
+   * Map an offset expressed in server-document coordinates (the shadow,
+   * i.e. the last state agreed with the server) into the local document,
+   * accounting for un-synced local edits. Used to place remote cursors
+   * correctly while the local user is mid-edit.
    */
-  mapToLocalOffset(shadowByteOffset: number): number {
-    if (this.shadow === this.document) return shadowByteOffset;
+  mapToLocalOffset(shadowOffset: number): number {
+    if (this.shadow === this.document) return shadowOffset;
     const pending = diff(this.shadow, this.document);
-    return mapByteOffsetThroughEdits(shadowByteOffset, pending);
+    return mapOffsetThroughEdits(shadowOffset, pending);
+  }
+
+  /**
+   * Adopt the server's document after the shadows diverged, re-applying any
+   * local edits made since the last sync on top of it.
+   */
+  resync(content: string): void {
+    const local = diff(this.shadow, this.document);
+    this.shadow = content;
+    this.document = patch(content, local);
+    this.version++;
   }
 
   /** Apply incoming server edits to both shadow and document. */
@@ -2,10 +2,16 @@
 
 import { useCallback, useEffect, useRef, useState } from "react";
 import { SyncEngine } from "./sync-engine";
-import type { SyncMessage, CursorInfo } from "./protocol";
-import { byteToCharOffset, charToByteOffset, type EditList } from "./diff";
+import { PROTOCOL_VERSION, type SyncMessage, type CursorInfo } from "./protocol";
+import { fromDelta, toDelta, type EditList } from "./diff";
 
-const SYNC_INTERVAL_MS = 200;
+// Local changes are gathered this long before they are sent.
+const SYNC_DEBOUNCE_MS = 50;
+// Syncs go out for local changes and server pushes; when idle, only this
+// often, to keep the session alive and catch up on a lost push.
+const IDLE_SYNC_MS = 30000;
+// One ClientSync at a time: the next waits for the reply, or this long.
+const REPLY_TIMEOUT_MS = 5000;
 const RECONNECT_BASE_MS = 1000;
 const RECONNECT_MAX_MS = 10000;
 
@@ -25,10 +31,10 @@ interface UseSyncResult {
   remoteCursors: CursorInfo[];
   setCursorPosition: (position: number) => void;
   /**
-   * Convert a remote cursor's server-document byte offset into a local
-   * character offset, re-anchored through any un-synced local edits.
+   * Re-anchor a remote cursor's server-document offset through any un-synced
+   * local edits.
    */
-  mapRemoteCursor: (serverByteOffset: number) => number;
+  mapRemoteCursor: (serverOffset: number) => number;
   sessionClosed: boolean;
 }
 
@@ -51,7 +57,7 @@ export function useSync({
 
   const engineRef = useRef<SyncEngine | null>(null);
   const wsRef = useRef<WebSocket | null>(null);
-  const syncIntervalRef = useRef<ReturnType<typeof setInterval> | null>(null);
+  const syncTimerRef = useRef<ReturnType<typeof setTimeout> | null>(null);
   const reconnectTimeoutRef = useRef<ReturnType<typeof setTimeout> | null>(
     null
   );
@@ -59,63 +65,88 @@ export function useSync({
   const mountedRef = useRef(true);
   const sessionClosedRef = useRef(false);
   const cursorPositionRef = useRef<number | null>(null);
+  // Whether the server agreed to exchange edits in compact delta form.
+  const compactEditsRef = useRef(false);
+  // When the ClientSync awaiting its reply was sent. Bare edit lists are
+  // diffed against the shadow the previous reply left, so requests must not
+  // overlap.
+  const awaitingReplySinceRef = useRef<number | null>(null);
+  // Whether something asked for a sync while a reply was awaited.
+  const syncQueuedRef = useRef(false);
+  const sendSyncRef = useRef<() => void>(() => {});
   const onRemoteEditsRef = useRef(onRemoteEdits);
   onRemoteEditsRef.current = onRemoteEdits;
 
-  const setDocument = useCallback((content: string) => {
-    if (engineRef.current) {
-      engineRef.current.edit(content);
+  const stopSyncTimer = useCallback(() => {
+    if (syncTimerRef.current) {
+      clearTimeout(syncTimerRef.current);
+      syncTimerRef.current = null;
     }
-    setDocumentState(content);
   }, []);
 
-  const setCursorPosition = useCallback((position: number) => {
-    cursorPositionRef.current = position;
-  }, []);
+  const scheduleSync = useCallback(
+    (delay: number) => {
+      stopSyncTimer();
+      syncTimerRef.current = setTimeout(() => sendSyncRef.current(), delay);
+    },
+    [stopSyncTimer]
+  );
+
+  const setDocument = useCallback(
+    (content: string) => {
+      if (engineRef.current) {
+        engineRef.current.edit(content);
+        scheduleSync(SYNC_DEBOUNCE_MS);
+      }
+      setDocumentState(content);
+    },
+    [scheduleSync]
+  );
+
+  const setCursorPosition = useCallback(
+    (position: number) => {
+      if (cursorPositionRef.current === position) return;
+      cursorPositionRef.current = position;
+      if (engineRef.current) {
+        scheduleSync(SYNC_DEBOUNCE_MS);
+      }
+    },
+    [scheduleSync]
+  );
 
-  const mapRemoteCursor = useCallback((serverByteOffset: number): number => {
+  const mapRemoteCursor = useCallback((serverOffset: number): number => {
     const engine = engineRef.current;
-    if (!engine) return serverByteOffset;
-    // Re-anchor the server-space offset through our pending local edits, then
-    // express it as a character offset for CodeMirror.
-    const localByte = engine.mapToLocalOffset(serverByteOffset);
-    return byteToCharOffset(engine.text(), localByte);
+    return engine ? engine.mapToLocalOffset(serverOffset) : serverOffset;
   }, []);
 
-  const stopSyncInterval = useCallback(() => {
-    if (syncIntervalRef.current) {
-      clearInterval(syncIntervalRef.current);
-      syncIntervalRef.current = null;
+  // Send a ClientSync now, or once the reply awaited arrives (or times out).
+  sendSyncRef.current = () => {
+    const ws = wsRef.current;
+    const engine = engineRef.current;
+    if (!ws || !engine || ws.readyState !== WebSocket.OPEN) return;
+    const since = awaitingReplySinceRef.current;
+    if (since !== null && Date.now() - since < REPLY_TIMEOUT_MS) {
+      syncQueuedRef.current = true;
+      scheduleSync(since + REPLY_TIMEOUT_MS - Date.now());
+      return;
     }
-  }, []);
+    syncQueuedRef.current = false;
 
-  const startSyncInterval = useCallback(
-    (ws: WebSocket, engine: SyncEngine) => {
-      stopSyncInterval();
-      syncIntervalRef.current = setInterval(() => {
-        if (ws.readyState !== WebSocket.OPEN) return;
-
-        const edits = engine.diffAndUpdateShadow();
-        // The cursor arrives from CodeMirror as a character offset; the wire
-        // protocol and every other client expect a UTF-8 byte offset.
-        const cursorChar = cursorPositionRef.current;
-        const cursorByte =
-          cursorChar === null
-            ? null
-            : charToByteOffset(engine.text(), cursorChar);
-        const msg: SyncMessage = {
-          ClientSync: {
-            client_id: clientId,
-            edits,
-            client_version: engine.getVersion(),
-            cursor_position: cursorByte,
-          },
-        };
-        ws.send(JSON.stringify(msg));
-      }, SYNC_INTERVAL_MS);
-    },
-    [clientId, stopSyncInterval]
-  );
+    const edits = engine.diffAndUpdateShadow();
+    awaitingReplySinceRef.current = Date.now();
+    const msg: SyncMessage = {
+      ClientSync: {
+        client_id: clientId,
+        ...(compactEditsRef.current
+          ? { edits: { edits: [], checksum: "" }, delta: toDelta(edits) }
+          : { edits }),
+        client_version: engine.getVersion(),
+        cursor_position: cursorPositionRef.current,
+      },
+    };
+    ws.send(JSON.stringify(msg));
+    scheduleSync(IDLE_SYNC_MS);
+  };
 
   const connect = useCallback(() => {
     if (!mountedRef.current) return;
@@ -126,10 +157,30 @@ export function useSync({
 
     ws.onopen = () => {
       reconnectDelayRef.current = RECONNECT_BASE_MS;
-      const msg: SyncMessage = { Connect: { client_id: clientId } };
+      const msg: SyncMessage = {
+        Connect: {
+          client_id: clientId,
+          protocol_version: PROTOCOL_VERSION,
+          // Pushes to a client sending bare edit lists carry no edits, only
+          // a request to sync.
+          features: ["resync", "push"],
+          // Edit and cursor offsets count UTF-16 code units, like JS strings
+          // and CodeMirror.
+          offset_unit: "Utf16",
+          compact_edits: true,
+        },
+      };
       ws.send(JSON.stringify(msg));
     };
 
+    // Let the next sync go, sending it now if one was asked for meanwhile.
+    const receivedReply = () => {
+      awaitingReplySinceRef.current = null;
+      if (syncQueuedRef.current) {
+        scheduleSync(0);
+      }
+    };
+
     ws.onmessage = (event) => {
       let msg: SyncMessage;
       try {
@@ -144,22 +195,32 @@ export function useSync({
         setSessionClosed(true);
         setIsConnected(false);
         setRemoteCursors([]);
-        stopSyncInterval();
+        stopSyncTimer();
         wsRef.current?.close();
         return;
       }
 
       if (typeof msg === "object" && msg !== null) {
         if ("ConnectOk" in msg) {
-          const { document: doc, server_version } = msg.ConnectOk;
-          const engine = new SyncEngine(doc.content, clientId);
-          engineRef.current = engine;
+          const { document: doc, server_version, compact_edits } = msg.ConnectOk;
+          compactEditsRef.current = compact_edits ?? false;
+          awaitingReplySinceRef.current = null;
+          syncQueuedRef.current = false;
+          engineRef.current = new SyncEngine(doc.content, clientId);
           setDocumentState(doc.content);
           setServerVersion(server_version);
           setIsConnected(true);
-          startSyncInterval(ws, engine);
+          // The first sync tells the server how we sync, so pushes can start.
+          sendSyncRef.current();
         } else if ("ServerSync" in msg) {
-          const { edits, server_version, cursors } = msg.ServerSync;
+          // A push only asks for a sync; its reply brings the changes.
+          if (msg.ServerSync.pushed) {
+            sendSyncRef.current();
+            return;
+          }
+          receivedReply();
+          const { server_version, cursors, delta } = msg.ServerSync;
+          const edits = delta ? fromDelta(delta) : msg.ServerSync.edits;
           setServerVersion(server_version);
           setRemoteCursors(cursors);
           if (edits.edits.length > 0 && engineRef.current) {
@@ -169,7 +230,16 @@ export function useSync({
             }
             setDocumentState(engineRef.current.text());
           }
+        } else if ("Resync" in msg) {
+          receivedReply();
+          const { document: doc, server_version } = msg.Resync;
+          setServerVersion(server_version);
+          if (engineRef.current) {
+            engineRef.current.resync(doc.content);
+            setDocumentState(engineRef.current.text());
+          }
         } else if ("Error" in msg) {
+          receivedReply();
           console.error("Server error:", msg.Error.message);
         }
       }
@@ -178,7 +248,7 @@ export function useSync({
     ws.onclose = () => {
       setIsConnected(false);
       setRemoteCursors([]);
-      stopSyncInterval();
+      stopSyncTimer();
 
       if (!mountedRef.current || sessionClosedRef.current) return;
 
@@ -191,7 +261,7 @@ export function useSync({
     ws.onerror = (err) => {
       console.error("WebSocket error:", err);
     };
-  }, [serverUrl, clientId, sessionToken, startSyncInterval, stopSyncInterval]);
+  }, [serverUrl, clientId, sessionToken, scheduleSync, stopSyncTimer]);
 
   useEffect(() => {
     mountedRef.current = true;
@@ -199,7 +269,7 @@ export function useSync({
 
     return () => {
       mountedRef.current = false;
-      stopSyncInterval();
+      stopSyncTimer();
       if (reconnectTimeoutRef.current) {
         clearTimeout(reconnectTimeoutRef.current);
       }
@@ -207,7 +277,7 @@ export function useSync({
         wsRef.current.close();
       }
     };
-  }, [connect, stopSyncInterval]);
+  }, [connect, stopSyncTimer]);
 
   return {
     document,